@@ -1,122 +1,419 @@
 use core::cell::RefCell;
 
-// use arrform::{arrform, ArrForm};
 use cortex_m::interrupt::Mutex;
-use freertos_rust::{CurrentTask, Duration};
 use stm32f4xx_hal::adc::Adc;
-use stm32f4xx_hal::dma::{PeripheralToMemory, Stream0, Transfer};
+use stm32f4xx_hal::dma::{DMAError, PeripheralToMemory, Stream0, Transfer};
 use stm32f4xx_hal::pac::{ADC1, DMA2};
 use stm32f4xx_hal::pac::interrupt;
+use crate::config::{config, G_CONFIG};
 
-// use crate::usb::usb_println;
+type DMATransfer = Transfer<Stream0<DMA2>, 0, Adc<ADC1>, PeripheralToMemory, &'static mut [u16]>;
 
-type DMATransfer = Transfer<Stream0<DMA2>, 0, Adc<ADC1>, PeripheralToMemory, &'static mut [u16; 4]>;
+/// conversions of one scan: temperature, vbat, vin, current, VREFINT
+pub const SEQUENCE_LEN: usize = 5;
+/// position of VREFINT in the scan
+const VREFINT_INDEX: usize = 4;
+/// range of `Config::oversampling`, scans averaged per DMA transfer
+pub const MAX_OVERSAMPLING: u8 = 16;
+const ADC_MEMORY_LEN: usize = SEQUENCE_LEN * MAX_OVERSAMPLING as usize;
+/// PCLK2 / 2, see `main`
+const ADC_CLOCK_HZ: f32 = 12_000_000.0;
+/// 480 sampling and 12 conversion cycles per channel
+const SEQUENCE_TIME_MS: f32 = SEQUENCE_LEN as f32 * 492.0 * 1000.0 / ADC_CLOCK_HZ;
+/// longest moving average in filter updates
+const MAX_AVERAGE_LEN: usize = 32;
+/// range of `Filter::time_ms`
+pub const MAX_FILTER_TIME_MS: u16 = 60_000;
 
 pub static G_XFR: Mutex<RefCell<Option<DMATransfer>>> = Mutex::new(RefCell::new(None));
+/// buffer handed to the DMA in exchange for the one it just filled, see `DMA2_STREAM0`
+pub static G_ADC_SPARE: Mutex<RefCell<Option<&'static mut [u16]>>> = Mutex::new(RefCell::new(None));
 pub static G_VBAT: Mutex<RefCell<Option<f32>>> = Mutex::new(RefCell::new(None));
 pub static G_VIN: Mutex<RefCell<Option<f32>>> = Mutex::new(RefCell::new(None));
 pub static G_CURRENT: Mutex<RefCell<Option<f32>>> = Mutex::new(RefCell::new(None));
-pub static G_ADC_BUF: Mutex<RefCell<Option<[u16; 4]>>> = Mutex::new(RefCell::new(None));
+pub static G_TEMPERATURE: Mutex<RefCell<Option<f32>>> = Mutex::new(RefCell::new(None));
+/// analog supply computed from VREFINT
+pub static G_VDDA: Mutex<RefCell<Option<f32>>> = Mutex::new(RefCell::new(None));
+/// the averaged raw samples of the last transfer
+pub static G_ADC_BUF: Mutex<RefCell<Option<[f32; SEQUENCE_LEN]>>> = Mutex::new(RefCell::new(None));
+static G_FILTER_STATE: Mutex<RefCell<[FilterState; CHANNELS]>> =
+    Mutex::new(RefCell::new([FilterState::new(); CHANNELS]));
 
-pub static mut ADC_MEMORY: [u16; 4] = [0u16; 4];
+/// the double buffered DMA fills two of them in turn, the third is `G_ADC_SPARE`
+pub static mut ADC_MEMORY: [[u16; ADC_MEMORY_LEN]; 3] = [[0u16; ADC_MEMORY_LEN]; 3];
+
+// internal temperature sensor, typical values of the STM32F405 datasheet
+const TEMPERATURE_V25: f32 = 0.76;
+const TEMPERATURE_SLOPE: f32 = 0.0025; // V / °C
+
+/// factory conversion of VREFINT at `VREFINT_CAL_VDDA`, 12 bit
+const VREFINT_CAL_ADDRESS: usize = 0x1FFF_7A2A;
+const VREFINT_CAL_VDDA: f32 = 3.3;
+const MAX_SAMPLE: f32 = 4095.0;
+
+/// number of calibrated measurement channels
+pub const CHANNELS: usize = 3;
 
 ///
 ///
-/// measures the current in Ampere
+/// linear correction of a channel, `value = gain * raw + offset`
 ///
-/// returns: f32
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Calibration {
+    pub gain: f32,
+    /// in the unit of the channel
+    pub offset: f32,
+}
+
+impl Calibration {
+    /// leaves the nominal conversion untouched
+    pub const IDENTITY: Calibration = Calibration { gain: 1.0, offset: 0.0 };
+
+    pub fn apply(&self, raw: f32) -> f32 {
+        self.gain * raw + self.offset
+    }
+
+    ///
+    ///
+    /// corrects the gain so that `raw` reads as `reference`, the offset is kept
+    ///
+    /// returns: `None` if `raw` is too small to calibrate against
+    ///
+    pub fn with_point(&self, point: CalibrationPoint) -> Option<Calibration> {
+        if point.raw.abs() < 0.01 {
+            return None;
+        }
+        Some(Calibration {
+            gain: (point.reference - self.offset) / point.raw,
+            offset: self.offset,
+        })
+    }
+
+    ///
+    ///
+    /// the line through two reference points
+    ///
+    /// returns: `None` if the raw readings are too close to each other for a usable slope
+    ///
+    pub fn from_points(low: CalibrationPoint, high: CalibrationPoint) -> Option<Calibration> {
+        let span = high.raw - low.raw;
+        if span.abs() < 0.1 * high.raw.abs().max(low.raw.abs()) || span.abs() < 0.01 {
+            return None;
+        }
+        let gain = (high.reference - low.reference) / span;
+        Some(Calibration {
+            gain,
+            offset: low.reference - gain * low.raw,
+        })
+    }
+}
+
 ///
 ///
-pub fn read_current() -> f32 {
-    let mut current = 0.0;
+/// uncalibrated reading of a channel and what a reference meter showed at the same time
+///
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct CalibrationPoint {
+    pub raw: f32,
+    pub reference: f32,
+}
+
+///
+///
+/// digital filter applied to a channel after oversampling
+///
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum FilterMode {
+    Off,
+    /// first order low pass, `time_ms` is the time constant
+    Iir,
+    /// moving average over `time_ms`, at most `MAX_AVERAGE_LEN` updates
+    Average,
+}
 
-    cortex_m::interrupt::free(|cs| match G_CURRENT.borrow(cs).borrow_mut().as_mut() {
-        None => {}
-        Some(sampled_voltage) => {
-            current = *sampled_voltage / 33.0; // sens = 33 mV / A
+impl FilterMode {
+    pub const fn to_u8(self) -> u8 {
+        match self {
+            FilterMode::Off => 0,
+            FilterMode::Iir => 1,
+            FilterMode::Average => 2,
         }
-    });
-    // add some delay
-    CurrentTask::delay(Duration::ms(2));
-    // trigger a new conversion (is this really needed?)
-    cortex_m::interrupt::free(|cs| {
-        if let Some(transfer) = G_XFR.borrow(cs).borrow_mut().as_mut() {
-            transfer.start(|adc| {
-                adc.start_conversion();
-            });
-        };
-    });
-    current
+    }
+
+    pub const fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(FilterMode::Off),
+            1 => Some(FilterMode::Iir),
+            2 => Some(FilterMode::Average),
+            _ => None,
+        }
+    }
+
+    pub const fn name(&self) -> &'static str {
+        match self {
+            FilterMode::Off => "off",
+            FilterMode::Iir => "iir",
+            FilterMode::Average => "average",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "off" => Some(FilterMode::Off),
+            "iir" => Some(FilterMode::Iir),
+            "average" => Some(FilterMode::Average),
+            _ => None,
+        }
+    }
 }
 
-pub fn read_v_bat() -> f32 {
-    let mut voltage = 0.0;
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Filter {
+    pub mode: FilterMode,
+    /// 0..=60000
+    pub time_ms: u16,
+}
+
+impl Filter {
+    pub const OFF: Filter = Filter {
+        mode: FilterMode::Off,
+        time_ms: 0,
+    };
+}
 
-    cortex_m::interrupt::free(|cs| match G_VBAT.borrow(cs).borrow_mut().as_mut() {
-        None => {}
-        Some(sampled_voltage) => {
-            voltage = *sampled_voltage / 3.4 * 12.0;
+///
+///
+/// history of the filter of one channel
+///
+#[derive(Clone, Copy)]
+struct FilterState {
+    /// last output, `None` until the first sample
+    value: Option<f32>,
+    window: [f32; MAX_AVERAGE_LEN],
+    /// index of the oldest sample in `window`
+    next: usize,
+    /// samples in `window`
+    count: usize,
+}
+
+impl FilterState {
+    const fn new() -> Self {
+        FilterState {
+            value: None,
+            window: [0.0; MAX_AVERAGE_LEN],
+            next: 0,
+            count: 0,
         }
-    });
-    // add some delay
-    CurrentTask::delay(Duration::ms(2));
-    // trigger a new conversion (is this really needed?)
-    cortex_m::interrupt::free(|cs| {
-        if let Some(transfer) = G_XFR.borrow(cs).borrow_mut().as_mut() {
-            transfer.start(|adc| {
-                adc.start_conversion();
-            });
+    }
+
+    ///
+    ///
+    /// feeds one sample, `period_ms` is the time since the previous one
+    ///
+    /// returns: the filtered value
+    ///
+    fn update(&mut self, filter: Filter, period_ms: f32, sample: f32) -> f32 {
+        let value = match (filter.mode, self.value) {
+            (FilterMode::Iir, Some(value)) => {
+                let alpha = (period_ms / filter.time_ms as f32).min(1.0);
+                value + (sample - value) * alpha
+            }
+            (FilterMode::Average, _) => {
+                let len = ((filter.time_ms as f32 / period_ms) as usize).clamp(1, MAX_AVERAGE_LEN);
+                self.window[self.next] = sample;
+                self.next = (self.next + 1) % MAX_AVERAGE_LEN;
+                self.count = (self.count + 1).min(len);
+                // the newest `count` samples end right before `next`
+                let sum: f32 = (1..=self.count)
+                    .map(|age| self.window[(self.next + MAX_AVERAGE_LEN - age) % MAX_AVERAGE_LEN])
+                    .sum();
+                sum / self.count as f32
+            }
+            _ => sample,
         };
-    });
-    voltage
+        if filter.mode != FilterMode::Average {
+            self.count = 0;
+        }
+        self.value = Some(value);
+        value
+    }
+}
+
+///
+///
+/// calibrated measurement channels, the index selects the entry in `Config::calibration`
+///
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Channel {
+    VBat = 0,
+    VIn = 1,
+    Current = 2,
+}
+
+impl Channel {
+    pub const ALL: [Channel; CHANNELS] = [Channel::VBat, Channel::VIn, Channel::Current];
+
+    pub const fn name(&self) -> &'static str {
+        match self {
+            Channel::VBat => "vbat",
+            Channel::VIn => "vin",
+            Channel::Current => "current",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Channel::ALL.iter().copied().find(|channel| channel.name() == name)
+    }
+
+    /// calibration stored in the configuration for this channel
+    pub fn calibration(&self) -> Calibration {
+        config().calibration[*self as usize]
+    }
+
+    ///
+    ///
+    /// measures the channel with the nominal divider / sensor sensitivity only, but filtered
+    ///
+    pub fn read_raw(&self) -> f32 {
+        // the ADC converts continuously, the DMA interrupt keeps the filtered samples up to date
+        let sampled_voltage = cortex_m::interrupt::free(|cs| *self.sample().borrow(cs).borrow()).unwrap_or(0.0);
+        match self {
+            Channel::VBat | Channel::VIn => sampled_voltage / 3.4 * 12.0,
+            Channel::Current => sampled_voltage / 33.0, // sens = 33 mV / A
+        }
+    }
+
+    /// filtered voltage at the ADC input
+    fn sample(&self) -> &'static Mutex<RefCell<Option<f32>>> {
+        match self {
+            Channel::VBat => &G_VBAT,
+            Channel::VIn => &G_VIN,
+            Channel::Current => &G_CURRENT,
+        }
+    }
+
+    /// measures the channel with its calibration applied
+    pub fn read(&self) -> f32 {
+        self.calibration().apply(self.read_raw())
+    }
+}
+
+/// low point of a two point calibration per channel, kept until the high point arrives
+pub static G_CALIBRATION_LOW: Mutex<RefCell<[Option<CalibrationPoint>; CHANNELS]>> =
+    Mutex::new(RefCell::new([None; CHANNELS]));
+
+///
+///
+/// measures the current in Ampere
+///
+/// returns: f32
+///
+///
+pub fn read_current() -> f32 {
+    Channel::Current.read()
+}
+
+pub fn read_v_bat() -> f32 {
+    Channel::VBat.read()
 }
 
 pub fn read_v_in() -> f32 {
-    let mut voltage = 0.0;
+    Channel::VIn.read()
+}
+
+///
+///
+/// measures the die temperature in °C, only accurate to a few degrees
+///
+pub fn read_temperature() -> f32 {
+    cortex_m::interrupt::free(|cs| match *G_TEMPERATURE.borrow(cs).borrow() {
+        None => 25.0,
+        Some(sampled_voltage) => (sampled_voltage - TEMPERATURE_V25) / TEMPERATURE_SLOPE + 25.0,
+    })
+}
 
-    cortex_m::interrupt::free(|cs| match G_VIN.borrow(cs).borrow_mut().as_mut() {
-        None => {}
-        Some(sampled_voltage) => {
-            voltage = *sampled_voltage / 3.4 * 12.0;
+///
+///
+/// measures the analog supply VDDA in V, all conversions are scaled with it
+///
+pub fn read_vdda() -> f32 {
+    cortex_m::interrupt::free(|cs| G_VDDA.borrow(cs).borrow().unwrap_or(VREFINT_CAL_VDDA))
+}
+
+///
+///
+/// computes the analog supply from a VREFINT sample and the factory calibration
+///
+/// returns: VDDA in V, the nominal `VREFINT_CAL_VDDA` if the sample or the calibration is implausible
+///
+fn vdda(vrefint: f32) -> f32 {
+    let calibration = unsafe { core::ptr::read_volatile(VREFINT_CAL_ADDRESS as *const u16) };
+    if vrefint < 1.0 || calibration == 0 || calibration == u16::MAX {
+        return VREFINT_CAL_VDDA;
+    }
+    VREFINT_CAL_VDDA * calibration as f32 / vrefint
+}
+
+///
+///
+/// averages each channel over the scans of a transfer
+///
+/// returns: the mean sample of every conversion of the scan, in `SEQUENCE_LEN` order
+///
+fn average_scans(buffer: &[u16]) -> [f32; SEQUENCE_LEN] {
+    let mut sums = [0u32; SEQUENCE_LEN];
+    for scan in buffer.chunks_exact(SEQUENCE_LEN) {
+        for (sum, &sample) in sums.iter_mut().zip(scan) {
+            *sum += sample as u32;
         }
-    });
-    // add some delay
-    CurrentTask::delay(Duration::ms(2));
-    // trigger a new conversion (is this really needed?)
-    cortex_m::interrupt::free(|cs| {
-        if let Some(transfer) = G_XFR.borrow(cs).borrow_mut().as_mut() {
-            transfer.start(|adc| {
-                adc.start_conversion();
-            });
-        };
-    });
-    voltage
+    }
+    let scans = (buffer.len() / SEQUENCE_LEN).max(1) as f32;
+    sums.map(|sum| sum as f32 / scans)
 }
 
+///
+///
+/// the ADC scans continuously and the DMA runs in double buffer mode, so it never stops
+/// and every buffer starts with the first conversion of a scan
+///
+/// the filled buffer is swapped for the spare one, which the DMA did not touch since it was
+/// averaged, so the samples are read while the DMA writes to a different buffer
+///
 #[interrupt]
 #[allow(non_snake_case)]
 fn DMA2_STREAM0() {
     cortex_m::interrupt::free(|cs| {
-        if let Some(xfer) = G_XFR.borrow(cs).borrow_mut().as_mut() {
-            unsafe {
-                if let Ok((buffer, _)) = xfer.next_transfer(&mut ADC_MEMORY) {
-                    let sample_to_millivolts = xfer.peripheral().make_sample_to_millivolts();
-
-                    //println!("DMA1_CH1 IRQ: results: {:?}", buf).unwrap();
-                    G_VBAT.borrow(cs).replace(Some(
-                        sample_to_millivolts(buffer[1]) as f32 / 1000.0,
-                    ));
-                    G_VIN.borrow(cs).replace(Some(
-                        sample_to_millivolts(buffer[2]) as f32 / 1000.0,
-                    ));
-                    G_CURRENT.borrow(cs).replace(Some(
-                        sample_to_millivolts(buffer[3]) as f32 / 1000.0,
-                    ));
-                    G_ADC_BUF.borrow(cs).replace(Some(*buffer));
-                }
+        let mut xfer = G_XFR.borrow(cs).borrow_mut();
+        let mut spare = G_ADC_SPARE.borrow(cs).borrow_mut();
+        let (xfer, buffer) = match (xfer.as_mut(), spare.take()) {
+            (Some(xfer), Some(buffer)) => (xfer, buffer),
+            _ => return,
+        };
+        let buffer = match xfer.next_transfer(buffer) {
+            Ok((filled, _)) => filled,
+            // the interrupt came too late and the DMA already moved on, skip this batch
+            Err(DMAError::NotReady(buffer) | DMAError::SmallBuffer(buffer) | DMAError::Overrun(buffer)) => {
+                *spare = Some(buffer);
+                return;
             }
-        } else {
-            //println!("DMA1_CH1 IRQ: ERR: no xfer").unwrap();
+        };
+
+        let samples = average_scans(buffer);
+        let vdda = vdda(samples[VREFINT_INDEX]);
+        let volts = samples.map(|sample| sample * vdda / MAX_SAMPLE);
+
+        G_VDDA.borrow(cs).replace(Some(vdda));
+        // temperature changes slowly and is not filtered
+        G_TEMPERATURE.borrow(cs).replace(Some(volts[0]));
+        let filters = G_CONFIG.borrow(cs).borrow().filter;
+        let period_ms = SEQUENCE_TIME_MS * (buffer.len() / SEQUENCE_LEN) as f32;
+        let mut state = G_FILTER_STATE.borrow(cs).borrow_mut();
+        for channel in Channel::ALL {
+            let i = channel as usize;
+            let value = state[i].update(filters[i], period_ms, volts[1 + i]);
+            channel.sample().borrow(cs).replace(Some(value));
         }
+        G_ADC_BUF.borrow(cs).replace(Some(samples));
+        *spare = Some(buffer);
     });
 }
@@ -0,0 +1,45 @@
+use ups_core::autonomous::{AutonomousAction, AutonomousConfig, AutonomousShutdown};
+use crate::config::Config;
+use crate::shutdown;
+
+///
+///
+/// runs `AutonomousShutdown` and applies its decision to `G_OUTPUT_CONTROL`, see
+/// `ups_core::autonomous`
+///
+/// `host_active` is true if the host talked to the device since the last call,
+/// `host_present` is true if the device is configured by a host
+///
+/// returns: true if the host is considered lost
+///
+pub fn autonomous_update(
+    policy: &mut AutonomousShutdown,
+    now_ms: u32,
+    host_active: bool,
+    host_present: bool,
+    on_battery: bool,
+    capacity: u8,
+    config: &Config,
+) -> bool {
+    let settings = AutonomousConfig {
+        shutdown_capacity: config.autonomous_shutdown_capacity,
+        grace_s: config.autonomous_grace,
+        host_timeout_s: config.host_timeout,
+    };
+    match policy.update(now_ms, host_active, host_present, on_battery, capacity, &settings) {
+        Some(AutonomousAction::Start(grace_s)) => {
+            if shutdown::start_autonomous_shutdown(grace_s) {
+                log_warn!("host lost at {} %, shutdown in {} s", capacity, grace_s);
+            } else {
+                log_warn!("host lost at {} %, shutdown already scheduled or output not switchable", capacity);
+            }
+        }
+        Some(AutonomousAction::Abort) => {
+            if shutdown::abort_autonomous_shutdown() {
+                log_info!("autonomous shutdown cancelled");
+            }
+        }
+        None => {}
+    }
+    policy.host_lost()
+}
@@ -0,0 +1,156 @@
+use core::fmt;
+use arrform::ArrForm;
+use ups_protocol::{ErrorCode, Frame, FrameReader, Message, StatusMessage, MAX_ENCODED_LEN, MAX_LOG_CHUNK};
+use crate::config::{config, try_update_config, ConfigError};
+use crate::console::UsbWriter;
+use crate::log::G_LOG;
+use crate::serial_number::serial_number;
+use crate::shutdown::output_enabled;
+use crate::telemetry::telemetry_latest;
+
+///
+///
+/// device side of the `ups-protocol` binary protocol on the CDC port
+///
+/// answers the requests of the host and sends events when mains or the status bits change
+///
+pub struct BinaryHost {
+    reader: FrameReader,
+    /// sequence number of the next event
+    event_seq: u8,
+    /// PresentStatus bits of the last record, `None` before the first one
+    last_status: Option<u16>,
+}
+
+impl BinaryHost {
+    pub const fn new() -> Self {
+        BinaryHost {
+            reader: FrameReader::new(),
+            event_seq: 0,
+            last_status: None,
+        }
+    }
+
+    ///
+    ///
+    /// processes received bytes, responses go to `out`
+    ///
+    /// frames that are not valid are dropped, the host retries after its timeout
+    ///
+    pub fn input(&mut self, bytes: &[u8], out: &mut UsbWriter) -> fmt::Result {
+        for &byte in bytes {
+            let request = match self.reader.push(byte).map(Frame::decode) {
+                Some(Ok(frame)) if frame.message.is_request() => frame,
+                _ => continue,
+            };
+            let seq = request.seq;
+            // the response may borrow from these
+            let mut value = ArrForm::<64>::new();
+            let mut chunk = [0; MAX_LOG_CHUNK];
+            let message = respond(request.message, &mut value, &mut chunk);
+            send(out, Frame { seq, message })?;
+        }
+        Ok(())
+    }
+
+    ///
+    ///
+    /// sends the events for changes since the last call, call periodically
+    ///
+    pub fn poll(&mut self, out: &mut UsbWriter) -> fmt::Result {
+        let status = match telemetry_latest() {
+            None => return Ok(()),
+            Some(record) => record.status_word(),
+        };
+        let last_status = match self.last_status.replace(status) {
+            None => return Ok(()),
+            Some(last_status) => last_status,
+        };
+        if status == last_status {
+            return Ok(());
+        }
+        // bit 2 of PresentStatus is ACPresent
+        const AC_PRESENT: u16 = 1 << 2;
+        if (status ^ last_status) & AC_PRESENT != 0 {
+            let present = status & AC_PRESENT != 0;
+            self.event(out, Message::MainsChanged { present })?;
+        }
+        self.event(out, Message::StatusChanged { status })
+    }
+
+    fn event(&mut self, out: &mut UsbWriter, message: Message) -> fmt::Result {
+        let seq = self.event_seq;
+        self.event_seq = self.event_seq.wrapping_add(1);
+        send(out, Frame { seq, message })
+    }
+}
+
+fn send(out: &mut UsbWriter, frame: Frame) -> fmt::Result {
+    let mut encoded = [0; MAX_ENCODED_LEN];
+    let len = frame.encode(&mut encoded).map_err(|_| fmt::Error)?;
+    out.write_bytes(&encoded[..len])
+}
+
+fn config_error(error: ConfigError) -> ErrorCode {
+    match error {
+        ConfigError::UnknownKey => ErrorCode::UnknownKey,
+        ConfigError::InvalidValue => ErrorCode::InvalidValue,
+    }
+}
+
+///
+///
+/// answers one request, `value` and `chunk` hold the data the response refers to
+///
+fn respond<'a>(request: Message, value: &'a mut ArrForm<64>, chunk: &'a mut [u8; MAX_LOG_CHUNK]) -> Message<'a> {
+    match request {
+        Message::GetInfo => Message::Info {
+            firmware_version: env!("CARGO_PKG_VERSION"),
+            serial_number: serial_number(),
+        },
+        Message::GetStatus => match telemetry_latest() {
+            None => Message::Nack(ErrorCode::Failed),
+            Some(record) => Message::Status(StatusMessage {
+                time_ms: record.time_ms,
+                v_bat: record.v_bat,
+                v_in: record.v_in,
+                current: record.current,
+                temperature: record.temperature,
+                capacity: record.capacity,
+                runtime_s: record.runtime_s,
+                status: record.status_word(),
+                output_enabled: output_enabled(),
+            }),
+        },
+        Message::GetConfig { key } => match config().write_value(key, value) {
+            None => Message::Nack(ErrorCode::UnknownKey),
+            Some(Err(_)) => Message::Nack(ErrorCode::Failed),
+            Some(Ok(())) => Message::ConfigValue { value: value.as_str() },
+        },
+        Message::SetConfig { key, value } => {
+            match try_update_config(|config| config.set_value(key, value), ConfigError::InvalidValue) {
+                Err(error) => Message::Nack(config_error(error)),
+                Ok(()) => {
+                    log_info!("config changed");
+                    Message::Ack
+                }
+            }
+        }
+        Message::ReadLog { offset } => {
+            let (len, total) = cortex_m::interrupt::free(|cs| {
+                let log = G_LOG.borrow(cs).borrow();
+                (log.read(offset as usize, chunk), log.len())
+            });
+            Message::LogChunk {
+                offset,
+                total: total as u16,
+                data: &chunk[..len],
+            }
+        }
+        Message::ClearLog => {
+            cortex_m::interrupt::free(|cs| G_LOG.borrow(cs).borrow_mut().clear());
+            Message::Ack
+        }
+        _ => Message::Nack(ErrorCode::UnknownMessage),
+    }
+}
@@ -0,0 +1,456 @@
+use core::cell::RefCell;
+use core::fmt::{self, Write};
+use cortex_m::interrupt::{CriticalSection, Mutex};
+use stm32f4xx_hal::flash::FlashExt;
+use stm32f4xx_hal::pac::FLASH;
+use crate::adc::{Calibration, Channel, Filter, FilterMode, CHANNELS, MAX_FILTER_TIME_MS, MAX_OVERSAMPLING};
+use crate::serial_number::{programmed_serial, PROGRAMMED_SERIAL_LEN};
+use crate::telemetry::{TelemetryFormat, MAX_TELEMETRY_PERIOD_MS, MIN_TELEMETRY_PERIOD_MS};
+use crate::usb::UsbMode;
+use crate::usb_serial::{SerialProtocol, TxPolicy};
+use crate::utils::crc16;
+
+/// last 128K sector of the STM32F405, outside of the 512K used by the firmware (see memory.x)
+const CONFIG_SECTOR: u8 = 11;
+const CONFIG_OFFSET: usize = 0x000E_0000;
+
+/// a change is written once the configuration was left alone this long, so a host that
+/// writes the capacity limits over and over does not erase the sector every time
+const SAVE_DELAY_MS: u32 = 2000;
+
+const CONFIG_MAGIC: u32 = 0x4353_5055; // "UPSC"
+/// layout of the stored configuration, bumped whenever the layout changes
+const CONFIG_VERSION: u8 = 2;
+pub const CONFIG_SIZE: usize = 96;
+/// size of version 1, which only stored the capacity limits
+const CONFIG_SIZE_V1: usize = 64;
+
+///
+///
+/// settings that survive a reset, stored in flash
+///
+#[derive(Clone, Copy)]
+pub struct Config {
+    /// capacity in % below which the host is asked to shut down
+    pub remaining_capacity_limit: u8,
+    /// capacity in % below which the host is warned
+    pub warning_capacity_limit: u8,
+    /// seconds after which an unchanged input report is sent again, 1..=255
+    pub report_keep_alive: u8,
+    /// USB classes used when the mode switch selects HID, see `main`
+    pub usb_mode: UsbMode,
+    /// USB serial number, ASCII padded with 0, all 0 to use the unique device id
+    pub serial_number: [u8; PROGRAMMED_SERIAL_LEN],
+    /// capacity in % at which the UPS shuts down on its own without a host, 0 disables it
+    pub autonomous_shutdown_capacity: u8,
+    /// seconds between reaching `autonomous_shutdown_capacity` and cutting the output, 0..=32767
+    pub autonomous_grace: u16,
+    /// seconds without host activity after which the host counts as lost, 1..=255
+    pub host_timeout: u8,
+    /// correction of the vbat, vin and current measurements, see `adc::Channel`
+    pub calibration: [Calibration; CHANNELS],
+    /// protocol on the CDC port when the mode switch selects the configured mode, see `main`
+    pub serial_protocol: SerialProtocol,
+    /// format of the telemetry stream of the shell, see `telemetry`
+    pub telemetry_format: TelemetryFormat,
+    /// milliseconds between telemetry records, 100..=60000
+    pub telemetry_period: u16,
+    /// what happens to CDC output the host does not read in time
+    pub tx_policy: TxPolicy,
+    /// slave address of the Modbus protocol, 1..=247
+    pub modbus_address: u8,
+    /// ADC scans averaged per sample, 1..=16, takes effect after a reset
+    pub oversampling: u8,
+    /// filter of the vbat, vin and current samples, see `adc::Channel`
+    pub filter: [Filter; CHANNELS],
+}
+
+/// range of a calibration gain, anything outside points to a broken measurement
+const MIN_GAIN: f32 = 0.5;
+const MAX_GAIN: f32 = 2.0;
+/// largest calibration offset in V or A
+const MAX_OFFSET: f32 = 1.0;
+
+/// keys accepted by `Config::write_value` / `Config::set_value`
+pub const CONFIG_KEYS: &[&str] = &[
+    "remaining_capacity_limit",
+    "warning_capacity_limit",
+    "report_keep_alive",
+    "usb_mode",
+    "serial_number",
+    "autonomous_shutdown_capacity",
+    "autonomous_grace",
+    "host_timeout",
+    "gain_vbat",
+    "gain_vin",
+    "gain_current",
+    "offset_vbat",
+    "offset_vin",
+    "offset_current",
+    "serial_protocol",
+    "telemetry_format",
+    "telemetry_period",
+    "tx_policy",
+    "modbus_address",
+    "oversampling",
+    "filter_vbat",
+    "filter_vin",
+    "filter_current",
+    "filter_time_vbat",
+    "filter_time_vin",
+    "filter_time_current",
+];
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ConfigError {
+    UnknownKey,
+    InvalidValue,
+}
+
+impl Config {
+    pub const fn new() -> Self {
+        Config {
+            remaining_capacity_limit: 5,
+            warning_capacity_limit: 10,
+            report_keep_alive: 10,
+            usb_mode: UsbMode::Hid,
+            serial_number: [0; PROGRAMMED_SERIAL_LEN],
+            autonomous_shutdown_capacity: 10,
+            autonomous_grace: 60,
+            host_timeout: 30,
+            calibration: [Calibration::IDENTITY; CHANNELS],
+            serial_protocol: SerialProtocol::Shell,
+            telemetry_format: TelemetryFormat::Csv,
+            telemetry_period: 1000,
+            tx_policy: TxPolicy::Block,
+            modbus_address: 1,
+            oversampling: 16,
+            // smooth vbat for the capacity estimate, keep vin fast to detect outages
+            filter: [
+                Filter {
+                    mode: FilterMode::Iir,
+                    time_ms: 2000,
+                },
+                Filter::OFF,
+                Filter {
+                    mode: FilterMode::Iir,
+                    time_ms: 200,
+                },
+            ],
+        }
+    }
+
+    /// checks the ranges documented on the fields
+    pub fn is_valid(&self) -> bool {
+        self.remaining_capacity_limit <= 100
+            && self.warning_capacity_limit <= 100
+            && self.report_keep_alive != 0
+            && self.autonomous_shutdown_capacity <= 100
+            && self.autonomous_grace <= i16::MAX as u16
+            && self.host_timeout != 0
+            && self.calibration.iter().all(|calibration| {
+                (MIN_GAIN..=MAX_GAIN).contains(&calibration.gain)
+                    && (-MAX_OFFSET..=MAX_OFFSET).contains(&calibration.offset)
+            })
+            && (MIN_TELEMETRY_PERIOD_MS..=MAX_TELEMETRY_PERIOD_MS).contains(&self.telemetry_period)
+            && (1..=247).contains(&self.modbus_address)
+            && (1..=MAX_OVERSAMPLING).contains(&self.oversampling)
+            && self.filter.iter().all(|filter| filter.time_ms <= MAX_FILTER_TIME_MS)
+    }
+
+    ///
+    ///
+    /// writes the value of a configuration key in the format accepted by `set_value`
+    ///
+    /// returns: `None` if the key is unknown
+    ///
+    pub fn write_value(&self, key: &str, out: &mut dyn Write) -> Option<fmt::Result> {
+        let result = match key {
+            "remaining_capacity_limit" => write!(out, "{}", self.remaining_capacity_limit),
+            "warning_capacity_limit" => write!(out, "{}", self.warning_capacity_limit),
+            "report_keep_alive" => write!(out, "{}", self.report_keep_alive),
+            "usb_mode" => out.write_str(self.usb_mode.name()),
+            "serial_number" => out.write_str(programmed_serial(&self.serial_number).unwrap_or("uid")),
+            "autonomous_shutdown_capacity" => write!(out, "{}", self.autonomous_shutdown_capacity),
+            "autonomous_grace" => write!(out, "{}", self.autonomous_grace),
+            "host_timeout" => write!(out, "{}", self.host_timeout),
+            "gain_vbat" => write!(out, "{}", self.calibration[0].gain),
+            "gain_vin" => write!(out, "{}", self.calibration[1].gain),
+            "gain_current" => write!(out, "{}", self.calibration[2].gain),
+            "offset_vbat" => write!(out, "{}", self.calibration[0].offset),
+            "offset_vin" => write!(out, "{}", self.calibration[1].offset),
+            "offset_current" => write!(out, "{}", self.calibration[2].offset),
+            "serial_protocol" => out.write_str(self.serial_protocol.name()),
+            "telemetry_format" => out.write_str(self.telemetry_format.name()),
+            "telemetry_period" => write!(out, "{}", self.telemetry_period),
+            "tx_policy" => out.write_str(self.tx_policy.name()),
+            "modbus_address" => write!(out, "{}", self.modbus_address),
+            "oversampling" => write!(out, "{}", self.oversampling),
+            "filter_vbat" => out.write_str(self.filter[0].mode.name()),
+            "filter_vin" => out.write_str(self.filter[1].mode.name()),
+            "filter_current" => out.write_str(self.filter[2].mode.name()),
+            "filter_time_vbat" => write!(out, "{}", self.filter[0].time_ms),
+            "filter_time_vin" => write!(out, "{}", self.filter[1].time_ms),
+            "filter_time_current" => write!(out, "{}", self.filter[2].time_ms),
+            _ => return None,
+        };
+        Some(result)
+    }
+
+    ///
+    ///
+    /// changes a configuration key, the configuration is left untouched if the value is invalid
+    ///
+    /// USB and serial protocol settings take effect after a reset
+    ///
+    pub fn set_value(&mut self, key: &str, value: &str) -> Result<(), ConfigError> {
+        fn parse<T: core::str::FromStr>(value: &str) -> Result<T, ConfigError> {
+            value.parse().map_err(|_| ConfigError::InvalidValue)
+        }
+        // the channel name is the suffix of the filter keys
+        fn filter_channel(key: &str) -> Result<usize, ConfigError> {
+            let channel = key.rsplit('_').next().and_then(Channel::from_name);
+            Ok(channel.ok_or(ConfigError::UnknownKey)? as usize)
+        }
+        let mut config = *self;
+        match key {
+            "remaining_capacity_limit" => config.remaining_capacity_limit = parse(value)?,
+            "warning_capacity_limit" => config.warning_capacity_limit = parse(value)?,
+            "report_keep_alive" => config.report_keep_alive = parse(value)?,
+            "usb_mode" => config.usb_mode = UsbMode::from_name(value).ok_or(ConfigError::InvalidValue)?,
+            "serial_number" => {
+                // "uid" goes back to the unique device id
+                let mut serial_number = [0; PROGRAMMED_SERIAL_LEN];
+                if value != "uid" {
+                    if value.len() > PROGRAMMED_SERIAL_LEN {
+                        return Err(ConfigError::InvalidValue);
+                    }
+                    serial_number[..value.len()].copy_from_slice(value.as_bytes());
+                    programmed_serial(&serial_number).ok_or(ConfigError::InvalidValue)?;
+                }
+                config.serial_number = serial_number;
+            }
+            "autonomous_shutdown_capacity" => config.autonomous_shutdown_capacity = parse(value)?,
+            "autonomous_grace" => config.autonomous_grace = parse(value)?,
+            "host_timeout" => config.host_timeout = parse(value)?,
+            "gain_vbat" => config.calibration[0].gain = parse(value)?,
+            "gain_vin" => config.calibration[1].gain = parse(value)?,
+            "gain_current" => config.calibration[2].gain = parse(value)?,
+            "offset_vbat" => config.calibration[0].offset = parse(value)?,
+            "offset_vin" => config.calibration[1].offset = parse(value)?,
+            "offset_current" => config.calibration[2].offset = parse(value)?,
+            "serial_protocol" => {
+                config.serial_protocol = SerialProtocol::from_name(value).ok_or(ConfigError::InvalidValue)?
+            }
+            "telemetry_format" => {
+                config.telemetry_format = TelemetryFormat::from_name(value).ok_or(ConfigError::InvalidValue)?
+            }
+            "telemetry_period" => config.telemetry_period = parse(value)?,
+            "tx_policy" => config.tx_policy = TxPolicy::from_name(value).ok_or(ConfigError::InvalidValue)?,
+            "modbus_address" => config.modbus_address = parse(value)?,
+            "oversampling" => config.oversampling = parse(value)?,
+            "filter_vbat" | "filter_vin" | "filter_current" => {
+                let mode = FilterMode::from_name(value).ok_or(ConfigError::InvalidValue)?;
+                config.filter[filter_channel(key)?].mode = mode;
+            }
+            "filter_time_vbat" | "filter_time_vin" | "filter_time_current" => {
+                config.filter[filter_channel(key)?].time_ms = parse(value)?
+            }
+            _ => return Err(ConfigError::UnknownKey),
+        }
+        if !config.is_valid() {
+            return Err(ConfigError::InvalidValue);
+        }
+        *self = config;
+        Ok(())
+    }
+
+    pub fn to_bytes(&self) -> [u8; CONFIG_SIZE] {
+        let mut bytes = [0xFF; CONFIG_SIZE];
+        bytes[0..4].copy_from_slice(&CONFIG_MAGIC.to_le_bytes());
+        bytes[4] = CONFIG_VERSION;
+        bytes[5] = self.remaining_capacity_limit;
+        bytes[6] = self.warning_capacity_limit;
+        bytes[7] = self.report_keep_alive;
+        bytes[8] = self.usb_mode.to_u8();
+        // byte 9 held the index of the removed USB identity profiles
+        bytes[10..10 + PROGRAMMED_SERIAL_LEN].copy_from_slice(&self.serial_number);
+        bytes[26] = self.autonomous_shutdown_capacity;
+        bytes[27..29].copy_from_slice(&self.autonomous_grace.to_le_bytes());
+        bytes[29] = self.host_timeout;
+        for (i, calibration) in self.calibration.iter().enumerate() {
+            bytes[30 + 4 * i..34 + 4 * i].copy_from_slice(&calibration.gain.to_le_bytes());
+            bytes[48 + 4 * i..52 + 4 * i].copy_from_slice(&calibration.offset.to_le_bytes());
+        }
+        bytes[42] = self.serial_protocol.to_u8();
+        bytes[43] = self.telemetry_format.to_u8();
+        bytes[44..46].copy_from_slice(&self.telemetry_period.to_le_bytes());
+        bytes[46] = self.tx_policy.to_u8();
+        bytes[47] = self.modbus_address;
+        bytes[60] = self.oversampling;
+        for (i, filter) in self.filter.iter().enumerate() {
+            bytes[61 + i] = filter.mode.to_u8();
+            bytes[64 + 2 * i..66 + 2 * i].copy_from_slice(&filter.time_ms.to_le_bytes());
+        }
+        let crc = crc16(&bytes[..CONFIG_SIZE - 2]);
+        bytes[CONFIG_SIZE - 2..].copy_from_slice(&crc.to_le_bytes());
+        bytes
+    }
+
+    ///
+    ///
+    /// reads a configuration of this version or of version 1, which only stored the
+    /// capacity limits, all other fields keep their defaults then
+    ///
+    /// returns: `None` if the bytes do not contain a valid configuration
+    ///
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < CONFIG_SIZE
+            || u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) != CONFIG_MAGIC
+        {
+            return None;
+        }
+        let version = bytes[4];
+        let size = match version {
+            1 => CONFIG_SIZE_V1,
+            CONFIG_VERSION => CONFIG_SIZE,
+            _ => return None,
+        };
+        let bytes = &bytes[..size];
+        let crc = u16::from_le_bytes([bytes[size - 2], bytes[size - 1]]);
+        if crc16(&bytes[..size - 2]) != crc {
+            return None;
+        }
+        let mut config = Config::new();
+        config.remaining_capacity_limit = bytes[5];
+        config.warning_capacity_limit = bytes[6];
+        if version == CONFIG_VERSION {
+            config.report_keep_alive = bytes[7];
+            config.usb_mode = UsbMode::from_u8(bytes[8])?;
+            config.serial_number.copy_from_slice(&bytes[10..10 + PROGRAMMED_SERIAL_LEN]);
+            config.autonomous_shutdown_capacity = bytes[26];
+            config.autonomous_grace = u16::from_le_bytes([bytes[27], bytes[28]]);
+            config.host_timeout = bytes[29];
+            for (i, calibration) in config.calibration.iter_mut().enumerate() {
+                calibration.gain = f32::from_le_bytes(bytes[30 + 4 * i..34 + 4 * i].try_into().ok()?);
+                calibration.offset = f32::from_le_bytes(bytes[48 + 4 * i..52 + 4 * i].try_into().ok()?);
+            }
+            config.serial_protocol = SerialProtocol::from_u8(bytes[42])?;
+            config.telemetry_format = TelemetryFormat::from_u8(bytes[43])?;
+            config.telemetry_period = u16::from_le_bytes([bytes[44], bytes[45]]);
+            config.tx_policy = TxPolicy::from_u8(bytes[46])?;
+            config.modbus_address = bytes[47];
+            config.oversampling = bytes[60];
+            for (i, filter) in config.filter.iter_mut().enumerate() {
+                filter.mode = FilterMode::from_u8(bytes[61 + i])?;
+                filter.time_ms = u16::from_le_bytes([bytes[64 + 2 * i], bytes[65 + 2 * i]]);
+            }
+        }
+        if !config.is_valid() {
+            return None;
+        }
+        Some(config)
+    }
+}
+
+pub static G_CONFIG: Mutex<RefCell<Config>> = Mutex::new(RefCell::new(Config::new()));
+pub static G_CONFIG_DIRTY: Mutex<RefCell<bool>> = Mutex::new(RefCell::new(false));
+/// tick at which `save_config()` first saw the pending change, restarted by every `update_config()`
+pub static G_CONFIG_DIRTY_SINCE: Mutex<RefCell<Option<u32>>> = Mutex::new(RefCell::new(None));
+pub static G_FLASH: Mutex<RefCell<Option<FLASH>>> = Mutex::new(RefCell::new(None));
+
+///
+///
+/// loads the configuration from flash, falls back to the defaults if there is none
+///
+pub fn config_init(flash: FLASH) {
+    let config = Config::from_bytes(&flash.read()[CONFIG_OFFSET..]).unwrap_or(Config::new());
+    cortex_m::interrupt::free(|cs| {
+        *G_CONFIG.borrow(cs).borrow_mut() = config;
+        *G_FLASH.borrow(cs).borrow_mut() = Some(flash);
+    });
+}
+
+pub fn config() -> Config {
+    cortex_m::interrupt::free(|cs| *G_CONFIG.borrow(cs).borrow())
+}
+
+///
+///
+/// changes the configuration in RAM, it is written to flash by the next `save_config()`
+///
+pub fn update_config(f: impl FnOnce(&mut Config)) {
+    cortex_m::interrupt::free(|cs| {
+        f(&mut G_CONFIG.borrow(cs).borrow_mut());
+        mark_dirty(cs);
+    });
+}
+
+///
+///
+/// changes the configuration in RAM like `update_config`, `f` changes a copy that only
+/// replaces the configuration if `f` succeeds and the result is valid
+///
+/// the copy is taken and written back in the same critical section, so a change made by
+/// an interrupt in between is not reverted
+///
+/// returns: the error of `f`, `invalid` if the changed configuration is not valid
+///
+pub fn try_update_config<E>(f: impl FnOnce(&mut Config) -> Result<(), E>, invalid: E) -> Result<(), E> {
+    cortex_m::interrupt::free(|cs| {
+        let mut config = G_CONFIG.borrow(cs).borrow_mut();
+        let mut changed = *config;
+        f(&mut changed)?;
+        if !changed.is_valid() {
+            return Err(invalid);
+        }
+        *config = changed;
+        mark_dirty(cs);
+        Ok(())
+    })
+}
+
+fn mark_dirty(cs: &CriticalSection) {
+    *G_CONFIG_DIRTY.borrow(cs).borrow_mut() = true;
+    *G_CONFIG_DIRTY_SINCE.borrow(cs).borrow_mut() = None;
+}
+
+///
+///
+/// writes the configuration to flash once it has not changed for `SAVE_DELAY_MS`, call periodically
+///
+/// erasing the sector stalls the cpu for about a second, so never call this from an interrupt,
+/// interrupts stay enabled while the flash is erased and programmed
+///
+pub fn save_config(now: u32) {
+    let pending = cortex_m::interrupt::free(|cs| {
+        if !*G_CONFIG_DIRTY.borrow(cs).borrow() {
+            return None;
+        }
+        let since = *G_CONFIG_DIRTY_SINCE.borrow(cs).borrow_mut().get_or_insert(now);
+        if now.wrapping_sub(since) < SAVE_DELAY_MS {
+            return None;
+        }
+        // a change made while the flash is written marks the configuration dirty again
+        let flash = G_FLASH.borrow(cs).borrow_mut().take()?;
+        *G_CONFIG_DIRTY.borrow(cs).borrow_mut() = false;
+        *G_CONFIG_DIRTY_SINCE.borrow(cs).borrow_mut() = None;
+        Some((flash, G_CONFIG.borrow(cs).borrow().to_bytes()))
+    });
+    let (mut flash, bytes) = match pending {
+        Some(pending) => pending,
+        None => return,
+    };
+
+    let written = {
+        let mut unlocked = flash.unlocked();
+        unlocked.erase(CONFIG_SECTOR).is_ok() && unlocked.program(CONFIG_OFFSET, bytes.iter()).is_ok()
+    };
+
+    cortex_m::interrupt::free(|cs| {
+        *G_FLASH.borrow(cs).borrow_mut() = Some(flash);
+        if !written {
+            *G_CONFIG_DIRTY.borrow(cs).borrow_mut() = true;
+        }
+    });
+}
@@ -0,0 +1,378 @@
+use core::fmt::{self, Write};
+use freertos_rust::{CurrentTask, Duration};
+use micromath::F32Ext;
+use crate::adc::{
+    read_current, read_temperature, read_v_bat, read_v_in, read_vdda, Calibration, CalibrationPoint, Channel,
+    G_CALIBRATION_LOW,
+};
+use crate::config::{config, try_update_config, ConfigError, CONFIG_KEYS};
+use crate::identity::IDENTITY;
+use crate::log::{log_dump, Level, G_LOG, G_LOG_CDC, G_LOG_FILTER, LOG_MODULES};
+use ups_core::megatec::{MegatecContext, UpsRating, UpsStatus};
+use ups_core::modbus::{Exception, ModbusContext};
+use ups_core::shell::{CalibrationStep, ShellContext, ShellError};
+use crate::shutdown::{
+    cancel_shutdown, output_enabled, reboot_delay, set_reboot_delay, set_shutdown_delay, set_shutdown_with_restore,
+    shutdown_delay, OUTPUT_SWITCHABLE,
+};
+use crate::telemetry::{telemetry_latest, Record};
+use crate::usb::usb_state;
+use crate::usb_serial::{usb_available, usb_rx_counters, usb_tx_counters, usb_write};
+use crate::utils::battery_capacity;
+
+/// output voltage of the boost converter
+const OUTPUT_VOLTAGE: f32 = 12.0;
+/// output current the load percentage refers to
+const RATED_CURRENT: f32 = 3.0;
+/// two Li-Ion cells in series
+const NOMINAL_BATTERY_VOLTAGE: f32 = 2.0 * 3.7;
+
+///
+///
+/// `Write` for the CDC port, queues the data for the USB interrupt
+///
+pub struct UsbWriter;
+
+impl UsbWriter {
+    /// fails if the host did not take the bytes in time, see `usb_write`
+    pub fn write_bytes(&mut self, bytes: &[u8]) -> fmt::Result {
+        if usb_write(bytes) < bytes.len() {
+            return Err(fmt::Error);
+        }
+        Ok(())
+    }
+}
+
+impl Write for UsbWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.write_bytes(s.as_bytes())
+    }
+}
+
+fn config_error(error: ConfigError) -> ShellError {
+    match error {
+        ConfigError::UnknownKey => ShellError::UnknownKey,
+        ConfigError::InvalidValue => ShellError::InvalidValue,
+    }
+}
+
+fn write_delay(out: &mut dyn Write, seconds: i16) -> fmt::Result {
+    if seconds < 0 {
+        out.write_str("-")
+    } else {
+        write!(out, "{} s", seconds)
+    }
+}
+
+///
+///
+/// `ShellContext` and `MegatecContext` of the firmware
+///
+pub struct Console;
+
+impl ShellContext for Console {
+    fn status(&mut self, out: &mut dyn Write) -> fmt::Result {
+        let v_bat = read_v_bat();
+        let v_in = read_v_in();
+        let current = read_current();
+        let output_enabled = output_enabled();
+        write!(out, "v_bat: {:.2} V, v_in: {:.2} V, current: {:.3} A\r\n", v_bat, v_in, current)?;
+        write!(out, "vdda: {:.3} V\r\n", read_vdda())?;
+        write!(
+            out,
+            "capacity: {} %, mains: {}\r\n",
+            battery_capacity(v_bat),
+            if v_in > 10.0 { "present" } else { "lost" }
+        )?;
+        write!(out, "output: {}, shutdown in: ", if output_enabled { "on" } else { "off" })?;
+        write_delay(out, shutdown_delay())?;
+        out.write_str(", reboot in: ")?;
+        write_delay(out, reboot_delay())?;
+        write!(out, "\r\nusb: {:?}\r\n", usb_state())?;
+        let (received, dropped) = usb_rx_counters();
+        write!(out, "cdc rx: {} bytes, {} dropped, {} buffered\r\n", received, dropped, usb_available())?;
+        let (sent, dropped) = usb_tx_counters();
+        write!(out, "cdc tx: {} bytes, {} dropped\r\n", sent, dropped)
+    }
+
+    fn config_keys(&self) -> &'static [&'static str] {
+        CONFIG_KEYS
+    }
+
+    fn config_get(&self, key: &str, out: &mut dyn Write) -> Result<(), ShellError> {
+        match config().write_value(key, out) {
+            None => Err(ShellError::UnknownKey),
+            Some(result) => result.map_err(|_| ShellError::Failed("output failed")),
+        }
+    }
+
+    fn config_set(&mut self, key: &str, value: &str) -> Result<(), ShellError> {
+        try_update_config(|config| config.set_value(key, value), ConfigError::InvalidValue).map_err(config_error)?;
+        log_info!("config changed");
+        Ok(())
+    }
+
+    fn log_dump(&mut self, out: &mut dyn Write) -> fmt::Result {
+        log_dump(out)
+    }
+
+    fn log_clear(&mut self) {
+        cortex_m::interrupt::free(|cs| G_LOG.borrow(cs).borrow_mut().clear());
+    }
+
+    fn log_levels(&self, out: &mut dyn Write) -> fmt::Result {
+        let (default, levels) = cortex_m::interrupt::free(|cs| {
+            let filter = G_LOG_FILTER.borrow(cs).borrow();
+            let mut levels = [Level::Info; LOG_MODULES.len()];
+            for (level, module) in levels.iter_mut().zip(LOG_MODULES) {
+                *level = filter.level(module);
+            }
+            (filter.default_level(), levels)
+        });
+        write!(out, "default = {}\r\n", default.name())?;
+        for (module, level) in LOG_MODULES.iter().zip(levels.iter()) {
+            write!(out, "{} = {}\r\n", module, level.name())?;
+        }
+        Ok(())
+    }
+
+    fn set_log_level(&mut self, module: Option<&str>, level: &str) -> Result<(), ShellError> {
+        let level = match (module, level) {
+            (Some(_), "default") => None,
+            _ => Some(Level::from_name(level).ok_or(ShellError::InvalidValue)?),
+        };
+        cortex_m::interrupt::free(|cs| {
+            let mut filter = G_LOG_FILTER.borrow(cs).borrow_mut();
+            match (module, level) {
+                (None, Some(level)) => {
+                    filter.set_default(level);
+                    Ok(())
+                }
+                (Some(module), level) if filter.set(module, level) => Ok(()),
+                _ => Err(ShellError::Failed("unknown module, try 'log level'")),
+            }
+        })
+    }
+
+    fn set_log_cdc(&mut self, on: bool) {
+        cortex_m::interrupt::free(|cs| *G_LOG_CDC.borrow(cs).borrow_mut() = on);
+    }
+
+    fn reset(&mut self) {
+        // leave the host time to receive the reply
+        CurrentTask::delay(Duration::ms(100));
+        cortex_m::peripheral::SCB::sys_reset();
+    }
+
+    fn calibration(&mut self, out: &mut dyn Write) -> fmt::Result {
+        for channel in Channel::ALL {
+            let calibration = channel.calibration();
+            let raw = channel.read_raw();
+            write!(
+                out,
+                "{}: gain {:.4}, offset {:.4}, raw {:.3}, calibrated {:.3}\r\n",
+                channel.name(),
+                calibration.gain,
+                calibration.offset,
+                raw,
+                calibration.apply(raw)
+            )?;
+        }
+        Ok(())
+    }
+
+    fn calibrate(&mut self, channel: &str, step: CalibrationStep) -> Result<(), ShellError> {
+        let channel = Channel::from_name(channel).ok_or(ShellError::InvalidValue)?;
+        let index = channel as usize;
+        let point = |reference| CalibrationPoint {
+            raw: channel.read_raw(),
+            reference,
+        };
+        let calibration = match step {
+            CalibrationStep::Single(reference) => channel
+                .calibration()
+                .with_point(point(reference))
+                .ok_or(ShellError::Failed("measured value is too small to calibrate against"))?,
+            CalibrationStep::Low(reference) => {
+                let low = point(reference);
+                cortex_m::interrupt::free(|cs| G_CALIBRATION_LOW.borrow(cs).borrow_mut()[index] = Some(low));
+                return Ok(());
+            }
+            CalibrationStep::High(reference) => {
+                let low = cortex_m::interrupt::free(|cs| G_CALIBRATION_LOW.borrow(cs).borrow_mut()[index].take())
+                    .ok_or(ShellError::Failed("no low point, calibrate the low point first"))?;
+                Calibration::from_points(low, point(reference))
+                    .ok_or(ShellError::Failed("points too close together, increase the difference"))?
+            }
+            CalibrationStep::Reset => Calibration::IDENTITY,
+        };
+        try_update_config(
+            |config| {
+                config.calibration[index] = calibration;
+                Ok(())
+            },
+            ShellError::Failed("gain or offset out of range, check the reference"),
+        )?;
+        log_info!(
+            "calibration of {} changed to gain {}, offset {}",
+            channel.name(),
+            calibration.gain,
+            calibration.offset
+        );
+        Ok(())
+    }
+}
+
+impl MegatecContext for Console {
+    fn status(&mut self) -> UpsStatus {
+        let v_bat = read_v_bat();
+        let v_in = read_v_in();
+        let current = read_current();
+        let output_enabled = output_enabled();
+        let utility_fail = v_in <= 10.0;
+        UpsStatus {
+            input_voltage: v_in,
+            output_voltage: if output_enabled { OUTPUT_VOLTAGE } else { 0.0 },
+            load: (current.max(0.0) / RATED_CURRENT * 100.0).min(255.0) as u8,
+            battery_voltage: v_bat,
+            temperature: read_temperature(),
+            utility_fail,
+            // same threshold as the BelowRemainingCapacityLimit status bit
+            battery_low: utility_fail && battery_capacity(v_bat) < config().remaining_capacity_limit,
+            shutdown_active: shutdown_delay() >= 0 || !output_enabled,
+        }
+    }
+
+    fn rating(&self) -> UpsRating {
+        UpsRating {
+            voltage: OUTPUT_VOLTAGE,
+            current: RATED_CURRENT as u8,
+            battery_voltage: NOMINAL_BATTERY_VOLTAGE,
+        }
+    }
+
+    fn info(&self) -> (&'static str, &'static str, &'static str) {
+        (IDENTITY.manufacturer, IDENTITY.product, env!("CARGO_PKG_VERSION"))
+    }
+
+    fn shutdown(&mut self, delay_s: u16, restore_s: u32) {
+        if !OUTPUT_SWITCHABLE {
+            log_warn!("shutdown requested, the output can not be switched on this board");
+            return;
+        }
+        set_shutdown_with_restore(delay_s, restore_s);
+        log_info!("shutdown in {} s requested, restore after {} s", delay_s, restore_s);
+    }
+
+    fn cancel_shutdown(&mut self) {
+        cancel_shutdown();
+        log_info!("shutdown cancelled");
+    }
+}
+
+/// number of PresentStatus bits served as discrete inputs
+const STATUS_BITS: u16 = 14;
+
+impl ModbusContext for Console {
+    fn input_register(&mut self, address: u16) -> Option<u16> {
+        // all 0 until the USB task published the first measurements
+        let record = telemetry_latest();
+        let value = |f: fn(&Record) -> u16| record.as_ref().map_or(0, f);
+        let register = match address {
+            0 => value(|record| (record.v_in * 100.0) as u16),
+            1 => value(|record| (record.v_bat * 100.0) as u16),
+            2 => value(|record| (record.current * 1000.0) as i16 as u16),
+            3 => value(|record| record.capacity as u16),
+            4 => value(|record| record.runtime_s),
+            5 => value(|record| record.status_word()),
+            6 => value(|record| (record.average_current * 1000.0) as i16 as u16),
+            7 => value(|record| (record.temperature * 10.0) as i16 as u16),
+            _ => return None,
+        };
+        Some(register)
+    }
+
+    fn holding_register(&mut self, address: u16) -> Option<u16> {
+        let config = config();
+        let register = match address {
+            0 => config.remaining_capacity_limit as u16,
+            1 => config.warning_capacity_limit as u16,
+            2 => shutdown_delay() as u16,
+            3 => reboot_delay() as u16,
+            4 => config.autonomous_shutdown_capacity as u16,
+            5 => config.autonomous_grace,
+            6 => config.host_timeout as u16,
+            _ => return None,
+        };
+        Some(register)
+    }
+
+    fn write_holding_register(&mut self, address: u16, value: u16) -> Result<(), Exception> {
+        if address == 2 || address == 3 {
+            let delay = value as i16;
+            if delay < -1 {
+                return Err(Exception::IllegalDataValue);
+            }
+            if !OUTPUT_SWITCHABLE {
+                return Err(Exception::ServerDeviceFailure);
+            }
+            if address == 2 {
+                set_shutdown_delay(delay);
+            } else {
+                set_reboot_delay(delay);
+            }
+            log_info!("delay register {} set to {}", address, delay);
+            return Ok(());
+        }
+        let byte = u8::try_from(value).map_err(|_| Exception::IllegalDataValue);
+        try_update_config(
+            |config| {
+                match address {
+                    0 => config.remaining_capacity_limit = byte?,
+                    1 => config.warning_capacity_limit = byte?,
+                    4 => config.autonomous_shutdown_capacity = byte?,
+                    5 => config.autonomous_grace = value,
+                    6 => config.host_timeout = byte?,
+                    _ => return Err(Exception::IllegalDataAddress),
+                }
+                Ok(())
+            },
+            Exception::IllegalDataValue,
+        )?;
+        log_info!("config changed");
+        Ok(())
+    }
+
+    fn coil(&mut self, address: u16) -> Option<bool> {
+        match address {
+            0 => Some(output_enabled()),
+            _ => None,
+        }
+    }
+
+    fn write_coil(&mut self, address: u16, value: bool) -> Result<(), Exception> {
+        if address != 0 {
+            return Err(Exception::IllegalDataAddress);
+        }
+        if !OUTPUT_SWITCHABLE {
+            return Err(Exception::ServerDeviceFailure);
+        }
+        if value {
+            cancel_shutdown();
+            log_info!("output switched on");
+        } else {
+            // cut now, the output comes back once mains is present
+            set_shutdown_delay(0);
+            log_info!("output switched off");
+        }
+        Ok(())
+    }
+
+    fn discrete_input(&mut self, address: u16) -> Option<bool> {
+        if address >= STATUS_BITS {
+            return None;
+        }
+        let status = telemetry_latest().map_or(0, |record| record.status_word());
+        Some(status & (1 << address) != 0)
+    }
+}
@@ -1 +1,2 @@
-pub mod led;
\ No newline at end of file
+pub mod led;
+pub mod output;
\ No newline at end of file
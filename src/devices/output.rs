@@ -0,0 +1,39 @@
+use stm32f4xx_hal::gpio::{Output, Pin};
+
+///
+///
+/// enable line of the TPS61378 boost converter, pulling it low disconnects the load
+///
+/// note: on the current pcb revision EN is strapped to VCC, it has to be wired to
+/// GPIO1 (PB15) and the firmware built with the `load-switch` feature to be able to
+/// switch the output
+///
+pub struct LoadSwitch<const P: char, const N: u8> {
+    pub pin: Pin<P, N, Output>,
+    state: bool,
+}
+
+impl<const P: char, const N: u8> LoadSwitch<P, N> {
+    pub fn new(
+        mut pin: Pin<P, N, Output>) -> Self {
+        pin.set_high();
+        LoadSwitch {
+            pin,
+            state: true,
+        }
+    }
+
+    pub fn on(&mut self) {
+        self.pin.set_high();
+        self.state = true;
+    }
+
+    pub fn off(&mut self) {
+        self.pin.set_low();
+        self.state = false;
+    }
+
+    pub fn is_on(&self) -> bool {
+        self.state
+    }
+}
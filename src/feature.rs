@@ -0,0 +1,158 @@
+use ups_hid::report::*;
+use crate::config::{config, update_config};
+use crate::shutdown;
+
+pub const HID_REQ_GET_REPORT: u8 = 0x01;
+pub const HID_REQ_GET_IDLE: u8 = 0x02;
+pub const HID_REQ_GET_PROTOCOL: u8 = 0x03;
+pub const HID_REQ_SET_REPORT: u8 = 0x09;
+pub const HID_REQ_SET_IDLE: u8 = 0x0A;
+pub const HID_REQ_SET_PROTOCOL: u8 = 0x0B;
+
+pub const HID_DESCRIPTOR_TYPE: u8 = 0x21;
+pub const HID_REPORT_DESCRIPTOR_TYPE: u8 = 0x22;
+
+pub const HID_REPORT_TYPE_INPUT: u8 = 0x01;
+pub const HID_REPORT_TYPE_FEATURE: u8 = 0x03;
+
+/// seconds of runtime below which the UPS asks the host to shut down, until the host sets it
+pub const DEFAULT_REMAINING_TIME_LIMIT: u16 = 600;
+
+///
+///
+/// current value of every feature report declared in `Report::DESCRIPTOR`
+///
+/// the USB task keeps the measured values up to date, the OTG_FS interrupt
+/// answers GET_REPORT / SET_REPORT requests of the host from here
+///
+pub struct FeatureReports {
+    pub status: Status,
+    pub rechargeable: u8,
+    pub capacity_mode: u8,
+    pub capacity_granularity_1: u8,
+    pub capacity_granularity_2: u8,
+    pub full_charge_capacity: u8,
+    pub design_capacity: u8,
+    pub remaining_capacity: u8,
+    pub manufacture_date: u16,
+    pub average_time_to_full: u16,
+    pub average_time_to_empty: u16,
+    pub run_time_to_empty: u16,
+    pub remaining_time_limit: u16,
+    pub config_voltage: u16,
+    pub voltage: u16,
+    pub current: i16,
+    pub average_current: i16,
+    pub audible_alarm_control: u8,
+}
+
+impl FeatureReports {
+    pub fn new() -> Self {
+        FeatureReports {
+            status: Status::new(),
+            rechargeable: 1,
+            capacity_mode: 2, // percent
+            capacity_granularity_1: 1,
+            capacity_granularity_2: 1,
+            full_charge_capacity: 100,
+            design_capacity: 100,
+            remaining_capacity: 100,
+            manufacture_date: manufacture_date(2024, 9, 26),
+            average_time_to_full: 7200,
+            average_time_to_empty: 0,
+            run_time_to_empty: 0,
+            remaining_time_limit: DEFAULT_REMAINING_TIME_LIMIT,
+            config_voltage: 740, // 2 x 3.7 V in cV
+            voltage: 0,
+            current: 0,
+            average_current: 0,
+            audible_alarm_control: 2, // disabled
+        }
+    }
+
+    ///
+    ///
+    /// returns the report with the given id, `None` if the id is not declared in the descriptor
+    ///
+    /// iDeviceChemistry and iOEMInformation carry the string indexes allocated on the bus,
+    /// `PowerDevice` answers them
+    ///
+    pub fn get(&self, id: u8) -> Option<Report> {
+        let report = match id {
+            HID_PD_IPRODUCT => Report::IProduct(IPRODUCT),
+            HID_PD_SERIAL => Report::SerialNumber(ISERIAL),
+            HID_PD_MANUFACTURER => Report::Manufacturer(IMANUFACTURER),
+            HID_PD_RECHARGEABLE => Report::Rechargeable(self.rechargeable),
+            HID_PD_CAPACITYMODE => Report::CapacityMode(self.capacity_mode),
+            HID_PD_CPCTYGRANULARITY1 => Report::CapacityGranularity1(self.capacity_granularity_1),
+            HID_PD_CPCTYGRANULARITY2 => Report::CapacityGranularity2(self.capacity_granularity_2),
+            HID_PD_FULLCHRGECAPACITY => Report::FullChargeCapacity(self.full_charge_capacity),
+            HID_PD_DESIGNCAPACITY => Report::DesignCapacity(self.design_capacity),
+            HID_PD_REMAININGCAPACITY => Report::RemainingCapacity(self.remaining_capacity),
+            HID_PD_WARNCAPACITYLIMIT => Report::WarningCapacityLimit(config().warning_capacity_limit),
+            HID_PD_REMNCAPACITYLIMIT => Report::RemainingCapacityLimit(config().remaining_capacity_limit),
+            HID_PD_MANUFACTUREDATE => Report::ManufactureDate(self.manufacture_date),
+            HID_PD_AVERAGETIME2FULL => Report::AverageTimeToFull(self.average_time_to_full),
+            HID_PD_AVERAGETIME2EMPTY => Report::AverageTimeToEmpty(self.average_time_to_empty),
+            HID_PD_RUNTIMETOEMPTY => Report::RunTimeToEmpty(self.run_time_to_empty),
+            HID_PD_REMAINTIMELIMIT => Report::RemainingTimeLimit(self.remaining_time_limit),
+            HID_PD_DELAYBE4SHUTDOWN => Report::DelayBeforeShutdown(shutdown::shutdown_delay()),
+            HID_PD_DELAYBE4REBOOT => Report::DelayBeforeReboot(shutdown::reboot_delay()),
+            HID_PD_CONFIGVOLTAGE => Report::ConfigVoltage(self.config_voltage),
+            HID_PD_VOLTAGE => Report::Voltage(self.voltage),
+            HID_PD_CURRENT => Report::Current(self.current),
+            HID_PD_AVERAGECURRENT => Report::AverageCurrent(self.average_current),
+            HID_PD_AUDIBLEALARMCTRL => Report::AudibleAlarmControl(self.audible_alarm_control),
+            HID_PD_PRESENTSTATUS => Report::PresentStatus(self.status),
+            _ => return None,
+        };
+        Some(report)
+    }
+
+    ///
+    ///
+    /// stores a report written by the host, only reports declared as Data are accepted
+    ///
+    /// `data` is the payload of the SET_REPORT request, starting with the report id
+    ///
+    /// returns: true if the value was accepted
+    ///
+    pub fn set(&mut self, id: u8, data: &[u8]) -> bool {
+        let report = match Report::decode(data) {
+            Some(report) if report.id() == id => report,
+            _ => return false,
+        };
+        match report {
+            Report::CapacityGranularity1(value) if value <= 100 => {
+                self.capacity_granularity_1 = value;
+            }
+            Report::WarningCapacityLimit(value) if value <= 100 => {
+                update_config(|config| config.warning_capacity_limit = value);
+            }
+            Report::RemainingCapacityLimit(value) if value <= 100 => {
+                update_config(|config| config.remaining_capacity_limit = value);
+            }
+            Report::AudibleAlarmControl(value) if (1..=3).contains(&value) => {
+                self.audible_alarm_control = value;
+            }
+            Report::RemainingTimeLimit(value) if (120..=1380).contains(&value) => {
+                self.remaining_time_limit = value;
+            }
+            // stalled if the output can not be cut, NUT then reports the failed shutdown
+            Report::DelayBeforeShutdown(value) if shutdown::OUTPUT_SWITCHABLE => shutdown::set_shutdown_delay(value),
+            Report::DelayBeforeReboot(value) if shutdown::OUTPUT_SWITCHABLE => shutdown::set_reboot_delay(value),
+            _ => return false,
+        }
+        true
+    }
+}
+
+///
+///
+/// encodes a date as expected by the ManufactureDate usage (Smart Battery format)
+///
+/// returns: u16
+///
+pub const fn manufacture_date(year: u16, month: u16, day: u16) -> u16 {
+    ((year - 1980) << 9) | (month << 5) | day
+}
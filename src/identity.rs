@@ -0,0 +1,65 @@
+//! USB identity.
+//!
+//! VID/PID, strings and the report descriptor the host uses to identify the UPS.
+
+use ups_hid::descriptor::*;
+use ups_hid::parser::check;
+use ups_hid::report::*;
+use crate::serial_number::serial_number;
+
+/// largest report descriptor, `usb_hid` copies it into a buffer of this size
+pub const MAX_DESCRIPTOR_LEN: usize = 512;
+
+///
+///
+/// everything the host uses to identify the UPS
+///
+pub struct UsbIdentity {
+    pub vid: u16,
+    pub pid: u16,
+    pub manufacturer: &'static str,
+    pub product: &'static str,
+    pub oem_vendor: &'static str,
+    pub descriptor: &'static [u8],
+}
+
+impl UsbIdentity {
+    ///
+    ///
+    /// string table for every string index referenced in the report descriptor
+    ///
+    /// returns: Option<&str>
+    ///
+    pub fn string(&self, index: u8) -> Option<&'static str> {
+        match index {
+            IMANUFACTURER => Some(self.manufacturer),
+            IPRODUCT => Some(self.product),
+            ISERIAL => Some(serial_number()),
+            IDEVICECHEMISTRY => Some(STRING_DEVICE_CHEMISTRY),
+            IOEMVENDOR => Some(self.oem_vendor),
+            _ => None,
+        }
+    }
+}
+
+/// the report descriptor of `REPORTS`, checked at compile time
+const DESCRIPTOR: &[u8] = {
+    const LEN: usize = build::<0>(REPORTS, &[]).len;
+    const BYTES: [u8; LEN] = build::<LEN>(REPORTS, &[]).bytes;
+    const _: () = match check(&BYTES, REPORTS, &[], HID_PD_PRESENTSTATUS, core::mem::size_of::<Status>() * 8) {
+        Ok(()) => (),
+        Err(mismatch) => panic!("{}", mismatch.message()),
+    };
+    &BYTES
+};
+
+const _: () = assert!(DESCRIPTOR.len() <= MAX_DESCRIPTOR_LEN, "report descriptor is too large");
+
+pub static IDENTITY: UsbIdentity = UsbIdentity {
+    vid: 0x03f0,
+    pid: 0x1f06,
+    manufacturer: "hacknus",
+    product: "UPS",
+    oem_vendor: "hacknus",
+    descriptor: DESCRIPTOR,
+};
@@ -0,0 +1,218 @@
+//! Leveled logging with per-module filters.
+//!
+//! Messages are written with `log_error!` .. `log_trace!`, the level filter is checked
+//! before anything is formatted. Enabled messages go to the RAM ring buffer (`log` in
+//! the shell), to RTT and, if switched on, to the CDC console. The macros are visible in
+//! every module declared after `log` in `main`.
+
+use core::cell::RefCell;
+use core::fmt::{self, Write};
+use arrform::ArrForm;
+use cortex_m::interrupt::Mutex;
+use freertos_rust::FreeRtosUtils;
+use ups_core::ring_buffer::LogBuffer;
+use crate::usb_serial::usb_write_nonblocking;
+
+/// RAM kept for the event log, the oldest lines are dropped when it is full
+pub const LOG_SIZE: usize = 1024;
+/// longest line stored, longer messages are truncated
+pub const LOG_LINE_LEN: usize = 96;
+
+/// ring buffer of log lines, see `ups_core::ring_buffer::LogBuffer`
+pub type EventLog = LogBuffer<LOG_SIZE, LOG_LINE_LEN>;
+
+pub static G_LOG: Mutex<RefCell<EventLog>> = Mutex::new(RefCell::new(EventLog::new()));
+
+#[derive(Clone, Copy, PartialEq, PartialOrd, Debug)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl Level {
+    pub const fn name(&self) -> &'static str {
+        match self {
+            Level::Error => "error",
+            Level::Warn => "warn",
+            Level::Info => "info",
+            Level::Debug => "debug",
+            Level::Trace => "trace",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "error" => Some(Level::Error),
+            "warn" => Some(Level::Warn),
+            "info" => Some(Level::Info),
+            "debug" => Some(Level::Debug),
+            "trace" => Some(Level::Trace),
+            _ => None,
+        }
+    }
+
+    /// single letter in front of every line
+    const fn tag(&self) -> char {
+        match self {
+            Level::Error => 'E',
+            Level::Warn => 'W',
+            Level::Info => 'I',
+            Level::Debug => 'D',
+            Level::Trace => 'T',
+        }
+    }
+}
+
+/// modules with their own level, `main` stands for the crate root
+pub const LOG_MODULES: &[&str] = &[
+    "main",
+    "usb",
+    "usb_hid",
+    "usb_serial",
+    "adc",
+    "config",
+    "shutdown",
+    "autonomous",
+    "console",
+    "binary",
+    "telemetry",
+];
+
+///
+///
+/// level per module, modules without their own level use the default
+///
+pub struct LogFilter {
+    default: Level,
+    modules: [Option<Level>; LOG_MODULES.len()],
+}
+
+impl LogFilter {
+    pub const fn new() -> Self {
+        LogFilter {
+            default: Level::Info,
+            modules: [None; LOG_MODULES.len()],
+        }
+    }
+
+    pub fn level(&self, module: &str) -> Level {
+        LOG_MODULES
+            .iter()
+            .position(|name| *name == module)
+            .and_then(|i| self.modules[i])
+            .unwrap_or(self.default)
+    }
+
+    pub fn default_level(&self) -> Level {
+        self.default
+    }
+
+    pub fn set_default(&mut self, level: Level) {
+        self.default = level;
+    }
+
+    ///
+    ///
+    /// sets the level of one module, `None` goes back to the default
+    ///
+    /// returns: false if the module is not in `LOG_MODULES`
+    ///
+    pub fn set(&mut self, module: &str, level: Option<Level>) -> bool {
+        match LOG_MODULES.iter().position(|name| *name == module) {
+            None => false,
+            Some(i) => {
+                self.modules[i] = level;
+                true
+            }
+        }
+    }
+}
+
+pub static G_LOG_FILTER: Mutex<RefCell<LogFilter>> = Mutex::new(RefCell::new(LogFilter::new()));
+/// the CDC sink is off by default, log lines would garble the output of the other protocols
+pub static G_LOG_CDC: Mutex<RefCell<bool>> = Mutex::new(RefCell::new(false));
+
+/// name of the module in `LOG_MODULES` for a `module_path!()`
+pub fn module_name(module_path: &str) -> &str {
+    match module_path.split_once("::") {
+        None => "main",
+        Some((_, module)) => module,
+    }
+}
+
+pub fn log_enabled(level: Level, module_path: &str) -> bool {
+    let module = module_name(module_path);
+    cortex_m::interrupt::free(|cs| level <= G_LOG_FILTER.borrow(cs).borrow().level(module))
+}
+
+///
+///
+/// formats a message and hands it to the sinks if the filter lets it pass, only call
+/// from tasks, use the `log_*!` macros
+///
+pub fn log_at(level: Level, module_path: &str, args: fmt::Arguments) {
+    if !log_enabled(level, module_path) {
+        return;
+    }
+    let ms = FreeRtosUtils::get_tick_count(); // 1 tick = 1 ms
+    let mut line = ArrForm::<LOG_LINE_LEN>::new();
+    // a message that does not fit is truncated
+    line.format(format_args!(
+        "[{:6}.{:03}] {} {}: {}",
+        ms / 1000,
+        ms % 1000,
+        level.tag(),
+        module_name(module_path),
+        args
+    ))
+    .ok();
+    let cdc = cortex_m::interrupt::free(|cs| {
+        G_LOG.borrow(cs).borrow_mut().push(line.as_str());
+        *G_LOG_CDC.borrow(cs).borrow()
+    });
+    rtt_target::rprintln!("{}", line.as_str());
+    // a log call must never wait for the host to read
+    if cdc {
+        usb_write_nonblocking(line.as_bytes());
+        usb_write_nonblocking(b"\r\n");
+    }
+}
+
+macro_rules! log_error {
+    ($($arg:tt)*) => { $crate::log::log_at($crate::log::Level::Error, module_path!(), format_args!($($arg)*)) };
+}
+
+macro_rules! log_warn {
+    ($($arg:tt)*) => { $crate::log::log_at($crate::log::Level::Warn, module_path!(), format_args!($($arg)*)) };
+}
+
+macro_rules! log_info {
+    ($($arg:tt)*) => { $crate::log::log_at($crate::log::Level::Info, module_path!(), format_args!($($arg)*)) };
+}
+
+macro_rules! log_debug {
+    ($($arg:tt)*) => { $crate::log::log_at($crate::log::Level::Debug, module_path!(), format_args!($($arg)*)) };
+}
+
+macro_rules! log_trace {
+    ($($arg:tt)*) => { $crate::log::log_at($crate::log::Level::Trace, module_path!(), format_args!($($arg)*)) };
+}
+
+///
+///
+/// writes every line of the event log to `out`
+///
+pub fn log_dump(out: &mut dyn Write) -> core::fmt::Result {
+    // work on a copy, `out` may block and must not be called with interrupts disabled
+    let log = cortex_m::interrupt::free(|cs| G_LOG.borrow(cs).borrow().clone());
+    let mut result = Ok(());
+    log.for_each_line(|line| {
+        if result.is_ok() {
+            result = write!(out, "{}\r\n", line);
+        }
+    });
+    result
+}
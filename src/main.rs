@@ -21,40 +21,69 @@ use stm32f4xx_hal::{
     prelude::*,
 };
 use crate::devices::led::LED;
+use crate::devices::output::LoadSwitch;
 use crate::intrpt::{G_BUTTON, G_STATE};
+use crate::shutdown::G_OUTPUT_CONTROL;
+use crate::autonomous::autonomous_update;
+use ups_core::autonomous::AutonomousShutdown;
+use crate::config::{config, config_init, save_config};
 
 use freertos_rust::*;
 use core::alloc::Layout;
 use core::borrow::BorrowMut;
 use core::f32::consts::PI;
-use crate::report::{HID_PD_PRESENTSTATUS, HID_PD_REMAININGCAPACITY, HID_PD_RUNTIMETOEMPTY, Report, Status};
-use modular_bitfield_to_value::ToValue;
-use stm32f4xx_hal::adc::config::{AdcConfig, Dma, SampleTime, Scan, Sequence};
-use stm32f4xx_hal::adc::{Adc, Temperature};
+use ups_hid::report::{Report, Status};
+use ups_core::report_scheduler::ReportScheduler;
+use stm32f4xx_hal::adc::config::{AdcConfig, Continuous, Dma, SampleTime, Scan, Sequence};
+use stm32f4xx_hal::adc::{Adc, Temperature, Vref};
 use stm32f4xx_hal::dma::config::DmaConfig;
 use stm32f4xx_hal::dma::{StreamsTuple, Transfer};
 use stm32f4xx_hal::timer::Channel4;
-use crate::adc::{read_current, read_v_bat, read_v_in, ADC_MEMORY, G_XFR};
-use arrform::{arrform, ArrForm};
+use crate::adc::{read_current, read_temperature, read_v_bat, read_v_in, ADC_MEMORY, G_ADC_SPARE, G_XFR, SEQUENCE_LEN};
 
+#[macro_use]
+mod log;
 mod devices;
 mod intrpt;
+mod usb;
 mod usb_hid;
-mod report;
+mod identity;
+mod feature;
+mod shutdown;
+mod autonomous;
+mod serial_number;
+mod config;
 mod adc;
 mod utils;
 mod usb_serial;
+mod telemetry;
+mod binary;
+mod console;
+
+/// the USB task samples the measurements and polls the report scheduler at this rate
+const USB_TASK_PERIOD_MS: u32 = 20;
+const AVERAGE_CURRENT_TIME_CONSTANT_MS: f32 = 9000.0;
 
 #[global_allocator]
 static GLOBAL: FreeRtosAllocator = FreeRtosAllocator;
 
 
-use crate::usb_hid::{G_USB_HID, usb_hid_init, G_USB_DEVICE, G_USB_HID_MODE};
-use crate::usb_serial::{usb_println, usb_serial_init};
-use crate::utils::LEDState;
+use crate::usb::{usb_init, usb_remote_wakeup, usb_state, UsbMode};
+use usb_device::device::UsbDeviceState;
+use crate::usb_hid::{G_USB_HID, hid_host_activity, hid_remaining_time_limit, hid_send_report};
+use crate::usb_serial::{usb_read, usb_read_line, SerialProtocol};
+use crate::console::{Console, UsbWriter};
+use ups_core::shell::Shell;
+use ups_core::megatec::{Megatec, LINE_LEN};
+use crate::telemetry::{telemetry_publish, Record, Telemetry};
+use crate::binary::BinaryHost;
+use ups_core::modbus::ModbusSlave;
+use crate::utils::{battery_capacity, LEDState};
 
 #[entry]
 fn main() -> ! {
+    rtt_target::rtt_init_print!();
+
     let mut dp = pac::Peripherals::take().unwrap();
 
     let rcc = dp.RCC.constrain();
@@ -79,6 +108,9 @@ fn main() -> ! {
     let gpioe = dp.GPIOE.split();
     let _gpiod = dp.GPIOD.split();
 
+    // load persistent configuration
+    config_init(dp.FLASH);
+
     // initialize dma
     let dma2 = StreamsTuple::new(dp.DMA2);
 
@@ -87,6 +119,9 @@ fn main() -> ! {
 
     let mut usb_led1 = LED::new(gpioc.pc13.into_push_pull_output());
 
+    // initialize load switch (boost converter enable)
+    let mut load_switch = LoadSwitch::new(gpiob.pb15.into_push_pull_output());
+
     // initialize pwm timer 3
     let mut stat_led_pwm = dp
         .TIM3
@@ -118,17 +153,17 @@ fn main() -> ! {
     };
     delay.delay(100.millis());
 
-    let hid_mode = sw.is_high();
-    cortex_m::interrupt::free(|cs| {
-        *G_USB_HID_MODE.borrow(cs).borrow_mut() = hid_mode;
-    });
+    // the switch selects the configured mode (HID only by default, as before the console
+    // existed) or the plain CDC console, the console always runs the shell so a
+    // misconfigured port can be recovered
+    let (usb_mode, serial_protocol) = if sw.is_high() {
+        (config().usb_mode, config().serial_protocol)
+    } else {
+        (UsbMode::Serial, SerialProtocol::Shell)
+    };
 
     unsafe {
-        if hid_mode {
-            usb_hid_init(usb);
-        } else {
-            usb_serial_init(usb);
-        }
+        usb_init(usb, usb_mode);
         cortex_m::peripheral::NVIC::unmask(Interrupt::OTG_FS);
         cortex_m::peripheral::NVIC::unmask(Interrupt::DMA2_STREAM0);
         // Enable the external interrupt in the NVIC by passing the button interrupt number
@@ -143,10 +178,13 @@ fn main() -> ! {
     let dma_config = DmaConfig::default()
         .transfer_complete_interrupt(true)
         .memory_increment(true)
-        .double_buffer(false);
+        .double_buffer(true);
 
+    // the ADC scans continuously, every DMA buffer holds `oversampling` scans, the DMA
+    // switches between two of them without stopping, see `adc::DMA2_STREAM0`
     let adc_config = AdcConfig::default()
         .dma(Dma::Continuous)
+        .continuous(Continuous::Continuous)
         .scan(Scan::Enabled);
     let mut adc = Adc::adc1(dp.ADC1, true, adc_config);
 
@@ -154,17 +192,22 @@ fn main() -> ! {
     adc.configure_channel(&adc_vbat, Sequence::Two, SampleTime::Cycles_480);
     adc.configure_channel(&adc_vin, Sequence::Three, SampleTime::Cycles_480);
     adc.configure_channel(&adc_current, Sequence::Four, SampleTime::Cycles_480);
+    adc.configure_channel(&Vref, Sequence::Five, SampleTime::Cycles_480);
     adc.enable_temperature_and_vref();
 
-    // let adc_buffer = cortex_m::singleton!(: [u16; 2] = [0; 2]).unwrap();
-    let mut transfer = unsafe {
-        Transfer::init_peripheral_to_memory(dma2.0, adc, &mut ADC_MEMORY, None, dma_config)
+    let len = SEQUENCE_LEN * config().oversampling as usize;
+    let (mut transfer, spare) = unsafe {
+        let [first, second, spare] = &mut ADC_MEMORY;
+        let transfer =
+            Transfer::init_peripheral_to_memory(dma2.0, adc, &mut first[..len], Some(&mut second[..len]), dma_config);
+        (transfer, &mut spare[..len])
     };
 
     transfer.start(|adc| {
         adc.start_conversion();
     });
     cortex_m::interrupt::free(|cs| {
+        G_ADC_SPARE.borrow(cs).replace(Some(spare));
         G_XFR.borrow(cs).replace(Some(transfer));
     });
 
@@ -184,6 +227,8 @@ fn main() -> ! {
         }
     }
 
+    log_info!("boot, usb mode {}, serial protocol {}", usb_mode.name(), serial_protocol.name());
+
     let led_state = LEDState::SlowBreathing;
     let led_state_container =
         Arc::new(Mutex::new(led_state).expect("Failed to create led state guard mutex"));
@@ -207,105 +252,242 @@ fn main() -> ! {
             let mut supply_present = false;
             let mut capacity = 0;
             let mut remaining_seconds = 0;
+            let mut average_current = 0.0;
+            let mut scheduler = ReportScheduler::new(config().report_keep_alive as u32 * 1000);
+            let mut autonomous_shutdown = AutonomousShutdown::new();
 
-            let battery_capacity = 2.0 * 3.7 * 2100.0; // Wh
+            let battery_energy_wh = 2.0 * 3.7 * 2100.0; // Wh
 
-            let mut remaining_capacity_report = Report::new_u8(HID_PD_REMAININGCAPACITY, 50);
-            let mut runtime_empty_report = Report::new_u16(HID_PD_RUNTIMETOEMPTY, 1);
             let mut status = Status::new();
             status.set_charging(1);
             status.set_ac_present(1);
             status.set_battery_present(0);
 
             let mut led_state = LEDState::SlowBreathing;
-
-            let mut status_report = Report::new_u16(HID_PD_PRESENTSTATUS, status.to_u16_le().unwrap());
+            let mut last_tick = FreeRtosUtils::get_tick_count();
             loop {
+                let now = FreeRtosUtils::get_tick_count();
+                let elapsed_ms = now.wrapping_sub(last_tick); // 1 tick = 1 ms
+                last_tick = now;
+
                 current = read_current();
                 vbat = read_v_bat();
                 vin = read_v_in();
+                let mains_lost = supply_present && vin <= 10.0;
+                if mains_lost {
+                    log_warn!("mains lost");
+                } else if !supply_present && vin > 10.0 {
+                    log_info!("mains present");
+                }
                 supply_present = vin > 10.0;
+                // the loop runs late while the flash is erased or other tasks hold the cpu
+                let alpha = (elapsed_ms as f32 / AVERAGE_CURRENT_TIME_CONSTANT_MS).min(1.0);
+                average_current += (current - average_current) * alpha;
+
+                // Voltage in cV, Current and AverageCurrent in mA (see units in Report::DESCRIPTOR)
+                let voltage_cv = (vbat * 100.0) as u16;
+                let current_ma = (current * 1000.0) as i16;
+                let average_current_ma = (average_current * 1000.0) as i16;
 
                 if vbat < 4.1 * 2.0 && supply_present {
                     status.set_charging(1);
                     status.set_discharging(0);
-                    status_report.update_u16_value(status.to_u16_le().unwrap());
                 } else if !supply_present {
                     status.set_charging(0);
                     status.set_discharging(1);
-                    status_report.update_u16_value(status.to_u16_le().unwrap());
                 }
 
                 if supply_present {
                     status.set_charging(1);
                     status.set_ac_present(1);
                     status.set_battery_present(0);
-                    status_report.update_u16_value(status.to_u16_le().unwrap());
                     led_state = LEDState::SlowBreathing;
                 } else {
                     status.set_ac_present(0);
                     status.set_charging(0);
                     status.set_battery_present(1);
-                    status_report.update_u16_value(status.to_u16_le().unwrap());
                     led_state = LEDState::FastBreathing;
                 }
 
-                if vbat < 3.5 * 2.0 {
-                    status.set_remaining_time_limit_expired(1);
-                    status.set_shutdown_requested(1);
-                    status_report.update_u16_value(status.to_u16_le().unwrap());
-                }
+                capacity = battery_capacity(vbat);
+                remaining_seconds = (battery_energy_wh / vbat / current) as u16;
+
+                // thresholds are set by the host through the capacity limit feature reports
+                // the warning limit is only evaluated by the host, below the remaining
+                // limit the UPS reports a low battery and asks the host to shut down
+                let limits = config();
+                let low = capacity < limits.remaining_capacity_limit && !supply_present;
+                // the remaining time limit is set by the host through its feature report
+                let time_expired = remaining_seconds < hid_remaining_time_limit() && !supply_present;
+                status.set_below_remaining_capacity_limit(low as u8);
+                status.set_remaining_time_limit_expired(time_expired as u8);
+                status.set_shutdown_requested((low || time_expired) as u8);
 
                 if vbat < 3.2 * 2.0 {
                     status.set_shutdown_imminent(1);
-                    status_report.update_u16_value(status.to_u16_le().unwrap());
+                } else {
+                    status.set_shutdown_imminent(0);
                 }
 
-                if hid_mode {
-                    cortex_m::interrupt::free(|cs| {
-                        if let Some(hid) = G_USB_HID.borrow(cs).borrow_mut().as_mut() {
-                            hid.send_report(&status_report);
-                        };
-                        usb_led1.toggle();
-                    });
+                // reports are paused while the host is suspended or not yet configured,
+                // a mains loss wakes the host if it allowed remote wakeup
+                let usb_state = usb_state();
+                let connected = usb_state == UsbDeviceState::Configured;
+                if mains_lost && usb_state == UsbDeviceState::Suspend {
+                    usb_remote_wakeup();
                 }
-                CurrentTask::delay(Duration::ms(300));
-
 
-                capacity = (100.0 / (4.15 * 2.0 - 3.3 * 2.0) * (vbat - 3.3 * 2.0)) as u8;
-                remaining_seconds = (battery_capacity / vbat / current) as u16;
-
-                remaining_capacity_report = Report::new_u8(HID_PD_REMAININGCAPACITY, capacity);
-                if hid_mode {
+                // without HID there is no way to tell if the host listens, a configured device has to do
+                let host_active = if usb_mode.has_hid() { hid_host_activity() } else { connected };
+                let host_lost = autonomous_update(
+                    &mut autonomous_shutdown,
+                    now,
+                    host_active,
+                    connected,
+                    !supply_present,
+                    capacity,
+                    &limits,
+                );
+                status.set_communication_lost(host_lost as u8);
+
+                if usb_mode.has_hid() {
                     cortex_m::interrupt::free(|cs| {
                         if let Some(hid) = G_USB_HID.borrow(cs).borrow_mut().as_mut() {
-                            hid.send_report(&remaining_capacity_report);
+                            hid.features.status = status;
+                            hid.features.remaining_capacity = capacity;
+                            hid.features.run_time_to_empty = remaining_seconds;
+                            hid.features.average_time_to_empty = remaining_seconds;
+                            hid.features.voltage = voltage_cv;
+                            hid.features.current = current_ma;
+                            hid.features.average_current = average_current_ma;
                         };
                     });
-                }
-                CurrentTask::delay(Duration::ms(300));
 
+                    scheduler.set_keep_alive(config().report_keep_alive as u32 * 1000);
+                    scheduler.update(Report::PresentStatus(status));
+                    scheduler.update(Report::RemainingCapacity(capacity));
+                    scheduler.update(Report::RunTimeToEmpty(remaining_seconds));
+                    scheduler.update(Report::Voltage(voltage_cv));
+                    scheduler.update(Report::Current(current_ma));
+                    scheduler.update(Report::AverageCurrent(average_current_ma));
+
+                    // the interrupt endpoint holds one report at a time, send at most one per loop
+                    if let Some(report) = scheduler.next(now).filter(|_| connected) {
+                        if hid_send_report(&report) {
+                            scheduler.sent(&report, now);
+                            usb_led1.toggle();
+                        }
+                    }
+                }
+                // streamed by the serial task
+                telemetry_publish(Record {
+                    time_ms: now,
+                    v_bat: vbat,
+                    v_in: vin,
+                    current,
+                    average_current,
+                    temperature: read_temperature(),
+                    capacity,
+                    runtime_s: remaining_seconds,
+                    status,
+                    led_state,
+                });
 
-                runtime_empty_report = Report::new_u16(HID_PD_RUNTIMETOEMPTY, remaining_seconds);
-                if hid_mode {
-                    cortex_m::interrupt::free(|cs| {
-                        if let Some(hid) = G_USB_HID.borrow(cs).borrow_mut().as_mut() {
-                            hid.send_report(&runtime_empty_report);
-                        };
-                    });
+                if let Ok(mut guard) = led_state_container_main.lock(Duration::ms(1)) {
+                    *guard = led_state.clone();
                 }
 
+                save_config(now);
+
+                CurrentTask::delay(Duration::ms(USB_TASK_PERIOD_MS));
+            }
+        }).unwrap();
 
-                if hid_mode {} else {
-                    usb_println(arrform!(128, "v_bat: {}, v_in: {}, current: {}, remaining seconds: {}",vbat, vin, current, remaining_seconds ).as_str());
-                    usb_led1.toggle();
+    if usb_mode.has_serial() {
+        Task::new()
+            .name("SERIAL TASK")
+            .stack_size(1024)
+            .priority(TaskPriority(2))
+            .start(move || {
+                let mut shell = Shell::new();
+                let mut megatec = Megatec::new();
+                let mut telemetry = Telemetry::new();
+                let mut binary = BinaryHost::new();
+                let mut modbus = ModbusSlave::new(config().modbus_address);
+                let mut console = Console;
+                let mut out = UsbWriter;
+                let mut buffer = [0; 64];
+                let mut line = [0; LINE_LEN];
+                loop {
+                    // Megatec commands are single lines, waiting for one replaces the delay below
+                    if serial_protocol == SerialProtocol::Megatec {
+                        // overlong lines are no command and a timeout only means the host is quiet
+                        if let Ok(len) = usb_read_line(&mut line, 10) {
+                            if len > 0 {
+                                let command = core::str::from_utf8(&line[..len]).unwrap_or("");
+                                megatec.execute(command, &mut console, &mut out).ok();
+                            }
+                        }
+                        continue;
+                    }
+                    let received = usb_read(&mut buffer);
+                    if received > 0 {
+                        let bytes = &buffer[..received];
+                        match serial_protocol {
+                            SerialProtocol::Shell => shell.input(bytes, &mut console, &mut out).ok(),
+                            // read line by line above
+                            SerialProtocol::Megatec => None,
+                            SerialProtocol::Binary => binary.input(bytes, &mut out).ok(),
+                            SerialProtocol::Modbus => {
+                                let now = FreeRtosUtils::get_tick_count(); // 1 tick = 1 ms
+                                modbus.input(bytes, now, &mut console, &mut |frame| {
+                                    out.write_bytes(frame).ok();
+                                });
+                                Some(())
+                            }
+                        };
+                    }
+                    let connected = usb_state() == UsbDeviceState::Configured;
+                    // the telemetry stream would garble the replies of other protocols
+                    if serial_protocol == SerialProtocol::Shell && connected {
+                        let config = config();
+                        let now = FreeRtosUtils::get_tick_count(); // 1 tick = 1 ms
+                        telemetry.poll(now, config.telemetry_format, config.telemetry_period, &mut out).ok();
+                    } else {
+                        telemetry.restart();
+                    }
+                    if serial_protocol == SerialProtocol::Binary && connected {
+                        binary.poll(&mut out).ok();
+                    }
+                    CurrentTask::delay(Duration::ms(10));
                 }
+            }).unwrap();
+    }
 
-                if let Ok(mut guard) = led_state_container_main.lock(Duration::ms(1)) {
-                    *guard = led_state.clone();
+    Task::new()
+        .name("OUTPUT TASK")
+        .stack_size(256)
+        .priority(TaskPriority(3))
+        .start(move || {
+            let mut last_tick = FreeRtosUtils::get_tick_count();
+            loop {
+                let mains_present = read_v_in() > 10.0;
+                let now = FreeRtosUtils::get_tick_count();
+                let elapsed_ms = now.wrapping_sub(last_tick); // 1 tick = 1 ms
+                last_tick = now;
+
+                let output_enabled = cortex_m::interrupt::free(|cs| {
+                    G_OUTPUT_CONTROL.borrow(cs).borrow_mut().tick(elapsed_ms, mains_present)
+                });
+                if output_enabled != load_switch.is_on() {
+                    if output_enabled {
+                        load_switch.on();
+                    } else {
+                        load_switch.off();
+                    }
                 }
 
-                CurrentTask::delay(Duration::ms(300));
+                CurrentTask::delay(Duration::ms(100));
             }
         }).unwrap();
 
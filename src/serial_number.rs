@@ -0,0 +1,64 @@
+/// 96 bit unique device id of the STM32F4
+const UID_ADDRESS: usize = 0x1FFF_7A10;
+
+/// 24 hex digits of the unique device id
+pub const SERIAL_NUMBER_LEN: usize = 24;
+/// length of a serial number programmed into the configuration
+pub const PROGRAMMED_SERIAL_LEN: usize = 16;
+
+// written once by `serial_number_init` before the USB device is created, read only afterwards
+static mut SERIAL_NUMBER: [u8; SERIAL_NUMBER_LEN] = [0; SERIAL_NUMBER_LEN];
+static mut SERIAL_NUMBER_USED: usize = 0;
+
+///
+///
+/// checks a serial number programmed into the configuration, unused bytes are 0
+///
+/// returns: the serial number, `None` if none is programmed or it is not printable ASCII
+///
+pub fn programmed_serial(bytes: &[u8; PROGRAMMED_SERIAL_LEN]) -> Option<&str> {
+    let len = bytes.iter().position(|&byte| byte == 0).unwrap_or(PROGRAMMED_SERIAL_LEN);
+    let serial = &bytes[..len];
+    if serial.is_empty() || !serial.iter().all(|byte| byte.is_ascii_graphic()) {
+        return None;
+    }
+    core::str::from_utf8(serial).ok()
+}
+
+///
+///
+/// builds the serial number reported over USB, the programmed one if it is valid,
+/// otherwise the unique device id in hex
+///
+/// called once by `usb_init` before the USB device is created
+///
+pub unsafe fn serial_number_init(programmed: &[u8; PROGRAMMED_SERIAL_LEN]) {
+    let mut serial = [0; SERIAL_NUMBER_LEN];
+    let len = match programmed_serial(programmed) {
+        Some(programmed) => {
+            serial[..programmed.len()].copy_from_slice(programmed.as_bytes());
+            programmed.len()
+        }
+        None => {
+            // most significant word first
+            for word in 0..3 {
+                let uid = core::ptr::read_volatile((UID_ADDRESS + 4 * (2 - word)) as *const u32);
+                for digit in 0..8 {
+                    let nibble = (uid >> (28 - 4 * digit)) & 0x0F;
+                    serial[8 * word + digit] = b"0123456789ABCDEF"[nibble as usize];
+                }
+            }
+            SERIAL_NUMBER_LEN
+        }
+    };
+    SERIAL_NUMBER = serial;
+    SERIAL_NUMBER_USED = len;
+}
+
+///
+///
+/// returns: the serial number built by `serial_number_init`
+///
+pub fn serial_number() -> &'static str {
+    unsafe { core::str::from_utf8(&SERIAL_NUMBER[..SERIAL_NUMBER_USED]).unwrap_or("") }
+}
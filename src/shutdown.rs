@@ -0,0 +1,65 @@
+use core::cell::RefCell;
+use cortex_m::interrupt::Mutex;
+use ups_core::shutdown::OutputControl;
+
+///
+///
+/// the EN line of the boost converter is wired to GPIO1 (PB15), see `LoadSwitch`
+///
+/// on the current pcb revision it is strapped to VCC, so unless the firmware is built with
+/// the `load-switch` feature the shutdown and reboot countdowns are rejected instead of
+/// pretending to cut the output
+///
+pub const OUTPUT_SWITCHABLE: bool = cfg!(feature = "load-switch");
+
+pub static G_OUTPUT_CONTROL: Mutex<RefCell<OutputControl>> =
+    Mutex::new(RefCell::new(OutputControl::new()));
+
+pub fn set_shutdown_delay(seconds: i16) {
+    cortex_m::interrupt::free(|cs| G_OUTPUT_CONTROL.borrow(cs).borrow_mut().set_shutdown_delay(seconds));
+}
+
+pub fn set_reboot_delay(seconds: i16) {
+    cortex_m::interrupt::free(|cs| G_OUTPUT_CONTROL.borrow(cs).borrow_mut().set_reboot_delay(seconds));
+}
+
+pub fn set_shutdown_with_restore(seconds: u16, restore_after_s: u32) {
+    cortex_m::interrupt::free(|cs| {
+        G_OUTPUT_CONTROL.borrow(cs).borrow_mut().set_shutdown_with_restore(seconds, restore_after_s)
+    });
+}
+
+///
+///
+/// returns: false if the output can not be switched or another shutdown countdown is running
+///
+pub fn start_autonomous_shutdown(seconds: u16) -> bool {
+    OUTPUT_SWITCHABLE
+        && cortex_m::interrupt::free(|cs| {
+            G_OUTPUT_CONTROL.borrow(cs).borrow_mut().start_autonomous_shutdown(seconds)
+        })
+}
+
+///
+///
+/// returns: true if the countdown started by `start_autonomous_shutdown` was aborted
+///
+pub fn abort_autonomous_shutdown() -> bool {
+    cortex_m::interrupt::free(|cs| G_OUTPUT_CONTROL.borrow(cs).borrow_mut().abort_autonomous_shutdown())
+}
+
+pub fn cancel_shutdown() {
+    cortex_m::interrupt::free(|cs| G_OUTPUT_CONTROL.borrow(cs).borrow_mut().cancel());
+}
+
+pub fn output_enabled() -> bool {
+    cortex_m::interrupt::free(|cs| G_OUTPUT_CONTROL.borrow(cs).borrow().output_enabled())
+}
+
+pub fn shutdown_delay() -> i16 {
+    cortex_m::interrupt::free(|cs| G_OUTPUT_CONTROL.borrow(cs).borrow().shutdown_delay())
+}
+
+pub fn reboot_delay() -> i16 {
+    cortex_m::interrupt::free(|cs| G_OUTPUT_CONTROL.borrow(cs).borrow().reboot_delay())
+}
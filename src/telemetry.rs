@@ -0,0 +1,224 @@
+use core::cell::RefCell;
+use core::fmt::{self, Write};
+use cortex_m::interrupt::Mutex;
+use ups_hid::report::Status;
+use crate::utils::LEDState;
+
+/// range of `Config::telemetry_period`
+pub const MIN_TELEMETRY_PERIOD_MS: u16 = 100;
+pub const MAX_TELEMETRY_PERIOD_MS: u16 = 60_000;
+
+///
+///
+/// format of the telemetry stream on the CDC console
+///
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum TelemetryFormat {
+    Off,
+    /// comma separated values, a header line precedes the first record
+    Csv,
+    /// one JSON object per line
+    Json,
+}
+
+impl TelemetryFormat {
+    pub const fn to_u8(self) -> u8 {
+        match self {
+            TelemetryFormat::Off => 0,
+            TelemetryFormat::Csv => 1,
+            TelemetryFormat::Json => 2,
+        }
+    }
+
+    pub const fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(TelemetryFormat::Off),
+            1 => Some(TelemetryFormat::Csv),
+            2 => Some(TelemetryFormat::Json),
+            _ => None,
+        }
+    }
+
+    pub const fn name(&self) -> &'static str {
+        match self {
+            TelemetryFormat::Off => "off",
+            TelemetryFormat::Csv => "csv",
+            TelemetryFormat::Json => "json",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "off" => Some(TelemetryFormat::Off),
+            "csv" => Some(TelemetryFormat::Csv),
+            "json" => Some(TelemetryFormat::Json),
+            _ => None,
+        }
+    }
+}
+
+/// `Status` bits in the order they are written
+const STATUS_FLAGS: [(&str, fn(&Status) -> u8); 14] = [
+    ("charging", Status::charging),
+    ("discharging", Status::discharging),
+    ("ac_present", Status::ac_present),
+    ("battery_present", Status::battery_present),
+    ("below_remaining_capacity_limit", Status::below_remaining_capacity_limit),
+    ("remaining_time_limit_expired", Status::remaining_time_limit_expired),
+    ("need_replace", Status::need_replace),
+    ("voltage_not_regulated", Status::voltage_nr),
+    ("full_charge", Status::full_charge),
+    ("full_discharge", Status::full_discharge),
+    ("shutdown_requested", Status::shutdown_requested),
+    ("shutdown_imminent", Status::shutdown_imminent),
+    ("communication_lost", Status::communication_lost),
+    ("overload", Status::overload),
+];
+
+///
+///
+/// one sample of everything the UPS knows about itself
+///
+#[derive(Clone, Copy)]
+pub struct Record {
+    /// time since boot
+    pub time_ms: u32,
+    pub v_bat: f32,
+    pub v_in: f32,
+    pub current: f32,
+    pub average_current: f32,
+    /// die temperature in °C
+    pub temperature: f32,
+    /// estimated capacity in %
+    pub capacity: u8,
+    /// estimated runtime in seconds
+    pub runtime_s: u16,
+    pub status: Status,
+    pub led_state: LEDState,
+}
+
+impl Record {
+    /// bits of the PresentStatus report, charging in bit 0
+    pub fn status_word(&self) -> u16 {
+        u16::from_le_bytes(self.status.into_bytes())
+    }
+
+    ///
+    ///
+    /// writes the CSV header matching `write_csv`, including the line ending
+    ///
+    pub fn write_csv_header(out: &mut dyn Write) -> fmt::Result {
+        out.write_str("time_ms,v_bat,v_in,current,average_current,temperature,capacity,runtime_s,led")?;
+        for (name, _) in STATUS_FLAGS.iter() {
+            write!(out, ",{}", name)?;
+        }
+        out.write_str("\r\n")
+    }
+
+    pub fn write_csv(&self, out: &mut dyn Write) -> fmt::Result {
+        write!(
+            out,
+            "{},{:.3},{:.3},{:.3},{:.3},{:.1},{},{},{}",
+            self.time_ms,
+            self.v_bat,
+            self.v_in,
+            self.current,
+            self.average_current,
+            self.temperature,
+            self.capacity,
+            self.runtime_s,
+            self.led_state.name()
+        )?;
+        for (_, flag) in STATUS_FLAGS.iter() {
+            write!(out, ",{}", flag(&self.status))?;
+        }
+        out.write_str("\r\n")
+    }
+
+    pub fn write_json(&self, out: &mut dyn Write) -> fmt::Result {
+        write!(
+            out,
+            "{{\"time_ms\":{},\"v_bat\":{:.3},\"v_in\":{:.3},\"current\":{:.3},\"average_current\":{:.3},\
+             \"temperature\":{:.1},\"capacity\":{},\"runtime_s\":{},\"led\":\"{}\",\"status\":{{",
+            self.time_ms,
+            self.v_bat,
+            self.v_in,
+            self.current,
+            self.average_current,
+            self.temperature,
+            self.capacity,
+            self.runtime_s,
+            self.led_state.name()
+        )?;
+        for (i, (name, flag)) in STATUS_FLAGS.iter().enumerate() {
+            let separator = if i == 0 { "" } else { "," };
+            write!(out, "{}\"{}\":{}", separator, name, flag(&self.status))?;
+        }
+        out.write_str("}}\r\n")
+    }
+}
+
+pub static G_TELEMETRY: Mutex<RefCell<Option<Record>>> = Mutex::new(RefCell::new(None));
+
+/// makes `record` the latest sample for the telemetry stream
+pub fn telemetry_publish(record: Record) {
+    cortex_m::interrupt::free(|cs| *G_TELEMETRY.borrow(cs).borrow_mut() = Some(record));
+}
+
+/// the latest sample, `None` until the USB task published the first one
+pub fn telemetry_latest() -> Option<Record> {
+    cortex_m::interrupt::free(|cs| *G_TELEMETRY.borrow(cs).borrow())
+}
+
+///
+///
+/// paces the telemetry stream, a CSV header is written whenever the format changes
+/// or the stream restarts
+///
+pub struct Telemetry {
+    last_ms: Option<u32>,
+    header_for: Option<TelemetryFormat>,
+}
+
+impl Telemetry {
+    pub const fn new() -> Self {
+        Telemetry {
+            last_ms: None,
+            header_for: None,
+        }
+    }
+
+    /// restarts the stream, e.g. after the host closed the port
+    pub fn restart(&mut self) {
+        self.last_ms = None;
+        self.header_for = None;
+    }
+
+    ///
+    ///
+    /// writes the latest record if `period_ms` passed since the previous one
+    ///
+    pub fn poll(&mut self, now_ms: u32, format: TelemetryFormat, period_ms: u16, out: &mut dyn Write) -> fmt::Result {
+        if format == TelemetryFormat::Off {
+            self.restart();
+            return Ok(());
+        }
+        if self.last_ms.map_or(false, |last| now_ms.wrapping_sub(last) < period_ms as u32) {
+            return Ok(());
+        }
+        let record = match telemetry_latest() {
+            None => return Ok(()),
+            Some(record) => record,
+        };
+        self.last_ms = Some(now_ms);
+        if format == TelemetryFormat::Csv && self.header_for != Some(format) {
+            Record::write_csv_header(out)?;
+        }
+        self.header_for = Some(format);
+        match format {
+            TelemetryFormat::Off => Ok(()),
+            TelemetryFormat::Csv => record.write_csv(out),
+            TelemetryFormat::Json => record.write_json(out),
+        }
+    }
+}
@@ -0,0 +1,212 @@
+use core::cell::RefCell;
+use cortex_m::interrupt::Mutex;
+use stm32f4xx_hal::otg_fs::{UsbBus, USB, UsbBusType};
+use stm32f4xx_hal::pac::{interrupt, OTG_FS_DEVICE, OTG_FS_PWRCLK};
+use usb_device::bus::UsbBusAllocator;
+use freertos_rust::{CurrentTask, Duration};
+use usb_device::device::{StringDescriptors, UsbDevice, UsbDeviceBuilder, UsbDeviceState, UsbVidPid};
+use usbd_serial::SerialPort;
+use usbd_hid_device::USB_CLASS_HID;
+use crate::config::config;
+use crate::identity::{IDENTITY, MAX_DESCRIPTOR_LEN};
+use crate::serial_number::{serial_number, serial_number_init};
+use crate::usb_hid::{PowerDevice, G_USB_HID};
+use crate::usb_serial::{usb_receive, usb_transmit, G_USB_SERIAL};
+
+// Make USB device globally available
+pub static G_USB_DEVICE: Mutex<RefCell<Option<UsbDevice<UsbBus<USB>>>>> =
+    Mutex::new(RefCell::new(None));
+
+static mut EP_MEMORY: [u32; 1024] = [0; 1024];
+/// report descriptor with the string indexes allocated on the bus
+static mut REPORT_DESCRIPTOR: [u8; MAX_DESCRIPTOR_LEN] = [0; MAX_DESCRIPTOR_LEN];
+static mut USB_BUS: Option<UsbBusAllocator<UsbBusType>> = None;
+
+/// the resume signal has to be driven for 1 to 15 ms
+const REMOTE_WAKEUP_SIGNAL_MS: u32 = 10;
+
+///
+///
+/// classes exposed on the USB port
+///
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum UsbMode {
+    /// HID power device only, as expected by UPS drivers that do not cope with composite devices
+    Hid,
+    /// CDC console only
+    Serial,
+    /// HID power device and CDC console on one configuration
+    Composite,
+}
+
+impl UsbMode {
+    pub const fn has_hid(&self) -> bool {
+        matches!(self, UsbMode::Hid | UsbMode::Composite)
+    }
+
+    pub const fn has_serial(&self) -> bool {
+        matches!(self, UsbMode::Serial | UsbMode::Composite)
+    }
+
+    pub const fn to_u8(self) -> u8 {
+        match self {
+            UsbMode::Hid => 0,
+            UsbMode::Serial => 1,
+            UsbMode::Composite => 2,
+        }
+    }
+
+    pub const fn name(&self) -> &'static str {
+        match self {
+            UsbMode::Hid => "hid",
+            UsbMode::Serial => "serial",
+            UsbMode::Composite => "composite",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "hid" => Some(UsbMode::Hid),
+            "serial" => Some(UsbMode::Serial),
+            "composite" => Some(UsbMode::Composite),
+            _ => None,
+        }
+    }
+
+    pub const fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(UsbMode::Hid),
+            1 => Some(UsbMode::Serial),
+            2 => Some(UsbMode::Composite),
+            _ => None,
+        }
+    }
+}
+
+///
+///
+/// creates the classes of `mode` on one bus allocator and builds the device with `IDENTITY`
+///
+/// the HID class is created first, so it owns interface 0 and the string indexes
+/// referenced by the report descriptor, the CDC class follows with interfaces 1 and 2
+///
+pub unsafe fn usb_init(usb: USB, mode: UsbMode) {
+    USB_BUS = Some(UsbBusType::new(usb, &mut EP_MEMORY));
+    let usb_bus = USB_BUS.as_ref().unwrap();
+    let config = config();
+    let identity = &IDENTITY;
+    serial_number_init(&config.serial_number);
+
+    let hid = if mode.has_hid() {
+        Some(PowerDevice::new(usb_bus, 10, identity, &mut REPORT_DESCRIPTOR))
+    } else {
+        None
+    };
+    let serial_port = if mode.has_serial() {
+        Some(SerialPort::new(usb_bus))
+    } else {
+        None
+    };
+
+    let builder = UsbDeviceBuilder::new(usb_bus, UsbVidPid(identity.vid, identity.pid));
+    let builder = match mode {
+        UsbMode::Hid => builder.device_class(USB_CLASS_HID),
+        UsbMode::Serial => builder.device_class(usbd_serial::USB_CLASS_CDC),
+        // the CDC class groups its two interfaces with an interface association descriptor
+        UsbMode::Composite => builder.composite_with_iads(),
+    };
+    let usb_dev = builder
+        .supports_remote_wakeup(true)
+        .strings(&[StringDescriptors::default()
+            .manufacturer(identity.manufacturer)
+            .product(identity.product)
+            .serial_number(serial_number())])
+        .unwrap()
+        .build();
+
+    cortex_m::interrupt::free(|cs| {
+        *G_USB_HID.borrow(cs).borrow_mut() = hid;
+        *G_USB_SERIAL.borrow(cs).borrow_mut() = serial_port;
+        *G_USB_DEVICE.borrow(cs).borrow_mut() = Some(usb_dev);
+    });
+}
+
+///
+///
+/// returns: the state of the USB device, `Default` if it is not initialised
+///
+pub fn usb_state() -> UsbDeviceState {
+    cortex_m::interrupt::free(|cs| {
+        G_USB_DEVICE
+            .borrow(cs)
+            .borrow()
+            .as_ref()
+            .map_or(UsbDeviceState::Default, |usb_dev| usb_dev.state())
+    })
+}
+
+///
+///
+/// signals remote wakeup to a suspended host, blocks the calling task for
+/// `REMOTE_WAKEUP_SIGNAL_MS`
+///
+/// returns: false if the device is not suspended or the host did not enable remote wakeup
+///
+pub fn usb_remote_wakeup() -> bool {
+    let allowed = cortex_m::interrupt::free(|cs| {
+        G_USB_DEVICE
+            .borrow(cs)
+            .borrow()
+            .as_ref()
+            .map_or(false, |usb_dev| {
+                usb_dev.state() == UsbDeviceState::Suspend && usb_dev.remote_wakeup_enabled()
+            })
+    });
+    if !allowed {
+        return false;
+    }
+    log_debug!("signalling remote wakeup");
+    // the registers belong to the bus inside G_USB_DEVICE, the critical section keeps the
+    // OTG_FS interrupt from modifying them between the read and the write
+    cortex_m::interrupt::free(|_| {
+        let (pwrclk, device) = unsafe { (&*OTG_FS_PWRCLK::ptr(), &*OTG_FS_DEVICE::ptr()) };
+        // ungate the phy clock in case it was stopped during suspend, then drive resume
+        pwrclk.fs_pcgcctl().modify(|_, w| w.stppclk().clear_bit().gatehclk().clear_bit());
+        device.fs_dctl().modify(|_, w| w.rwusig().set_bit());
+    });
+    CurrentTask::delay(Duration::ms(REMOTE_WAKEUP_SIGNAL_MS));
+    cortex_m::interrupt::free(|_| {
+        let device = unsafe { &*OTG_FS_DEVICE::ptr() };
+        device.fs_dctl().modify(|_, w| w.rwusig().clear_bit());
+    });
+    true
+}
+
+#[interrupt]
+#[allow(non_snake_case)]
+fn OTG_FS() {
+    cortex_m::interrupt::free(|cs| {
+        match G_USB_DEVICE.borrow(cs).borrow_mut().as_mut() {
+            None => {}
+            Some(usb_dev) => {
+                let mut hid = G_USB_HID.borrow(cs).borrow_mut();
+                let mut serial = G_USB_SERIAL.borrow(cs).borrow_mut();
+                // do this regularly to keep connection to USB host
+                match (hid.as_mut(), serial.as_mut()) {
+                    (Some(hid), Some(serial)) => {
+                        usb_dev.poll(&mut [hid, serial]);
+                    }
+                    (Some(hid), None) => {
+                        usb_dev.poll(&mut [hid]);
+                    }
+                    (None, Some(serial)) => {
+                        usb_dev.poll(&mut [serial]);
+                    }
+                    (None, None) => {}
+                }
+            }
+        }
+        usb_receive(cs);
+        usb_transmit(cs);
+    });
+}
@@ -1,72 +1,262 @@
 use core::cell::RefCell;
 use cortex_m::interrupt::Mutex;
-use stm32f4xx_hal::otg_fs::{UsbBus, USB, UsbBusType};
-use stm32f4xx_hal::pac::{interrupt};
-use usb_device::bus::UsbBusAllocator;
-use usb_device::device::{StringDescriptors, UsbDevice, UsbDeviceBuilder, UsbVidPid};
-use usbd_hid_device::{USB_CLASS_HID, Hid};
-use crate::report::Report;
-use crate::usb_serial::G_USB_SERIAL;
-
-// Make USB serial device globally available
-pub static G_USB_HID: Mutex<RefCell<Option<Hid<Report, UsbBus<USB>>>>> =
-    Mutex::new(RefCell::new(None));
+use stm32f4xx_hal::otg_fs::{UsbBus, USB};
+use usb_device::bus::{InterfaceNumber, StringIndex, UsbBusAllocator};
+use usb_device::class::{ControlIn, ControlOut, UsbClass};
+use usb_device::control::{Recipient, Request, RequestType};
+use usb_device::descriptor::DescriptorWriter;
+use usb_device::endpoint::{EndpointAddress, EndpointIn};
+use usb_device::LangID;
+use usbd_hid_device::USB_CLASS_HID;
+use crate::feature::*;
+use crate::identity::{UsbIdentity, MAX_DESCRIPTOR_LEN};
+use ups_hid::parser::remap_string_indexes;
+use ups_hid::report::{HID_PD_IDEVICECHEMISTRY, HID_PD_IOEMINFORMATION, IDEVICECHEMISTRY, IOEMVENDOR, Report};
+
+/// reports are at most 5 bytes, a full speed interrupt endpoint allows up to 64
+const MAX_PACKET_SIZE: u16 = 8;
 
-// Make USB device globally available
-pub static G_USB_DEVICE: Mutex<RefCell<Option<UsbDevice<UsbBus<USB>>>>> =
+// Make USB HID device globally available
+pub static G_USB_HID: Mutex<RefCell<Option<PowerDevice<UsbBus<USB>>>>> =
     Mutex::new(RefCell::new(None));
 
-pub static G_USB_HID_MODE: Mutex<RefCell<bool>> =
-    Mutex::new(RefCell::new(true));
-#[allow(dead_code)]
-pub unsafe fn usb_hid_init(usb: USB) {
-    static mut EP_MEMORY: [u32; 1024] = [0; 1024];
-    static mut USB_BUS: Option<UsbBusAllocator<UsbBusType>> = None;
-    USB_BUS = Some(UsbBusType::new(usb, &mut EP_MEMORY));
-    let usb_bus = USB_BUS.as_ref().unwrap();
-    let hid = Hid::new(usb_bus, 10);
-    let usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0x03f0, 0x1f06))
-        .device_class(USB_CLASS_HID)
-        .strings(&[StringDescriptors::default()
-            .manufacturer("hacknus")
-            .product("UPS")
-            .serial_number("UPS10")])
-        .unwrap()
-        .build();
-    cortex_m::interrupt::free(|cs| {
-        *G_USB_HID.borrow(cs).borrow_mut() = Some(hid);
-        *G_USB_DEVICE.borrow(cs).borrow_mut() = Some(usb_dev);
-    });
+///
+///
+/// HID power device class
+///
+/// answers the GET_REPORT / SET_REPORT requests for the feature reports from
+/// `FeatureReports` and serves the report descriptor and the class specific strings
+/// of the identity
+///
+pub struct PowerDevice<'a, B: usb_device::bus::UsbBus> {
+    interface: InterfaceNumber,
+    ep_in: EndpointIn<'a, B>,
+    identity: &'static UsbIdentity,
+    /// report descriptor of `identity` referencing the string indexes below
+    descriptor: &'static [u8],
+    chemistry: StringIndex,
+    oem_vendor: StringIndex,
+    idle: u8,
+    /// the host read an input report or requested a report since the last `take_host_activity`
+    host_active: bool,
+    pub features: FeatureReports,
 }
 
+impl<'a, B: usb_device::bus::UsbBus> PowerDevice<'a, B> {
+    ///
+    ///
+    /// creates the class, the report descriptor of `identity` is copied to `descriptor` with
+    /// its class specific string indexes replaced by the ones allocated on `usb_bus`
+    ///
+    pub fn new(
+        usb_bus: &'a UsbBusAllocator<B>,
+        poll_ms: u8,
+        identity: &'static UsbIdentity,
+        descriptor: &'static mut [u8; MAX_DESCRIPTOR_LEN],
+    ) -> Self {
+        let chemistry = usb_bus.string();
+        let oem_vendor = usb_bus.string();
+        let descriptor = &mut descriptor[..identity.descriptor.len()];
+        descriptor.copy_from_slice(identity.descriptor);
+        // the descriptor passed the parser at compile time
+        remap_string_indexes(descriptor, |index| match index {
+            IDEVICECHEMISTRY => u8::from(chemistry),
+            IOEMVENDOR => u8::from(oem_vendor),
+            index => index,
+        })
+        .unwrap();
+        PowerDevice {
+            interface: usb_bus.interface(),
+            ep_in: usb_bus.interrupt(MAX_PACKET_SIZE, poll_ms),
+            identity,
+            descriptor,
+            chemistry,
+            oem_vendor,
+            idle: 0,
+            host_active: false,
+            features: FeatureReports::new(),
+        }
+    }
 
-#[interrupt]
-#[allow(non_snake_case)]
-fn OTG_FS() {
-    cortex_m::interrupt::free(|cs| {
-        match G_USB_DEVICE.borrow(cs).borrow_mut().as_mut() {
-            None => {}
-            Some(usb_dev) => {
-                if let hid_mode = G_USB_HID_MODE.borrow(cs).borrow() {
-                    if *hid_mode {
-                        match G_USB_HID.borrow(cs).borrow_mut().as_mut() {
-                            None => {}
-                            Some(hid) => {
-                                // do this regularly to keep connection to USB host
-                                usb_dev.poll(&mut [hid]);
-                            }
-                        }
-                    } else {
-                        match G_USB_SERIAL.borrow(cs).borrow_mut().as_mut() {
-                            None => {}
-                            Some(serial) => {
-                                // do this regularly to keep connection to USB host
-                                usb_dev.poll(&mut [serial]);
-                            }
-                        }
-                    }
+    pub fn send_report(&mut self, report: &Report) -> usb_device::Result<usize> {
+        self.ep_in.write(report.encode().as_ref())
+    }
+
+    ///
+    ///
+    /// returns: true if the host talked to the power device since the last call
+    ///
+    pub fn take_host_activity(&mut self) -> bool {
+        core::mem::replace(&mut self.host_active, false)
+    }
+
+    ///
+    ///
+    /// the reports naming a class specific string, with the same indexes as the descriptor
+    ///
+    fn string_report(&self, id: u8) -> Option<Report> {
+        match id {
+            HID_PD_IDEVICECHEMISTRY => Some(Report::IDeviceChemistry(u8::from(self.chemistry))),
+            HID_PD_IOEMINFORMATION => Some(Report::IOEMInformation(u8::from(self.oem_vendor))),
+            _ => None,
+        }
+    }
+
+    fn hid_descriptor(&self) -> [u8; 7] {
+        let len = self.descriptor.len() as u16;
+        [
+            0x11, 0x01, // bcdHID 1.11
+            0x00, // country code
+            0x01, // number of class descriptors
+            HID_REPORT_DESCRIPTOR_TYPE,
+            len as u8,
+            (len >> 8) as u8,
+        ]
+    }
+}
+
+impl<B: usb_device::bus::UsbBus> UsbClass<B> for PowerDevice<'_, B> {
+    fn get_configuration_descriptors(&self, writer: &mut DescriptorWriter) -> usb_device::Result<()> {
+        writer.interface(self.interface, USB_CLASS_HID, 0, 0)?;
+        writer.write(HID_DESCRIPTOR_TYPE, &self.hid_descriptor())?;
+        writer.endpoint(&self.ep_in)
+    }
+
+    fn get_string(&self, index: StringIndex, _lang_id: LangID) -> Option<&str> {
+        if index == self.chemistry {
+            self.identity.string(IDEVICECHEMISTRY)
+        } else if index == self.oem_vendor {
+            self.identity.string(IOEMVENDOR)
+        } else {
+            None
+        }
+    }
+
+    fn reset(&mut self) {
+        self.idle = 0;
+    }
+
+    fn endpoint_in_complete(&mut self, addr: EndpointAddress) {
+        if addr == self.ep_in.address() {
+            self.host_active = true;
+        }
+    }
+
+    fn control_out(&mut self, xfer: ControlOut<B>) {
+        let req = *xfer.request();
+        if req.request_type != RequestType::Class
+            || req.recipient != Recipient::Interface
+            || req.index != u8::from(self.interface) as u16
+        {
+            return;
+        }
+        match req.request {
+            HID_REQ_SET_REPORT => {
+                self.host_active = true;
+                let report_type = (req.value >> 8) as u8;
+                let report_id = req.value as u8;
+                if report_type == HID_REPORT_TYPE_FEATURE && self.features.set(report_id, xfer.data()) {
+                    xfer.accept().ok();
+                } else {
+                    xfer.reject().ok();
+                }
+            }
+            HID_REQ_SET_IDLE => {
+                self.idle = (req.value >> 8) as u8;
+                xfer.accept().ok();
+            }
+            // only the report protocol exists for a power device
+            HID_REQ_SET_PROTOCOL => {
+                xfer.accept().ok();
+            }
+            _ => {
+                xfer.reject().ok();
+            }
+        }
+    }
+
+    fn control_in(&mut self, xfer: ControlIn<B>) {
+        let req = *xfer.request();
+        if req.recipient != Recipient::Interface || req.index != u8::from(self.interface) as u16 {
+            return;
+        }
+        match (req.request_type, req.request) {
+            (RequestType::Standard, Request::GET_DESCRIPTOR) => match (req.value >> 8) as u8 {
+                HID_REPORT_DESCRIPTOR_TYPE => {
+                    xfer.accept_with_static(self.descriptor).ok();
+                }
+                HID_DESCRIPTOR_TYPE => {
+                    xfer.accept_with(&self.hid_descriptor()).ok();
                 }
+                _ => {
+                    xfer.reject().ok();
+                }
+            },
+            (RequestType::Class, HID_REQ_GET_REPORT) => {
+                self.host_active = true;
+                let report_type = (req.value >> 8) as u8;
+                let report_id = req.value as u8;
+                if report_type != HID_REPORT_TYPE_FEATURE && report_type != HID_REPORT_TYPE_INPUT {
+                    xfer.reject().ok();
+                } else if let Some(report) = self.string_report(report_id).or_else(|| self.features.get(report_id)) {
+                    xfer.accept_with(report.encode().as_ref()).ok();
+                } else {
+                    xfer.reject().ok();
+                }
+            }
+            (RequestType::Class, HID_REQ_GET_IDLE) => {
+                xfer.accept_with(&[self.idle]).ok();
+            }
+            (RequestType::Class, HID_REQ_GET_PROTOCOL) => {
+                xfer.accept_with(&[1]).ok(); // report protocol
+            }
+            (RequestType::Class, _) => {
+                xfer.reject().ok();
             }
+            _ => {}
         }
-    });
-}
\ No newline at end of file
+    }
+}
+///
+///
+/// sends an input report to the host
+///
+/// returns: false if the HID class is not initialised or the endpoint is still busy
+///
+pub fn hid_send_report(report: &Report) -> bool {
+    cortex_m::interrupt::free(|cs| {
+        match G_USB_HID.borrow(cs).borrow_mut().as_mut() {
+            Some(hid) => hid.send_report(report).is_ok(),
+            None => false,
+        }
+    })
+}
+
+///
+///
+/// returns: true if the host talked to the power device since the last call
+///
+pub fn hid_host_activity() -> bool {
+    cortex_m::interrupt::free(|cs| {
+        G_USB_HID
+            .borrow(cs)
+            .borrow_mut()
+            .as_mut()
+            .map_or(false, |hid| hid.take_host_activity())
+    })
+}
+
+///
+///
+/// returns: the RemainingTimeLimit in seconds set by the host, the default of
+/// `FeatureReports` if the HID class is not initialised
+///
+pub fn hid_remaining_time_limit() -> u16 {
+    cortex_m::interrupt::free(|cs| {
+        G_USB_HID
+            .borrow(cs)
+            .borrow()
+            .as_ref()
+            .map_or(DEFAULT_REMAINING_TIME_LIMIT, |hid| hid.features.remaining_time_limit)
+    })
+}
@@ -1,99 +1,389 @@
 use core::cell::RefCell;
 
-// use arrform::{arrform, ArrForm};
-use cortex_m::interrupt::Mutex;
+use cortex_m::interrupt::{CriticalSection, Mutex};
+use freertos_rust::{CurrentTask, Duration, FreeRtosUtils};
 use stm32f4xx_hal::otg_fs::{UsbBus, USB};
-use stm32f4xx_hal::pac::interrupt;
-use usb_device::bus::UsbBusAllocator;
-use usb_device::prelude::*;
 use usbd_serial::SerialPort;
-use crate::usb_hid::G_USB_DEVICE;
+use crate::config::config;
+use ups_core::ring_buffer::{LineReader, RingBuffer};
+
+/// received bytes kept until a task reads them, the CDC class only buffers one packet
+pub const RX_BUFFER_SIZE: usize = 512;
+/// bytes queued for the host
+pub const TX_BUFFER_SIZE: usize = 1024;
+/// longest time `usb_write` waits for space with `TxPolicy::Block`
+const TX_BLOCK_TIMEOUT_MS: u32 = 100;
+
+///
+///
+/// protocol spoken on the CDC port
+///
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum SerialProtocol {
+    /// interactive command shell, see `shell`
+    Shell,
+    /// Megatec Q1 for NUT and other UPS monitors, see `megatec`
+    Megatec,
+    /// framed binary protocol for host tools, see `binary` and the `ups-protocol` crate
+    Binary,
+    /// Modbus RTU slave for PLCs, see `ups_core::modbus`
+    Modbus,
+}
+
+impl SerialProtocol {
+    pub const fn to_u8(self) -> u8 {
+        match self {
+            SerialProtocol::Shell => 0,
+            SerialProtocol::Megatec => 1,
+            SerialProtocol::Binary => 2,
+            SerialProtocol::Modbus => 3,
+        }
+    }
+
+    pub const fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(SerialProtocol::Shell),
+            1 => Some(SerialProtocol::Megatec),
+            2 => Some(SerialProtocol::Binary),
+            3 => Some(SerialProtocol::Modbus),
+            _ => None,
+        }
+    }
+
+    pub const fn name(&self) -> &'static str {
+        match self {
+            SerialProtocol::Shell => "shell",
+            SerialProtocol::Megatec => "megatec",
+            SerialProtocol::Binary => "binary",
+            SerialProtocol::Modbus => "modbus",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "shell" => Some(SerialProtocol::Shell),
+            "megatec" => Some(SerialProtocol::Megatec),
+            "binary" => Some(SerialProtocol::Binary),
+            "modbus" => Some(SerialProtocol::Modbus),
+            _ => None,
+        }
+    }
+}
 
 // Make USB serial device globally available
 pub static G_USB_SERIAL: Mutex<RefCell<Option<SerialPort<UsbBus<USB>>>>> =
     Mutex::new(RefCell::new(None));
 
-pub static mut EP_MEMORY: [u32; 1024] = [0; 1024];
-static mut USB_BUS: Option<UsbBusAllocator<stm32f4xx_hal::otg_fs::UsbBusType>> = None;
+///
+///
+/// bytes received on the CDC port, filled by the `OTG_FS` interrupt
+///
+pub struct RxBuffer {
+    bytes: RingBuffer<RX_BUFFER_SIZE>,
+    /// line splitting state of `usb_read_line`
+    lines: LineReader,
+    /// bytes taken from the CDC class since boot
+    received: u32,
+    /// received bytes dropped because the buffer was full
+    dropped: u32,
+}
 
-#[allow(dead_code)]
-pub unsafe fn usb_serial_init(usb: USB) {
-    USB_BUS = Some(stm32f4xx_hal::otg_fs::UsbBusType::new(usb, &mut EP_MEMORY));
-    let usb_bus = USB_BUS.as_ref().unwrap();
-    let serial_port = SerialPort::new(&usb_bus);
-    let usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0x03f0, 0x1f06))
-        .device_class(usbd_serial::USB_CLASS_CDC)
-        .strings(&[StringDescriptors::default()
-            .manufacturer("hacknus")
-            .product("UPS")
-            .serial_number("UPS10")])
-        .unwrap()
-        .build();
+impl RxBuffer {
+    pub const fn new() -> Self {
+        RxBuffer {
+            bytes: RingBuffer::new(),
+            lines: LineReader::new(),
+            received: 0,
+            dropped: 0,
+        }
+    }
+}
 
-    cortex_m::interrupt::free(|cs| {
-        *G_USB_SERIAL.borrow(cs).borrow_mut() = Some(serial_port);
-        *G_USB_DEVICE.borrow(cs).borrow_mut() = Some(usb_dev);
-    });
+pub static G_USB_RX: Mutex<RefCell<RxBuffer>> = Mutex::new(RefCell::new(RxBuffer::new()));
+
+///
+///
+/// moves the bytes the CDC class received into `G_USB_RX`, called by the `OTG_FS` interrupt
+/// after polling the device, bytes that do not fit are dropped and counted
+///
+pub fn usb_receive(cs: &CriticalSection) {
+    if let Some(serial) = G_USB_SERIAL.borrow(cs).borrow_mut().as_mut() {
+        let mut rx = G_USB_RX.borrow(cs).borrow_mut();
+        let mut packet = [0; 64];
+        while let Ok(count) = serial.read(&mut packet) {
+            if count == 0 {
+                break;
+            }
+            rx.received = rx.received.wrapping_add(count as u32);
+            for &byte in &packet[..count] {
+                if !rx.bytes.push(byte) {
+                    rx.dropped = rx.dropped.wrapping_add(1);
+                }
+            }
+        }
+    }
 }
 
-#[allow(dead_code)]
-pub fn usb_read(message: &mut [u8; 1024]) -> bool {
+///
+///
+/// takes the bytes received on the CDC port, does not block
+///
+/// returns: the number of bytes written to `buffer`, 0 if nothing was received
+///
+pub fn usb_read(buffer: &mut [u8]) -> usize {
+    cortex_m::interrupt::free(|cs| G_USB_RX.borrow(cs).borrow_mut().bytes.read(buffer))
+}
+
+/// number of received bytes waiting to be read
+pub fn usb_available() -> usize {
+    cortex_m::interrupt::free(|cs| G_USB_RX.borrow(cs).borrow().bytes.len())
+}
+
+///
+///
+/// returns: the bytes received since boot and the bytes dropped because nobody read them in time
+///
+pub fn usb_rx_counters() -> (u32, u32) {
     cortex_m::interrupt::free(|cs| {
-        *message = [0; 1024];
-        return match G_USB_SERIAL.borrow(cs).borrow_mut().as_mut() {
-            None => false,
-            Some(serial) => match serial.read(message) {
-                Ok(a) => {
-                    if a < 1024 {
-                        true
-                    } else {
-                        false
-                    }
-                }
-                Err(_err) => {
-                    // usb_println(arrform!(128, "Serial read Error: {:?}", err).as_str());
-                    // let _ = serial.flush();
-                    false
+        let rx = G_USB_RX.borrow(cs).borrow();
+        (rx.received, rx.dropped)
+    })
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ReadLineError {
+    /// no complete line arrived in time, the received part stays buffered
+    Timeout,
+    /// the line does not fit into the buffer, it is discarded up to its terminator
+    TooLong,
+}
+
+///
+///
+/// reads one line terminated by `\r`, `\n` or `\r\n`, blocks the calling task for at
+/// most `timeout_ms`
+///
+/// returns: the length of the line written to `line`, without the terminator
+///
+pub fn usb_read_line(line: &mut [u8], timeout_ms: u32) -> Result<usize, ReadLineError> {
+    let start = FreeRtosUtils::get_tick_count(); // 1 tick = 1 ms
+    loop {
+        let result = cortex_m::interrupt::free(|cs| {
+            let mut rx = G_USB_RX.borrow(cs).borrow_mut();
+            let rx = &mut *rx;
+            rx.lines.read_line(&mut rx.bytes, line)
+        });
+        if let Some(result) = result {
+            return result.map_err(|_| ReadLineError::TooLong);
+        }
+        if FreeRtosUtils::get_tick_count().wrapping_sub(start) >= timeout_ms {
+            return Err(ReadLineError::Timeout);
+        }
+        CurrentTask::delay(Duration::ms(1));
+    }
+}
+
+///
+///
+/// what `usb_write` does when the host does not read fast enough and the TX queue is full
+///
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum TxPolicy {
+    /// wait up to `TX_BLOCK_TIMEOUT_MS` for space, then drop the rest of the write
+    Block,
+    /// make room by dropping the oldest queued bytes
+    DropOldest,
+    /// drop the bytes that do not fit
+    DropNewest,
+}
+
+impl TxPolicy {
+    pub const fn to_u8(self) -> u8 {
+        match self {
+            TxPolicy::Block => 0,
+            TxPolicy::DropOldest => 1,
+            TxPolicy::DropNewest => 2,
+        }
+    }
+
+    pub const fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(TxPolicy::Block),
+            1 => Some(TxPolicy::DropOldest),
+            2 => Some(TxPolicy::DropNewest),
+            _ => None,
+        }
+    }
+
+    pub const fn name(&self) -> &'static str {
+        match self {
+            TxPolicy::Block => "block",
+            TxPolicy::DropOldest => "drop_oldest",
+            TxPolicy::DropNewest => "drop_newest",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "block" => Some(TxPolicy::Block),
+            "drop_oldest" => Some(TxPolicy::DropOldest),
+            "drop_newest" => Some(TxPolicy::DropNewest),
+            _ => None,
+        }
+    }
+}
+
+///
+///
+/// bytes waiting for the CDC class, drained by the `OTG_FS` interrupt
+///
+pub struct TxBuffer {
+    bytes: RingBuffer<TX_BUFFER_SIZE>,
+    /// bytes handed to the CDC class since boot
+    sent: u32,
+    /// bytes dropped because of `TxPolicy`
+    dropped: u32,
+}
+
+impl TxBuffer {
+    pub const fn new() -> Self {
+        TxBuffer {
+            bytes: RingBuffer::new(),
+            sent: 0,
+            dropped: 0,
+        }
+    }
+
+    ///
+    ///
+    /// queues as much of `bytes` as `policy` allows, with `TxPolicy::Block` the bytes
+    /// that do not fit are left to the caller
+    ///
+    /// returns: the number of bytes of `bytes` that were queued or dropped
+    ///
+    fn queue(&mut self, bytes: &[u8], policy: TxPolicy) -> usize {
+        let count = match policy {
+            TxPolicy::Block | TxPolicy::DropNewest => bytes.len().min(self.bytes.free()),
+            TxPolicy::DropOldest => {
+                let skipped = bytes.len().saturating_sub(TX_BUFFER_SIZE);
+                let overflow = (bytes.len() - skipped).saturating_sub(self.bytes.free());
+                self.bytes.discard(overflow);
+                self.dropped = self.dropped.wrapping_add((skipped + overflow) as u32);
+                for &byte in &bytes[skipped..] {
+                    self.bytes.push(byte);
                 }
-            },
+                return bytes.len();
+            }
         };
-    })
+        for &byte in &bytes[..count] {
+            self.bytes.push(byte);
+        }
+        if policy == TxPolicy::DropNewest {
+            self.dropped = self.dropped.wrapping_add((bytes.len() - count) as u32);
+            return bytes.len();
+        }
+        count
+    }
 }
 
-#[allow(dead_code)]
-pub fn usb_println(string: &str) {
-    cortex_m::interrupt::free(|cs| match G_USB_SERIAL.borrow(cs).borrow_mut().as_mut() {
-        None => {}
-        Some(serial) => {
-            let string_bytes = string.as_bytes();
-            let mut index = 0;
-            let length = 32;
-            loop {
-                if string_bytes.len() > index + length {
-                    let bytes_to_send = &string_bytes[index..index + length];
-                    serial.write(bytes_to_send).unwrap_or(0);
-                    serial.flush().unwrap_or(());
-                } else {
-                    let bytes_to_send = &string_bytes[index..];
-                    serial.write(bytes_to_send).unwrap_or(0);
-                    serial.flush().unwrap_or(());
-                    break;
+pub static G_USB_TX: Mutex<RefCell<TxBuffer>> = Mutex::new(RefCell::new(TxBuffer::new()));
+
+///
+///
+/// hands queued bytes to the CDC class, called by the `OTG_FS` interrupt after polling the
+/// device and by `usb_write` to start a transfer
+///
+pub fn usb_transmit(cs: &CriticalSection) {
+    if let Some(serial) = G_USB_SERIAL.borrow(cs).borrow_mut().as_mut() {
+        let mut tx = G_USB_TX.borrow(cs).borrow_mut();
+        let mut packet = [0; 64];
+        while !tx.bytes.is_empty() {
+            let count = tx.bytes.len().min(packet.len());
+            for (i, byte) in packet[..count].iter_mut().enumerate() {
+                *byte = tx.bytes.get(i).unwrap_or(0);
+            }
+            match serial.write(&packet[..count]) {
+                Ok(written) if written > 0 => {
+                    tx.bytes.discard(written);
+                    tx.sent = tx.sent.wrapping_add(written as u32);
                 }
-                index += length;
+                // the class buffer is full, the interrupt continues once the host read a packet
+                _ => break,
             }
-            serial.write(b"\r\n").unwrap_or(0);
-            serial.flush().unwrap_or(());
         }
+        serial.flush().ok();
+    }
+}
+
+///
+///
+/// queues bytes for the CDC port, only call from tasks
+///
+/// what happens if the queue is full depends on `Config::tx_policy`, dropped bytes are counted
+///
+/// returns: the number of bytes accepted, with `TxPolicy::Block` less than `bytes.len()`
+/// if the host did not read within `TX_BLOCK_TIMEOUT_MS`
+///
+pub fn usb_write(bytes: &[u8]) -> usize {
+    let policy = config().tx_policy;
+    let start = FreeRtosUtils::get_tick_count(); // 1 tick = 1 ms
+    let mut handled = 0;
+    loop {
+        cortex_m::interrupt::free(|cs| {
+            handled += G_USB_TX.borrow(cs).borrow_mut().queue(&bytes[handled..], policy);
+            usb_transmit(cs);
+        });
+        if handled == bytes.len() {
+            return handled;
+        }
+        if FreeRtosUtils::get_tick_count().wrapping_sub(start) >= TX_BLOCK_TIMEOUT_MS {
+            break;
+        }
+        CurrentTask::delay(Duration::ms(1));
+    }
+    cortex_m::interrupt::free(|cs| {
+        let mut tx = G_USB_TX.borrow(cs).borrow_mut();
+        tx.dropped = tx.dropped.wrapping_add((bytes.len() - handled) as u32);
+    });
+    handled
+}
+
+///
+///
+/// queues bytes for the CDC port without ever waiting for the host, safe for the log sink
+///
+/// with `TxPolicy::Block` the bytes that do not fit are dropped like with
+/// `TxPolicy::DropNewest`, dropped bytes are counted
+///
+pub fn usb_write_nonblocking(bytes: &[u8]) {
+    let policy = match config().tx_policy {
+        TxPolicy::DropOldest => TxPolicy::DropOldest,
+        TxPolicy::Block | TxPolicy::DropNewest => TxPolicy::DropNewest,
+    };
+    cortex_m::interrupt::free(|cs| {
+        G_USB_TX.borrow(cs).borrow_mut().queue(bytes, policy);
+        usb_transmit(cs);
+    });
+}
+
+///
+///
+/// returns: the bytes handed to the CDC class since boot and the bytes dropped because of `TxPolicy`
+///
+pub fn usb_tx_counters() -> (u32, u32) {
+    cortex_m::interrupt::free(|cs| {
+        let tx = G_USB_TX.borrow(cs).borrow();
+        (tx.sent, tx.dropped)
     })
 }
 
+#[allow(dead_code)]
+pub fn usb_println(string: &str) {
+    usb_write(string.as_bytes());
+    usb_write(b"\r\n");
+}
+
 #[allow(dead_code)]
 pub fn usb_print(string: &str) {
-    cortex_m::interrupt::free(|cs| match G_USB_SERIAL.borrow(cs).borrow_mut().as_mut() {
-        None => {}
-        Some(serial) => {
-            serial.write(string.as_bytes()).unwrap_or(0);
-            serial.flush().unwrap_or(());
-        }
-    })
+    usb_write(string.as_bytes());
 }
@@ -2,4 +2,26 @@
 pub enum LEDState {
     FastBreathing,
     SlowBreathing
-}
\ No newline at end of file
+}
+
+impl LEDState {
+    pub const fn name(&self) -> &'static str {
+        match self {
+            LEDState::FastBreathing => "fast_breathing",
+            LEDState::SlowBreathing => "slow_breathing",
+        }
+    }
+}
+
+///
+///
+/// estimates the remaining capacity in % from the voltage of the two cells in series
+///
+/// returns: u8
+///
+pub fn battery_capacity(v_bat: f32) -> u8 {
+    (100.0 / (4.15 * 2.0 - 3.3 * 2.0) * (v_bat - 3.3 * 2.0)).clamp(0.0, 100.0) as u8
+}
+
+/// shared with the host tools through the binary protocol crate
+pub use ups_protocol::crc16;
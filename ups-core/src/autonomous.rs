@@ -0,0 +1,210 @@
+//! Autonomous shutdown: cuts the output on its own when no host takes care of it.
+//!
+//! Only decides, the firmware applies the decision to `OutputControl` through
+//! `start_autonomous_shutdown` / `abort_autonomous_shutdown`, so a countdown the host
+//! started is never touched.
+
+///
+///
+/// settings of `AutonomousShutdown`, taken from the stored config
+///
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct AutonomousConfig {
+    /// battery capacity in % at which the countdown starts, 0 disables the policy
+    pub shutdown_capacity: u8,
+    /// seconds between reaching `shutdown_capacity` and cutting the output
+    pub grace_s: u16,
+    /// seconds without host activity after which the host counts as lost
+    pub host_timeout_s: u8,
+}
+
+///
+///
+/// what the firmware has to do with the shutdown countdown
+///
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum AutonomousAction {
+    /// start the countdown with the given seconds
+    Start(u16),
+    /// mains or the host came back, abort the countdown if it is still the one started
+    /// before and the output is not cut yet, otherwise `OutputControl` restores the output
+    /// once mains is present
+    Abort,
+}
+
+///
+///
+/// the host counts as lost if the device is not configured or the host neither read
+/// an input report nor requested a report for `host_timeout_s` seconds, once the
+/// battery is at `shutdown_capacity` the shutdown countdown is started with `grace_s`
+/// seconds, which cuts the output and restores it when mains is back
+///
+pub struct AutonomousShutdown {
+    /// time of the last host activity, `None` if the host was never seen
+    last_activity_ms: Option<u32>,
+    /// `AutonomousAction::Start` was returned and not yet followed by `Abort`
+    armed: bool,
+    host_lost: bool,
+}
+
+impl Default for AutonomousShutdown {
+    fn default() -> Self {
+        AutonomousShutdown::new()
+    }
+}
+
+impl AutonomousShutdown {
+    pub const fn new() -> Self {
+        AutonomousShutdown {
+            last_activity_ms: None,
+            armed: false,
+            host_lost: false,
+        }
+    }
+
+    ///
+    ///
+    /// `host_active` is true if the host talked to the device since the last call,
+    /// `host_present` is true if the device is configured by a host
+    ///
+    /// returns: what to do with the shutdown countdown, if anything
+    ///
+    pub fn update(
+        &mut self,
+        now_ms: u32,
+        host_active: bool,
+        host_present: bool,
+        on_battery: bool,
+        capacity: u8,
+        config: &AutonomousConfig,
+    ) -> Option<AutonomousAction> {
+        if host_active && host_present {
+            self.last_activity_ms = Some(now_ms);
+        }
+        self.host_lost = !host_present
+            || self.last_activity_ms.map_or(true, |last| {
+                now_ms.wrapping_sub(last) >= config.host_timeout_s as u32 * 1000
+            });
+
+        let battery_low = config.shutdown_capacity != 0 && capacity <= config.shutdown_capacity;
+        if !self.armed && self.host_lost && on_battery && battery_low {
+            self.armed = true;
+            Some(AutonomousAction::Start(config.grace_s))
+        } else if self.armed && (!on_battery || !self.host_lost) {
+            self.armed = false;
+            Some(AutonomousAction::Abort)
+        } else {
+            None
+        }
+    }
+
+    /// the host was lost at the last `update`
+    pub fn host_lost(&self) -> bool {
+        self.host_lost
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shutdown::OutputControl;
+
+    const CONFIG: AutonomousConfig = AutonomousConfig {
+        shutdown_capacity: 10,
+        grace_s: 60,
+        host_timeout_s: 30,
+    };
+
+    fn apply(action: Option<AutonomousAction>, output: &mut OutputControl) {
+        match action {
+            Some(AutonomousAction::Start(seconds)) => {
+                output.start_autonomous_shutdown(seconds);
+            }
+            Some(AutonomousAction::Abort) => {
+                output.abort_autonomous_shutdown();
+            }
+            None => {}
+        }
+    }
+
+    #[test]
+    fn host_times_out() {
+        let mut policy = AutonomousShutdown::new();
+        assert_eq!(policy.update(0, true, true, false, 100, &CONFIG), None);
+        assert!(!policy.host_lost());
+        policy.update(29_999, false, true, false, 100, &CONFIG);
+        assert!(!policy.host_lost());
+        policy.update(30_000, false, true, false, 100, &CONFIG);
+        assert!(policy.host_lost());
+        policy.update(30_100, true, true, false, 100, &CONFIG);
+        assert!(!policy.host_lost());
+        // activity without a configured device does not count
+        policy.update(30_200, true, false, false, 100, &CONFIG);
+        assert!(policy.host_lost());
+    }
+
+    #[test]
+    fn never_seen_host_is_lost() {
+        let mut policy = AutonomousShutdown::new();
+        policy.update(0, false, true, false, 100, &CONFIG);
+        assert!(policy.host_lost());
+    }
+
+    #[test]
+    fn starts_once_on_low_battery_without_host() {
+        let mut policy = AutonomousShutdown::new();
+        assert_eq!(policy.update(0, false, false, true, 11, &CONFIG), None);
+        assert_eq!(policy.update(100, false, false, true, 10, &CONFIG), Some(AutonomousAction::Start(60)));
+        assert_eq!(policy.update(200, false, false, true, 9, &CONFIG), None);
+    }
+
+    #[test]
+    fn stays_idle_while_the_host_listens_or_mains_is_present() {
+        let mut policy = AutonomousShutdown::new();
+        assert_eq!(policy.update(0, true, true, true, 5, &CONFIG), None);
+        assert_eq!(policy.update(100, false, false, false, 5, &CONFIG), None);
+    }
+
+    #[test]
+    fn zero_capacity_disables_the_policy() {
+        let config = AutonomousConfig {
+            shutdown_capacity: 0,
+            ..CONFIG
+        };
+        let mut policy = AutonomousShutdown::new();
+        assert_eq!(policy.update(0, false, false, true, 0, &config), None);
+    }
+
+    #[test]
+    fn mains_or_host_return_aborts() {
+        let mut policy = AutonomousShutdown::new();
+        policy.update(0, false, false, true, 5, &CONFIG);
+        assert_eq!(policy.update(100, false, false, false, 5, &CONFIG), Some(AutonomousAction::Abort));
+        assert_eq!(policy.update(200, false, false, false, 5, &CONFIG), None);
+
+        policy.update(300, false, false, true, 5, &CONFIG);
+        assert_eq!(policy.update(400, true, true, true, 5, &CONFIG), Some(AutonomousAction::Abort));
+    }
+
+    #[test]
+    fn abort_keeps_the_countdown_of_the_host() {
+        let mut policy = AutonomousShutdown::new();
+        let mut output = OutputControl::new();
+        apply(policy.update(0, false, false, true, 5, &CONFIG), &mut output);
+        assert_eq!(output.shutdown_delay(), 60);
+        // the host comes back and schedules its own shutdown
+        output.set_shutdown_delay(20);
+        apply(policy.update(100, true, true, true, 5, &CONFIG), &mut output);
+        assert_eq!(output.shutdown_delay(), 20);
+    }
+
+    #[test]
+    fn abort_cancels_its_own_countdown() {
+        let mut policy = AutonomousShutdown::new();
+        let mut output = OutputControl::new();
+        apply(policy.update(0, false, false, true, 5, &CONFIG), &mut output);
+        apply(policy.update(100, false, false, false, 5, &CONFIG), &mut output);
+        assert_eq!(output.shutdown_delay(), -1);
+        assert!(output.tick(120_000, false));
+    }
+}
@@ -0,0 +1,15 @@
+//! Hardware independent logic of the UPS firmware.
+//!
+//! Each module only depends on `core` and the shared protocol crates and reaches the
+//! firmware through a context trait, so the firmware drives it on the target and the
+//! tests drive it on the host.
+
+#![no_std]
+
+pub mod autonomous;
+pub mod megatec;
+pub mod modbus;
+pub mod report_scheduler;
+pub mod ring_buffer;
+pub mod shell;
+pub mod shutdown;
@@ -0,0 +1,319 @@
+//! Megatec "Q1" serial UPS protocol, as spoken by NUT's `nutdrv_qx` and `blazer_ser` drivers.
+//!
+//! Like the shell it only depends on `core`, the firmware is reached through `MegatecContext`,
+//! so commands can be fed to it on the host.
+//!
+//! Commands are terminated by `\r` and are not echoed, commands that are not understood
+//! are sent back unchanged, which the drivers take as "not supported":
+//!
+//! | command       | reply                                              |
+//! |---------------|----------------------------------------------------|
+//! | `Q1`          | `(MMM.M NNN.N PPP.P QQQ RR.R SS.S TT.T b7..b0`     |
+//! | `F`           | `#MMM.M QQQ SS.SS RR.R` (ratings)                  |
+//! | `I`           | `#company model version`, 15, 10 and 10 characters |
+//! | `T`, `TL`, `Tn` | none, battery tests are not supported          |
+//! | `CT`          | none                                               |
+//! | `Q`           | none, toggles the beeper flag                      |
+//! | `Sn`, `SnRm`  | none, cuts the output after n minutes (`.2`..`.9`, `01`..`99`), restores it m minutes (`0000`..`9999`) later once mains is present |
+//! | `C`           | none, cancels a shutdown, switches the output on if it was cut |
+
+use core::fmt::{self, Write};
+
+/// longest command, `S.5R0010` is the longest standard one
+pub const LINE_LEN: usize = 16;
+
+///
+///
+/// measurements and state reported by `Q1`
+///
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub struct UpsStatus {
+    pub input_voltage: f32,
+    pub output_voltage: f32,
+    /// load in % of the rated current
+    pub load: u8,
+    pub battery_voltage: f32,
+    pub temperature: f32,
+    pub utility_fail: bool,
+    pub battery_low: bool,
+    /// a shutdown countdown is running or the output is cut
+    pub shutdown_active: bool,
+}
+
+///
+///
+/// ratings reported by `F`
+///
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct UpsRating {
+    pub voltage: f32,
+    pub current: u8,
+    pub battery_voltage: f32,
+}
+
+///
+///
+/// what the protocol needs from the firmware
+///
+pub trait MegatecContext {
+    fn status(&mut self) -> UpsStatus;
+    fn rating(&self) -> UpsRating;
+    /// company, model and version reported by `I`
+    fn info(&self) -> (&'static str, &'static str, &'static str);
+    /// cuts the output after `delay_s`, it is restored `restore_s` after the cut once mains is present
+    fn shutdown(&mut self, delay_s: u16, restore_s: u32);
+    /// aborts a pending shutdown and switches the output on again
+    fn cancel_shutdown(&mut self);
+}
+
+///
+///
+/// answers the commands, the firmware splits the received bytes into lines of up to
+/// `LINE_LEN` bytes with `ring_buffer::LineReader`
+///
+pub struct Megatec {
+    /// there is no beeper, the flag only mirrors the `Q` toggles to the host
+    beeper: bool,
+}
+
+impl Megatec {
+    pub const fn new() -> Self {
+        Megatec { beeper: true }
+    }
+
+    ///
+    ///
+    /// answers one command, without the terminating `\r`
+    ///
+    pub fn execute(&mut self, line: &str, context: &mut dyn MegatecContext, out: &mut dyn Write) -> fmt::Result {
+        match line {
+            "Q1" => {
+                let status = context.status();
+                write!(
+                    out,
+                    "({:05.1} {:05.1} {:05.1} {:03} {:04.1} {:04.1} {:04.1} {}{}{}{}{}{}{}{}\r",
+                    status.input_voltage,
+                    status.input_voltage,
+                    status.output_voltage,
+                    status.load,
+                    0.0, // DC input, there is no frequency
+                    status.battery_voltage,
+                    status.temperature,
+                    status.utility_fail as u8,
+                    status.battery_low as u8,
+                    0, // bypass / boost
+                    0, // UPS failed
+                    1, // standby (offline) UPS
+                    0, // test in progress
+                    status.shutdown_active as u8,
+                    self.beeper as u8,
+                )
+            }
+            "F" => {
+                let rating = context.rating();
+                write!(
+                    out,
+                    "#{:05.1} {:03} {:05.2} {:04.1}\r",
+                    rating.voltage, rating.current, rating.battery_voltage, 0.0
+                )
+            }
+            "I" => {
+                let (company, model, version) = context.info();
+                write!(out, "#{:<15.15} {:<10.10} {:<10.10}\r", company, model, version)
+            }
+            "Q" => {
+                self.beeper = !self.beeper;
+                Ok(())
+            }
+            "C" => {
+                context.cancel_shutdown();
+                Ok(())
+            }
+            // there is no way to run the UPS from the battery on request
+            "T" | "TL" | "CT" => Ok(()),
+            _ if line.starts_with('T') && parse_minutes(&line[1..]).is_some() => Ok(()),
+            _ => match parse_shutdown(line) {
+                Some((delay_s, restore_s)) => {
+                    context.shutdown(delay_s, restore_s);
+                    Ok(())
+                }
+                // not understood, echo it back
+                None => write!(out, "{}\r", line),
+            },
+        }
+    }
+}
+
+impl Default for Megatec {
+    fn default() -> Self {
+        Megatec::new()
+    }
+}
+
+///
+///
+/// parses `Sn` or `SnRm`
+///
+/// returns: the shutdown delay and the restore time in seconds
+///
+fn parse_shutdown(line: &str) -> Option<(u16, u32)> {
+    let line = line.strip_prefix('S')?;
+    let (delay, restore) = match line.split_once('R') {
+        Some((delay, restore)) => (delay, Some(restore)),
+        None => (line, None),
+    };
+    let delay_s = parse_minutes(delay)?;
+    let restore_s = match restore {
+        Some(restore) if restore.len() == 4 && restore.bytes().all(|b| b.is_ascii_digit()) => {
+            restore.parse::<u32>().ok()? * 60
+        }
+        Some(_) => return None,
+        None => 0,
+    };
+    Some((delay_s, restore_s))
+}
+
+///
+///
+/// parses a delay of `.2`..`.9` or `01`..`99` minutes
+///
+/// returns: the delay in seconds
+///
+fn parse_minutes(minutes: &str) -> Option<u16> {
+    let digits = minutes.as_bytes();
+    match digits {
+        [b'.', tenths @ b'2'..=b'9'] => Some((tenths - b'0') as u16 * 6),
+        [tens, ones] if tens.is_ascii_digit() && ones.is_ascii_digit() && digits != b"00" => {
+            Some(((tens - b'0') as u16 * 10 + (ones - b'0') as u16) * 60)
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use crate::ring_buffer::{LineReader, RingBuffer};
+    use std::string::String;
+    use std::vec::Vec;
+
+    #[derive(Default)]
+    struct Ups {
+        status: UpsStatus,
+        shutdowns: Vec<(u16, u32)>,
+        cancels: usize,
+    }
+
+    impl MegatecContext for Ups {
+        fn status(&mut self) -> UpsStatus {
+            self.status
+        }
+
+        fn rating(&self) -> UpsRating {
+            UpsRating { voltage: 12.0, current: 3, battery_voltage: 7.4 }
+        }
+
+        fn info(&self) -> (&'static str, &'static str, &'static str) {
+            ("hacknus", "open-source-ups", "0.1.0")
+        }
+
+        fn shutdown(&mut self, delay_s: u16, restore_s: u32) {
+            self.shutdowns.push((delay_s, restore_s));
+        }
+
+        fn cancel_shutdown(&mut self) {
+            self.cancels += 1;
+        }
+    }
+
+    /// splits the bytes into commands like the firmware does and executes them
+    fn send(megatec: &mut Megatec, ups: &mut Ups, bytes: &[u8]) -> String {
+        let mut rx = RingBuffer::<64>::new();
+        for &byte in bytes {
+            assert!(rx.push(byte));
+        }
+        let mut reader = LineReader::new();
+        let mut line = [0; LINE_LEN];
+        let mut out = String::new();
+        while let Some(result) = reader.read_line(&mut rx, &mut line) {
+            if let Ok(len @ 1..) = result {
+                let command = core::str::from_utf8(&line[..len]).unwrap();
+                megatec.execute(command, ups, &mut out).unwrap();
+            }
+        }
+        out
+    }
+
+    #[test]
+    fn q1_reports_the_status() {
+        let status = UpsStatus {
+            input_voltage: 0.0,
+            output_voltage: 12.0,
+            load: 42,
+            battery_voltage: 7.25,
+            temperature: 31.5,
+            utility_fail: true,
+            battery_low: true,
+            shutdown_active: false,
+        };
+        let mut ups = Ups { status, ..Default::default() };
+        let mut megatec = Megatec::new();
+        let out = send(&mut megatec, &mut ups, b"Q1\r");
+        assert_eq!(out, "(000.0 000.0 012.0 042 00.0 07.2 31.5 11001001\r");
+        // Q toggles the beeper bit
+        send(&mut megatec, &mut ups, b"Q\r");
+        assert!(send(&mut megatec, &mut ups, b"Q1\r\n").ends_with(" 11001000\r"));
+    }
+
+    #[test]
+    fn ratings_and_info() {
+        let mut ups = Ups::default();
+        let mut megatec = Megatec::new();
+        assert_eq!(send(&mut megatec, &mut ups, b"F\r"), "#012.0 003 07.40 00.0\r");
+        assert_eq!(
+            send(&mut megatec, &mut ups, b"I\r"),
+            "#hacknus         open-sourc 0.1.0     \r"
+        );
+    }
+
+    #[test]
+    fn shutdown_commands() {
+        let mut ups = Ups::default();
+        let mut megatec = Megatec::new();
+        let out = send(&mut megatec, &mut ups, b"S.2\rS.9\rS01\rS99\rS05R0010\rS.5R9999\rC\r");
+        assert_eq!(out, "");
+        assert_eq!(ups.shutdowns, [(12, 0), (54, 0), (60, 0), (5940, 0), (300, 600), (30, 599_940)]);
+        assert_eq!(ups.cancels, 1);
+    }
+
+    #[test]
+    fn rejects_delays_outside_the_documented_range() {
+        let mut ups = Ups::default();
+        let mut megatec = Megatec::new();
+        for command in ["S.0", "S.1", "S00", "S1", "S100", "S.a", "S05R10", "S05R00001", "S05Rxxxx"] {
+            let out = send(&mut megatec, &mut ups, std::format!("{}\r", command).as_bytes());
+            assert_eq!(out, std::format!("{}\r", command));
+        }
+        assert!(ups.shutdowns.is_empty());
+    }
+
+    #[test]
+    fn tests_are_accepted_without_reply() {
+        let mut ups = Ups::default();
+        let mut megatec = Megatec::new();
+        assert_eq!(send(&mut megatec, &mut ups, b"T\rTL\rT.5\rT10\rCT\r"), "");
+        assert_eq!(send(&mut megatec, &mut ups, b"T.1\r"), "T.1\r");
+    }
+
+    #[test]
+    fn drops_overlong_lines() {
+        let mut ups = Ups::default();
+        let mut megatec = Megatec::new();
+        let mut line = [b'X'; LINE_LEN + 1].to_vec();
+        line.push(b'\r');
+        assert_eq!(send(&mut megatec, &mut ups, &line), "");
+        assert_eq!(send(&mut megatec, &mut ups, b"M\r"), "M\r");
+    }
+}
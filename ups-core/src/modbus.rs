@@ -0,0 +1,449 @@
+//! Modbus RTU slave on the CDC port.
+//!
+//! Only depends on `core` and `ups_protocol::crc16`, the firmware is reached through `ModbusContext`,
+//! so frames can be fed to the slave on the host.
+//!
+//! There is no baud rate on USB, a frame ends once the length implied by its function
+//! code arrived, a gap of `FRAME_TIMEOUT_MS` discards an incomplete frame.
+//!
+//! Register map, addresses are 0 based:
+//!
+//! | table            | address | content                                              |
+//! |------------------|---------|------------------------------------------------------|
+//! | input register   | 0       | vin in cV                                            |
+//! | input register   | 1       | vbat in cV                                           |
+//! | input register   | 2       | current in mA, signed                                |
+//! | input register   | 3       | state of charge in %                                 |
+//! | input register   | 4       | runtime to empty in s                                |
+//! | input register   | 5       | PresentStatus word, charging in bit 0                |
+//! | input register   | 6       | average current in mA, signed                        |
+//! | input register   | 7       | die temperature in 0.1 °C, signed                    |
+//! | holding register | 0       | remaining capacity limit in %                        |
+//! | holding register | 1       | warning capacity limit in %                          |
+//! | holding register | 2       | delay before shutdown in s, signed, -1 aborts        |
+//! | holding register | 3       | delay before reboot in s, signed, -1 aborts          |
+//! | holding register | 4       | autonomous shutdown capacity in %, 0 disables it     |
+//! | holding register | 5       | autonomous shutdown grace in s                       |
+//! | holding register | 6       | host timeout in s                                    |
+//! | coil             | 0       | output on, writing 0 cuts it, writing 1 restores it  |
+//! | discrete input   | 0..=13  | PresentStatus bits, see `ups_hid::report::Status`    |
+//!
+//! Supported functions: 0x01, 0x02, 0x03, 0x04, 0x05, 0x06 and 0x10.
+
+use ups_protocol::crc16;
+
+/// longest RTU frame
+pub const MAX_ADU_LEN: usize = 256;
+/// an incomplete frame is discarded after this silence
+pub const FRAME_TIMEOUT_MS: u32 = 50;
+/// requests to this address are executed without a response
+const BROADCAST_ADDRESS: u8 = 0;
+/// most registers in one read, limited by the frame length
+const MAX_READ_REGISTERS: u16 = 125;
+const MAX_READ_BITS: u16 = 2000;
+const MAX_WRITE_REGISTERS: u16 = 123;
+
+const READ_COILS: u8 = 0x01;
+const READ_DISCRETE_INPUTS: u8 = 0x02;
+const READ_HOLDING_REGISTERS: u8 = 0x03;
+const READ_INPUT_REGISTERS: u8 = 0x04;
+const WRITE_SINGLE_COIL: u8 = 0x05;
+const WRITE_SINGLE_REGISTER: u8 = 0x06;
+const WRITE_MULTIPLE_REGISTERS: u8 = 0x10;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Exception {
+    IllegalFunction = 0x01,
+    IllegalDataAddress = 0x02,
+    IllegalDataValue = 0x03,
+    ServerDeviceFailure = 0x04,
+}
+
+///
+///
+/// what the slave needs from the firmware, `None` marks an address outside the map
+///
+pub trait ModbusContext {
+    fn input_register(&mut self, address: u16) -> Option<u16>;
+    fn holding_register(&mut self, address: u16) -> Option<u16>;
+    fn write_holding_register(&mut self, address: u16, value: u16) -> Result<(), Exception>;
+    fn coil(&mut self, address: u16) -> Option<bool>;
+    fn write_coil(&mut self, address: u16, value: bool) -> Result<(), Exception>;
+    fn discrete_input(&mut self, address: u16) -> Option<bool>;
+}
+
+///
+///
+/// assembles request frames from the received bytes and answers the ones for `address`
+///
+pub struct ModbusSlave {
+    address: u8,
+    frame: [u8; MAX_ADU_LEN],
+    len: usize,
+    last_byte_ms: u32,
+}
+
+impl ModbusSlave {
+    pub const fn new(address: u8) -> Self {
+        ModbusSlave {
+            address,
+            frame: [0; MAX_ADU_LEN],
+            len: 0,
+            last_byte_ms: 0,
+        }
+    }
+
+    ///
+    ///
+    /// processes received bytes, `send` is called with every response frame
+    ///
+    pub fn input(&mut self, bytes: &[u8], now_ms: u32, context: &mut dyn ModbusContext, send: &mut dyn FnMut(&[u8])) {
+        if self.len > 0 && now_ms.wrapping_sub(self.last_byte_ms) >= FRAME_TIMEOUT_MS {
+            self.len = 0;
+        }
+        self.last_byte_ms = now_ms;
+        for &byte in bytes {
+            if self.len == MAX_ADU_LEN {
+                // garbage, start over
+                self.len = 0;
+            }
+            self.frame[self.len] = byte;
+            self.len += 1;
+            if request_len(&self.frame[..self.len]) == Some(self.len) {
+                let frame = self.frame;
+                let len = core::mem::replace(&mut self.len, 0);
+                let mut response = [0; MAX_ADU_LEN];
+                if let Some(response_len) = self.process(&frame[..len], context, &mut response) {
+                    send(&response[..response_len]);
+                }
+            }
+        }
+    }
+
+    ///
+    ///
+    /// executes one complete request frame
+    ///
+    /// returns: the length of the response in `response`, `None` if there is nothing to send
+    ///
+    pub fn process(&self, frame: &[u8], context: &mut dyn ModbusContext, response: &mut [u8; MAX_ADU_LEN]) -> Option<usize> {
+        if frame.len() < 4 {
+            return None;
+        }
+        let (pdu, crc) = frame.split_at(frame.len() - 2);
+        // a corrupted frame is dropped without response
+        if crc16(pdu) != u16::from_le_bytes([crc[0], crc[1]]) {
+            return None;
+        }
+        let address = pdu[0];
+        if address != self.address && address != BROADCAST_ADDRESS {
+            return None;
+        }
+        let function = pdu[1];
+        response[0] = self.address;
+        response[1] = function;
+        let len = match execute(function, &pdu[2..], context, &mut response[2..]) {
+            Ok(len) => 2 + len,
+            Err(exception) => {
+                response[1] = function | 0x80;
+                response[2] = exception as u8;
+                3
+            }
+        };
+        if address == BROADCAST_ADDRESS {
+            return None;
+        }
+        let crc = crc16(&response[..len]);
+        response[len..len + 2].copy_from_slice(&crc.to_le_bytes());
+        Some(len + 2)
+    }
+}
+
+///
+///
+/// returns: the length of the request frame starting with `frame`, `None` if not known yet
+///
+fn request_len(frame: &[u8]) -> Option<usize> {
+    match *frame.get(1)? {
+        WRITE_MULTIPLE_REGISTERS => frame.get(6).map(|&count| 9 + count as usize),
+        // every other function has a fixed length, unknown ones are answered with an exception
+        _ => Some(8),
+    }
+}
+
+fn u16_at(data: &[u8], offset: usize) -> Result<u16, Exception> {
+    match data.get(offset..offset + 2) {
+        Some(bytes) => Ok(u16::from_be_bytes([bytes[0], bytes[1]])),
+        None => Err(Exception::IllegalDataValue),
+    }
+}
+
+///
+///
+/// runs the function on the request data
+///
+/// returns: the length of the response data written to `out`
+///
+fn execute(function: u8, data: &[u8], context: &mut dyn ModbusContext, out: &mut [u8]) -> Result<usize, Exception> {
+    match function {
+        READ_COILS | READ_DISCRETE_INPUTS => {
+            let start = u16_at(data, 0)?;
+            let count = u16_at(data, 2)?;
+            if count == 0 || count > MAX_READ_BITS {
+                return Err(Exception::IllegalDataValue);
+            }
+            let bytes = (count as usize + 7) / 8;
+            out[0] = bytes as u8;
+            out[1..1 + bytes].fill(0);
+            for i in 0..count {
+                let address = start.checked_add(i).ok_or(Exception::IllegalDataAddress)?;
+                let bit = if function == READ_COILS {
+                    context.coil(address)
+                } else {
+                    context.discrete_input(address)
+                };
+                if bit.ok_or(Exception::IllegalDataAddress)? {
+                    out[1 + i as usize / 8] |= 1 << (i % 8);
+                }
+            }
+            Ok(1 + bytes)
+        }
+        READ_HOLDING_REGISTERS | READ_INPUT_REGISTERS => {
+            let start = u16_at(data, 0)?;
+            let count = u16_at(data, 2)?;
+            if count == 0 || count > MAX_READ_REGISTERS {
+                return Err(Exception::IllegalDataValue);
+            }
+            out[0] = (count * 2) as u8;
+            for i in 0..count {
+                let address = start.checked_add(i).ok_or(Exception::IllegalDataAddress)?;
+                let value = if function == READ_HOLDING_REGISTERS {
+                    context.holding_register(address)
+                } else {
+                    context.input_register(address)
+                };
+                let value = value.ok_or(Exception::IllegalDataAddress)?;
+                let offset = 1 + 2 * i as usize;
+                out[offset..offset + 2].copy_from_slice(&value.to_be_bytes());
+            }
+            Ok(1 + 2 * count as usize)
+        }
+        WRITE_SINGLE_COIL => {
+            let address = u16_at(data, 0)?;
+            let value = match u16_at(data, 2)? {
+                0xFF00 => true,
+                0x0000 => false,
+                _ => return Err(Exception::IllegalDataValue),
+            };
+            context.write_coil(address, value)?;
+            // the response echoes the request
+            out[..4].copy_from_slice(&data[..4]);
+            Ok(4)
+        }
+        WRITE_SINGLE_REGISTER => {
+            let address = u16_at(data, 0)?;
+            let value = u16_at(data, 2)?;
+            context.write_holding_register(address, value)?;
+            out[..4].copy_from_slice(&data[..4]);
+            Ok(4)
+        }
+        WRITE_MULTIPLE_REGISTERS => {
+            let start = u16_at(data, 0)?;
+            let count = u16_at(data, 2)?;
+            let bytes = *data.get(4).ok_or(Exception::IllegalDataValue)? as usize;
+            if count == 0 || count > MAX_WRITE_REGISTERS || bytes != 2 * count as usize {
+                return Err(Exception::IllegalDataValue);
+            }
+            // registers are written one by one, a failing register leaves the ones before written
+            for i in 0..count {
+                let address = start.checked_add(i).ok_or(Exception::IllegalDataAddress)?;
+                let value = u16_at(data, 5 + 2 * i as usize)?;
+                context.write_holding_register(address, value)?;
+            }
+            out[..4].copy_from_slice(&data[..4]);
+            Ok(4)
+        }
+        _ => Err(Exception::IllegalFunction),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// register map of the tests: 3 holding registers, 2 input registers, 1 coil, 2 discrete inputs
+    struct Registers {
+        holding: [u16; 3],
+        input: [u16; 2],
+        coil: bool,
+        discrete: [bool; 2],
+    }
+
+    impl Registers {
+        fn new() -> Self {
+            Registers {
+                holding: [20, 10, 0xFFFF],
+                input: [1480, 1300],
+                coil: true,
+                discrete: [true, false],
+            }
+        }
+    }
+
+    impl ModbusContext for Registers {
+        fn input_register(&mut self, address: u16) -> Option<u16> {
+            self.input.get(address as usize).copied()
+        }
+
+        fn holding_register(&mut self, address: u16) -> Option<u16> {
+            self.holding.get(address as usize).copied()
+        }
+
+        fn write_holding_register(&mut self, address: u16, value: u16) -> Result<(), Exception> {
+            match self.holding.get_mut(address as usize) {
+                Some(_) if value > 100 => Err(Exception::IllegalDataValue),
+                Some(register) => {
+                    *register = value;
+                    Ok(())
+                }
+                None => Err(Exception::IllegalDataAddress),
+            }
+        }
+
+        fn coil(&mut self, address: u16) -> Option<bool> {
+            (address == 0).then_some(self.coil)
+        }
+
+        fn write_coil(&mut self, address: u16, value: bool) -> Result<(), Exception> {
+            if address != 0 {
+                return Err(Exception::IllegalDataAddress);
+            }
+            self.coil = value;
+            Ok(())
+        }
+
+        fn discrete_input(&mut self, address: u16) -> Option<bool> {
+            self.discrete.get(address as usize).copied()
+        }
+    }
+
+    /// feeds `chunks` to a slave with address 1, one chunk per millisecond
+    fn exchange(registers: &mut Registers, chunks: &[&[u8]]) -> Option<([u8; MAX_ADU_LEN], usize)> {
+        let mut slave = ModbusSlave::new(1);
+        let mut response = None;
+        for (now, chunk) in chunks.iter().enumerate() {
+            slave.input(chunk, now as u32, registers, &mut |frame| {
+                assert!(response.is_none(), "more than one response");
+                let mut bytes = [0; MAX_ADU_LEN];
+                bytes[..frame.len()].copy_from_slice(frame);
+                response = Some((bytes, frame.len()));
+            });
+        }
+        response
+    }
+
+    fn request(registers: &mut Registers, frame: &[u8]) -> Option<([u8; MAX_ADU_LEN], usize)> {
+        exchange(registers, &[frame])
+    }
+
+    fn assert_response(response: Option<([u8; MAX_ADU_LEN], usize)>, expected: &[u8]) {
+        let (bytes, len) = response.expect("no response");
+        assert_eq!(&bytes[..len], expected);
+    }
+
+    #[test]
+    fn reads_holding_registers() {
+        let mut registers = Registers::new();
+        let response = request(&mut registers, &[0x01, 0x03, 0x00, 0x00, 0x00, 0x03, 0x05, 0xCB]);
+        assert_response(response, &[0x01, 0x03, 0x06, 0x00, 0x14, 0x00, 0x0A, 0xFF, 0xFF, 0x30, 0xC4]);
+    }
+
+    #[test]
+    fn reads_input_registers() {
+        let mut registers = Registers::new();
+        let response = request(&mut registers, &[0x01, 0x04, 0x00, 0x00, 0x00, 0x02, 0x71, 0xCB]);
+        assert_response(response, &[0x01, 0x04, 0x04, 0x05, 0xC8, 0x05, 0x14, 0x79, 0xE9]);
+    }
+
+    #[test]
+    fn writes_single_coil() {
+        let mut registers = Registers::new();
+        let frame = [0x01, 0x05, 0x00, 0x00, 0x00, 0x00, 0xCD, 0xCA];
+        assert_response(request(&mut registers, &frame), &frame);
+        assert!(!registers.coil);
+    }
+
+    #[test]
+    fn writes_single_register() {
+        let mut registers = Registers::new();
+        let frame = [0x01, 0x06, 0x00, 0x01, 0x00, 0x0F, 0x98, 0x0E];
+        assert_response(request(&mut registers, &frame), &frame);
+        assert_eq!(registers.holding, [20, 15, 0xFFFF]);
+    }
+
+    #[test]
+    fn writes_multiple_registers() {
+        let mut registers = Registers::new();
+        let frame = [0x01, 0x10, 0x00, 0x00, 0x00, 0x02, 0x04, 0x00, 0x19, 0x00, 0x0F, 0x62, 0x6C];
+        let response = request(&mut registers, &frame);
+        assert_response(response, &[0x01, 0x10, 0x00, 0x00, 0x00, 0x02, 0x41, 0xC8]);
+        assert_eq!(registers.holding, [25, 15, 0xFFFF]);
+    }
+
+    #[test]
+    fn drops_frame_with_bad_crc() {
+        let mut registers = Registers::new();
+        let response = request(&mut registers, &[0x01, 0x06, 0x00, 0x01, 0x00, 0x0F, 0x98, 0x0F]);
+        assert!(response.is_none());
+        assert_eq!(registers.holding, [20, 10, 0xFFFF]);
+    }
+
+    #[test]
+    fn executes_broadcast_without_response() {
+        let mut registers = Registers::new();
+        let response = request(&mut registers, &[0x00, 0x06, 0x00, 0x01, 0x00, 0x1E, 0x59, 0xD3]);
+        assert!(response.is_none());
+        assert_eq!(registers.holding, [20, 30, 0xFFFF]);
+    }
+
+    #[test]
+    fn ignores_other_addresses() {
+        let mut registers = Registers::new();
+        assert!(request(&mut registers, &[0x02, 0x03, 0x00, 0x00, 0x00, 0x01, 0x84, 0x39]).is_none());
+    }
+
+    #[test]
+    fn answers_exceptions() {
+        let mut registers = Registers::new();
+        // register 0x10 is outside the map
+        let response = request(&mut registers, &[0x01, 0x03, 0x00, 0x10, 0x00, 0x01, 0x85, 0xCF]);
+        assert_response(response, &[0x01, 0x83, 0x02, 0xC0, 0xF1]);
+        // function 0x2B is not supported
+        let response = request(&mut registers, &[0x01, 0x2B, 0x00, 0x00, 0x00, 0x00, 0x25, 0xCC]);
+        assert_response(response, &[0x01, 0xAB, 0x01, 0x9E, 0xF0]);
+    }
+
+    #[test]
+    fn reassembles_split_frames() {
+        let mut registers = Registers::new();
+        let chunks: [&[u8]; 3] = [&[0x01, 0x10, 0x00], &[0x00, 0x00, 0x02, 0x04, 0x00], &[0x19, 0x00, 0x0F, 0x62, 0x6C]];
+        let response = exchange(&mut registers, &chunks);
+        assert_response(response, &[0x01, 0x10, 0x00, 0x00, 0x00, 0x02, 0x41, 0xC8]);
+        assert_eq!(registers.holding, [25, 15, 0xFFFF]);
+    }
+
+    #[test]
+    fn discards_incomplete_frame_after_timeout() {
+        let mut registers = Registers::new();
+        let mut slave = ModbusSlave::new(1);
+        let mut responses = 0;
+        slave.input(&[0x01, 0x03, 0x00], 0, &mut registers, &mut |_| responses += 1);
+        slave.input(
+            &[0x01, 0x03, 0x00, 0x00, 0x00, 0x03, 0x05, 0xCB],
+            FRAME_TIMEOUT_MS,
+            &mut registers,
+            &mut |_| responses += 1,
+        );
+        assert_eq!(responses, 1);
+    }
+}
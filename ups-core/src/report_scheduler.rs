@@ -0,0 +1,208 @@
+//! Pacing of the HID input reports: which report the firmware sends next.
+
+use ups_hid::report::*;
+
+/// minimum time between two reports of a measured value, faster changes are coalesced
+pub const MIN_SPACING_MS: u32 = 1000;
+/// minimum time between two status reports, short so a mains loss reaches the host
+/// quickly, but a flapping status bit still leaves room for the other reports
+pub const STATUS_SPACING_MS: u32 = 100;
+
+/// input reports tracked by the scheduler
+const TRACKED: [(u8, u32); 6] = [
+    (HID_PD_PRESENTSTATUS, STATUS_SPACING_MS),
+    (HID_PD_REMAININGCAPACITY, MIN_SPACING_MS),
+    (HID_PD_RUNTIMETOEMPTY, MIN_SPACING_MS),
+    (HID_PD_VOLTAGE, MIN_SPACING_MS),
+    (HID_PD_CURRENT, MIN_SPACING_MS),
+    (HID_PD_AVERAGECURRENT, MIN_SPACING_MS),
+];
+
+#[derive(Clone, Copy)]
+struct Slot {
+    id: u8,
+    min_spacing_ms: u32,
+    /// latest value reported by the firmware
+    current: Option<Report>,
+    /// value the host received last
+    sent: Option<ReportBytes>,
+    sent_ms: u32,
+}
+
+///
+///
+/// decides which input report is sent next
+///
+/// a report is sent as soon as its value changes, but not more often than its
+/// minimum spacing, reports that did not change are repeated after the keep-alive
+/// interval so the host can tell that the UPS is still there
+///
+/// due reports take turns, the search starts after the report sent last, so a
+/// report that is due on every call cannot hold back the others
+///
+pub struct ReportScheduler {
+    slots: [Slot; TRACKED.len()],
+    keep_alive_ms: u32,
+    /// index of the slot sent last
+    last: usize,
+}
+
+impl ReportScheduler {
+    pub fn new(keep_alive_ms: u32) -> Self {
+        let mut slots = [Slot {
+            id: 0,
+            min_spacing_ms: 0,
+            current: None,
+            sent: None,
+            sent_ms: 0,
+        }; TRACKED.len()];
+        for (slot, (id, min_spacing_ms)) in slots.iter_mut().zip(TRACKED) {
+            slot.id = id;
+            slot.min_spacing_ms = min_spacing_ms;
+        }
+        ReportScheduler {
+            slots,
+            keep_alive_ms,
+            // the first search starts with the status
+            last: TRACKED.len() - 1,
+        }
+    }
+
+    pub fn set_keep_alive(&mut self, keep_alive_ms: u32) {
+        self.keep_alive_ms = keep_alive_ms;
+    }
+
+    /// stores the latest value of a report, reports that are not tracked are ignored
+    pub fn update(&mut self, report: Report) {
+        if let Some(slot) = self.slots.iter_mut().find(|slot| slot.id == report.id()) {
+            slot.current = Some(report);
+        }
+    }
+
+    ///
+    ///
+    /// returns: the report that should be sent now, `None` if nothing is due
+    ///
+    pub fn next(&self, now_ms: u32) -> Option<Report> {
+        // in turn, starting after the slot sent last
+        let turn = || (1..=self.slots.len()).map(|i| &self.slots[(self.last + i) % self.slots.len()]);
+        // changed values first, then the keep-alive
+        let changed = turn().find(|slot| {
+            slot.current.map_or(false, |report| {
+                slot.sent.map_or(true, |sent| sent != report.encode())
+                    && now_ms.wrapping_sub(slot.sent_ms) >= slot.min_spacing_ms
+            })
+        });
+        let due = changed.or_else(|| {
+            turn().find(|slot| slot.current.is_some() && now_ms.wrapping_sub(slot.sent_ms) >= self.keep_alive_ms)
+        });
+        due.and_then(|slot| slot.current)
+    }
+
+    /// records that the host received a report
+    pub fn sent(&mut self, report: &Report, now_ms: u32) {
+        if let Some(index) = self.slots.iter().position(|slot| slot.id == report.id()) {
+            self.slots[index].sent = Some(report.encode());
+            self.slots[index].sent_ms = now_ms;
+            self.last = index;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEEP_ALIVE_MS: u32 = 10_000;
+    /// period at which the firmware polls the scheduler
+    const PERIOD_MS: u32 = 20;
+
+    fn status(ac_present: u8) -> Report {
+        let mut status = Status::new();
+        status.set_ac_present(ac_present);
+        Report::PresentStatus(status)
+    }
+
+    /// sends what is due like the firmware does, returns the id of the sent report
+    fn poll(scheduler: &mut ReportScheduler, now_ms: u32) -> Option<u8> {
+        let report = scheduler.next(now_ms)?;
+        scheduler.sent(&report, now_ms);
+        Some(report.id())
+    }
+
+    #[test]
+    fn sends_every_report_once_then_waits() {
+        let mut scheduler = ReportScheduler::new(KEEP_ALIVE_MS);
+        scheduler.update(status(1));
+        scheduler.update(Report::RemainingCapacity(80));
+        scheduler.update(Report::Voltage(800));
+        assert_eq!(poll(&mut scheduler, 1000), Some(HID_PD_PRESENTSTATUS));
+        assert_eq!(poll(&mut scheduler, 1020), Some(HID_PD_REMAININGCAPACITY));
+        assert_eq!(poll(&mut scheduler, 1040), Some(HID_PD_VOLTAGE));
+        assert_eq!(poll(&mut scheduler, 1060), None);
+    }
+
+    #[test]
+    fn untracked_reports_are_ignored() {
+        let mut scheduler = ReportScheduler::new(KEEP_ALIVE_MS);
+        scheduler.update(Report::DelayBeforeShutdown(10));
+        assert_eq!(poll(&mut scheduler, 1000), None);
+    }
+
+    #[test]
+    fn changes_are_paced_by_the_minimum_spacing() {
+        let mut scheduler = ReportScheduler::new(KEEP_ALIVE_MS);
+        let mut sent = 0;
+        for (i, now) in (1000..3000).step_by(PERIOD_MS as usize).enumerate() {
+            scheduler.update(Report::Voltage(800 + i as u16));
+            if poll(&mut scheduler, now).is_some() {
+                sent += 1;
+            }
+        }
+        // at 1000 and 2000
+        assert_eq!(sent, 2);
+    }
+
+    #[test]
+    fn status_changes_are_paced_by_the_status_spacing() {
+        let mut scheduler = ReportScheduler::new(KEEP_ALIVE_MS);
+        scheduler.update(status(1));
+        assert_eq!(poll(&mut scheduler, 1000), Some(HID_PD_PRESENTSTATUS));
+        scheduler.update(status(0));
+        assert_eq!(poll(&mut scheduler, 1000 + STATUS_SPACING_MS - 1), None);
+        assert_eq!(poll(&mut scheduler, 1000 + STATUS_SPACING_MS), Some(HID_PD_PRESENTSTATUS));
+    }
+
+    #[test]
+    fn flapping_status_does_not_starve_the_other_reports() {
+        let mut scheduler = ReportScheduler::new(KEEP_ALIVE_MS);
+        scheduler.update(Report::RemainingCapacity(80));
+        scheduler.update(Report::RunTimeToEmpty(3600));
+        scheduler.update(Report::Voltage(800));
+        scheduler.update(Report::Current(500));
+        scheduler.update(Report::AverageCurrent(450));
+        let mut first_sent = [None; TRACKED.len()];
+        for (i, now) in (1000..2000).step_by(PERIOD_MS as usize).enumerate() {
+            scheduler.update(status(i as u8 % 2));
+            if let Some(id) = poll(&mut scheduler, now) {
+                let index = TRACKED.iter().position(|&(tracked, _)| tracked == id).unwrap();
+                first_sent[index].get_or_insert(now);
+            }
+        }
+        for (index, sent) in first_sent.iter().enumerate() {
+            let sent = sent.unwrap_or_else(|| panic!("report id {:#04x} starved", TRACKED[index].0));
+            // every report gets its turn within one round over all slots
+            assert!(sent < 1000 + TRACKED.len() as u32 * PERIOD_MS, "report id {:#04x}", TRACKED[index].0);
+        }
+    }
+
+    #[test]
+    fn unchanged_reports_are_repeated_after_the_keep_alive() {
+        let mut scheduler = ReportScheduler::new(KEEP_ALIVE_MS);
+        scheduler.update(Report::RemainingCapacity(80));
+        assert_eq!(poll(&mut scheduler, 1000), Some(HID_PD_REMAININGCAPACITY));
+        scheduler.update(Report::RemainingCapacity(80));
+        assert_eq!(poll(&mut scheduler, 1000 + KEEP_ALIVE_MS - 1), None);
+        assert_eq!(poll(&mut scheduler, 1000 + KEEP_ALIVE_MS), Some(HID_PD_REMAININGCAPACITY));
+    }
+}
@@ -0,0 +1,432 @@
+//! Fixed size byte FIFO between the USB interrupt and the tasks, the line splitting
+//! of the received bytes and the line ring of the event log.
+
+///
+///
+/// fixed size byte FIFO
+///
+#[derive(Clone)]
+pub struct RingBuffer<const N: usize> {
+    bytes: [u8; N],
+    start: usize,
+    len: usize,
+}
+
+impl<const N: usize> Default for RingBuffer<N> {
+    fn default() -> Self {
+        RingBuffer::new()
+    }
+}
+
+impl<const N: usize> RingBuffer<N> {
+    pub const fn new() -> Self {
+        RingBuffer {
+            bytes: [0; N],
+            start: 0,
+            len: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// space left in bytes
+    pub fn free(&self) -> usize {
+        N - self.len
+    }
+
+    /// byte `i` counted from the oldest one
+    pub fn get(&self, i: usize) -> Option<u8> {
+        if i < self.len {
+            Some(self.bytes[(self.start + i) % N])
+        } else {
+            None
+        }
+    }
+
+    /// returns: false if the buffer is full and the byte was not stored
+    pub fn push(&mut self, byte: u8) -> bool {
+        if self.len == N {
+            return false;
+        }
+        self.bytes[(self.start + self.len) % N] = byte;
+        self.len += 1;
+        true
+    }
+
+    pub fn pop(&mut self) -> Option<u8> {
+        let byte = self.get(0)?;
+        self.start = (self.start + 1) % N;
+        self.len -= 1;
+        Some(byte)
+    }
+
+    ///
+    ///
+    /// moves the oldest bytes to `out`
+    ///
+    /// returns: the number of bytes written to `out`
+    ///
+    pub fn read(&mut self, out: &mut [u8]) -> usize {
+        let count = self.len.min(out.len());
+        for byte in out[..count].iter_mut() {
+            // count <= len
+            *byte = self.pop().unwrap_or(0);
+        }
+        count
+    }
+
+    /// drops up to `count` of the oldest bytes
+    pub fn discard(&mut self, count: usize) {
+        let count = count.min(self.len);
+        self.start = (self.start + count) % N;
+        self.len -= count;
+    }
+
+    /// index of the first byte matching `predicate`, counted from the oldest one
+    pub fn position(&self, predicate: impl Fn(u8) -> bool) -> Option<usize> {
+        (0..self.len).find(|&i| predicate(self.bytes[(self.start + i) % N]))
+    }
+
+    pub fn clear(&mut self) {
+        self.start = 0;
+        self.len = 0;
+    }
+}
+
+///
+///
+/// `LineReader::read_line` dropped a line that does not fit into the caller's buffer
+///
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct LineTooLong;
+
+///
+///
+/// splits the bytes of a `RingBuffer` into lines terminated by `\r`, `\n` or `\r\n`
+///
+/// the state survives between calls, so a `\n` that arrives after its `\r` was already
+/// consumed does not end an empty line
+///
+#[derive(Default)]
+pub struct LineReader {
+    /// the last line ended with `\r`, a `\n` right after it belongs to it
+    after_cr: bool,
+    /// the current line did not fit, its bytes are dropped up to the terminator
+    overflow: bool,
+}
+
+impl LineReader {
+    pub const fn new() -> Self {
+        LineReader {
+            after_cr: false,
+            overflow: false,
+        }
+    }
+
+    ///
+    ///
+    /// takes the next complete line out of `rx`, an incomplete line stays in `rx`
+    ///
+    /// returns: the length of the line written to `line` without the terminator, `None` if
+    /// no complete line was buffered yet
+    ///
+    pub fn read_line<const N: usize>(
+        &mut self,
+        rx: &mut RingBuffer<N>,
+        line: &mut [u8],
+    ) -> Option<Result<usize, LineTooLong>> {
+        loop {
+            if self.after_cr {
+                match rx.get(0) {
+                    None => return None,
+                    Some(b'\n') => rx.discard(1),
+                    Some(_) => {}
+                }
+                self.after_cr = false;
+            }
+            let len = match rx.position(|byte| byte == b'\r' || byte == b'\n') {
+                Some(len) => len,
+                None if self.overflow => {
+                    rx.clear();
+                    return None;
+                }
+                // the line can not fit anymore, drop what arrived so far
+                None if rx.len() > line.len() => {
+                    rx.clear();
+                    self.overflow = true;
+                    return Some(Err(LineTooLong));
+                }
+                None => return None,
+            };
+            let overflow = core::mem::replace(&mut self.overflow, false);
+            let too_long = len > line.len();
+            if overflow || too_long {
+                rx.discard(len);
+            } else {
+                rx.read(&mut line[..len]);
+            }
+            self.after_cr = rx.pop() == Some(b'\r');
+            match (overflow, too_long) {
+                // the line was already reported when its start was dropped
+                (true, _) => {}
+                (false, true) => return Some(Err(LineTooLong)),
+                (false, false) => return Some(Ok(len)),
+            }
+        }
+    }
+}
+
+///
+///
+/// ring buffer of log lines, each line is terminated by `\n`, the oldest lines are
+/// dropped when it is full and lines longer than `LINE_LEN` are truncated
+///
+#[derive(Clone)]
+pub struct LogBuffer<const N: usize, const LINE_LEN: usize> {
+    ring: RingBuffer<N>,
+}
+
+impl<const N: usize, const LINE_LEN: usize> Default for LogBuffer<N, LINE_LEN> {
+    fn default() -> Self {
+        LogBuffer::new()
+    }
+}
+
+impl<const N: usize, const LINE_LEN: usize> LogBuffer<N, LINE_LEN> {
+    pub const fn new() -> Self {
+        LogBuffer { ring: RingBuffer::new() }
+    }
+
+    /// drops the oldest line
+    fn drop_line(&mut self) {
+        let len = self.ring.position(|byte| byte == b'\n').map_or(self.ring.len(), |i| i + 1);
+        self.ring.discard(len);
+    }
+
+    pub fn push(&mut self, line: &str) {
+        let line = &line.as_bytes()[..line.len().min(LINE_LEN).min(N - 1)];
+        while self.ring.free() < line.len() + 1 {
+            self.drop_line();
+        }
+        for &byte in line.iter().chain(b"\n") {
+            self.ring.push(byte);
+        }
+    }
+
+    ///
+    ///
+    /// calls `f` with every stored line, oldest first, without the `\n`
+    ///
+    pub fn for_each_line(&self, mut f: impl FnMut(&str)) {
+        let mut line = [0; LINE_LEN];
+        let mut n = 0;
+        for i in 0..self.ring.len() {
+            let byte = self.ring.get(i).unwrap_or(b'\n');
+            if byte == b'\n' {
+                f(core::str::from_utf8(&line[..n]).unwrap_or("?"));
+                n = 0;
+            } else if n < LINE_LEN {
+                line[n] = byte;
+                n += 1;
+            }
+        }
+    }
+
+    /// number of stored bytes, lines included with their `\n`
+    pub fn len(&self) -> usize {
+        self.ring.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ring.is_empty()
+    }
+
+    ///
+    ///
+    /// copies the stored bytes starting `offset` bytes after the oldest one
+    ///
+    /// returns: the number of bytes written to `out`
+    ///
+    pub fn read(&self, offset: usize, out: &mut [u8]) -> usize {
+        let count = self.ring.len().saturating_sub(offset).min(out.len());
+        for (i, byte) in out[..count].iter_mut().enumerate() {
+            *byte = self.ring.get(offset + i).unwrap_or(0);
+        }
+        count
+    }
+
+    pub fn clear(&mut self) {
+        self.ring.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use std::string::String;
+    use std::vec::Vec;
+
+    fn filled<const N: usize>(bytes: &[u8]) -> RingBuffer<N> {
+        let mut rx = RingBuffer::new();
+        for &byte in bytes {
+            assert!(rx.push(byte));
+        }
+        rx
+    }
+
+    #[test]
+    fn fifo_wraps_around() {
+        let mut rx = filled::<4>(b"abc");
+        assert_eq!(rx.pop(), Some(b'a'));
+        assert!(rx.push(b'd'));
+        assert!(rx.push(b'e'));
+        assert!(!rx.push(b'f'));
+        assert_eq!(rx.free(), 0);
+        assert_eq!(rx.position(|byte| byte == b'e'), Some(3));
+        let mut out = [0; 8];
+        assert_eq!(rx.read(&mut out), 4);
+        assert_eq!(&out[..4], b"bcde");
+        assert!(rx.is_empty());
+        assert_eq!(rx.pop(), None);
+    }
+
+    #[test]
+    fn discard_stops_at_len() {
+        let mut rx = filled::<4>(b"ab");
+        rx.discard(5);
+        assert!(rx.is_empty());
+        assert!(rx.push(b'x'));
+        assert_eq!(rx.get(0), Some(b'x'));
+        assert_eq!(rx.get(1), None);
+    }
+
+    #[test]
+    fn reads_lines_with_every_terminator() {
+        let mut rx = filled::<32>(b"Q1\rF\nI\r\nT");
+        let mut reader = LineReader::new();
+        let mut line = [0; 8];
+        assert_eq!(reader.read_line(&mut rx, &mut line), Some(Ok(2)));
+        assert_eq!(&line[..2], b"Q1");
+        assert_eq!(reader.read_line(&mut rx, &mut line), Some(Ok(1)));
+        assert_eq!(&line[..1], b"F");
+        assert_eq!(reader.read_line(&mut rx, &mut line), Some(Ok(1)));
+        assert_eq!(&line[..1], b"I");
+        // incomplete, stays buffered
+        assert_eq!(reader.read_line(&mut rx, &mut line), None);
+        assert_eq!(rx.len(), 1);
+    }
+
+    #[test]
+    fn late_line_feed_belongs_to_the_carriage_return() {
+        let mut rx = filled::<32>(b"Q1\r");
+        let mut reader = LineReader::new();
+        let mut line = [0; 8];
+        assert_eq!(reader.read_line(&mut rx, &mut line), Some(Ok(2)));
+        assert_eq!(reader.read_line(&mut rx, &mut line), None);
+        for &byte in b"\nF\r" {
+            rx.push(byte);
+        }
+        assert_eq!(reader.read_line(&mut rx, &mut line), Some(Ok(1)));
+        assert_eq!(&line[..1], b"F");
+    }
+
+    #[test]
+    fn empty_lines_are_kept() {
+        let mut rx = filled::<32>(b"\n\n\r\r");
+        let mut reader = LineReader::new();
+        let mut line = [0; 8];
+        for _ in 0..4 {
+            assert_eq!(reader.read_line(&mut rx, &mut line), Some(Ok(0)));
+        }
+        assert_eq!(reader.read_line(&mut rx, &mut line), None);
+    }
+
+    #[test]
+    fn overlong_line_is_reported_once() {
+        let mut reader = LineReader::new();
+        let mut line = [0; 4];
+
+        let mut rx = filled::<32>(b"abcdef\r\nQ1\r");
+        assert_eq!(reader.read_line(&mut rx, &mut line), Some(Err(LineTooLong)));
+        assert_eq!(reader.read_line(&mut rx, &mut line), Some(Ok(2)));
+        assert_eq!(&line[..2], b"Q1");
+
+        // the start is dropped before its terminator arrives
+        let mut rx = filled::<32>(b"abcde");
+        assert_eq!(reader.read_line(&mut rx, &mut line), Some(Err(LineTooLong)));
+        assert!(rx.is_empty());
+        for &byte in b"fgh\r\nQ1\r" {
+            rx.push(byte);
+        }
+        assert_eq!(reader.read_line(&mut rx, &mut line), Some(Ok(2)));
+        assert_eq!(&line[..2], b"Q1");
+        assert_eq!(reader.read_line(&mut rx, &mut line), None);
+    }
+
+    fn lines<const N: usize, const LINE_LEN: usize>(log: &LogBuffer<N, LINE_LEN>) -> Vec<String> {
+        let mut lines = Vec::new();
+        log.for_each_line(|line| lines.push(line.into()));
+        lines
+    }
+
+    #[test]
+    fn log_drops_the_oldest_lines_when_it_wraps_around() {
+        let mut log = LogBuffer::<16, 8>::new();
+        log.push("first");
+        log.push("second");
+        assert_eq!(lines(&log), ["first", "second"]);
+        // 6 + 7 + 6 bytes do not fit into 16
+        log.push("third");
+        assert_eq!(lines(&log), ["second", "third"]);
+        log.push("fourth");
+        assert_eq!(lines(&log), ["third", "fourth"]);
+        assert_eq!(log.len(), 13);
+    }
+
+    #[test]
+    fn log_truncates_long_lines() {
+        let mut log = LogBuffer::<16, 4>::new();
+        log.push("abcdefgh");
+        assert_eq!(lines(&log), ["abcd"]);
+        assert_eq!(log.len(), 5);
+    }
+
+    #[test]
+    fn log_reads_from_an_offset() {
+        let mut log = LogBuffer::<16, 8>::new();
+        log.push("first");
+        log.push("second");
+        log.push("third");
+        let mut out = [0; 4];
+        assert_eq!(log.read(0, &mut out), 4);
+        assert_eq!(&out, b"seco");
+        assert_eq!(log.read(4, &mut out), 4);
+        assert_eq!(&out, b"nd\nt");
+        assert_eq!(log.read(11, &mut out), 2);
+        assert_eq!(&out[..2], b"d\n");
+        assert_eq!(log.read(13, &mut out), 0);
+        assert_eq!(log.read(100, &mut out), 0);
+    }
+
+    #[test]
+    fn log_clear_drops_everything() {
+        let mut log = LogBuffer::<16, 8>::new();
+        log.push("first");
+        log.push("second");
+        log.push("third");
+        log.clear();
+        assert!(log.is_empty());
+        assert!(lines(&log).is_empty());
+        assert_eq!(log.read(0, &mut [0; 4]), 0);
+        log.push("again");
+        assert_eq!(lines(&log), ["again"]);
+    }
+}
@@ -0,0 +1,560 @@
+//! Line editing command shell for the CDC console.
+//!
+//! Only depends on `core`, the firmware is reached through `ShellContext`, so the shell
+//! can be fed with canned byte streams on the host.
+
+use core::fmt::{self, Write};
+
+pub const LINE_LEN: usize = 64;
+pub const PROMPT: &str = "> ";
+
+const BACKSPACE: u8 = 0x08;
+const DELETE: u8 = 0x7F;
+const CTRL_C: u8 = 0x03;
+const CTRL_U: u8 = 0x15;
+const ESCAPE: u8 = 0x1B;
+
+///
+///
+/// what the shell needs from the firmware
+///
+pub trait ShellContext {
+    /// prints the measurements and the state of the UPS
+    fn status(&mut self, out: &mut dyn Write) -> fmt::Result;
+    /// names of the configuration keys
+    fn config_keys(&self) -> &'static [&'static str];
+    /// prints the value of a configuration key
+    fn config_get(&self, key: &str, out: &mut dyn Write) -> Result<(), ShellError>;
+    /// changes a configuration key, the change is stored in flash by the firmware
+    fn config_set(&mut self, key: &str, value: &str) -> Result<(), ShellError>;
+    /// prints the event log, oldest line first
+    fn log_dump(&mut self, out: &mut dyn Write) -> fmt::Result;
+    fn log_clear(&mut self);
+    /// prints the default log level and the level of every module
+    fn log_levels(&self, out: &mut dyn Write) -> fmt::Result;
+    /// sets the level of `module`, or the default level if `module` is `None`,
+    /// "default" hands a module back to the default level
+    fn set_log_level(&mut self, module: Option<&str>, level: &str) -> Result<(), ShellError>;
+    /// copies log messages to the console
+    fn set_log_cdc(&mut self, on: bool);
+    /// resets the MCU, does not return on the target
+    fn reset(&mut self);
+    /// prints the calibration and the raw and calibrated reading of every channel
+    fn calibration(&mut self, out: &mut dyn Write) -> fmt::Result;
+    /// calibrates a measurement channel against a reference meter
+    fn calibrate(&mut self, channel: &str, step: CalibrationStep) -> Result<(), ShellError>;
+}
+
+///
+///
+/// one step of calibrating a channel, references are what the meter reads right now
+///
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum CalibrationStep {
+    /// corrects the gain only
+    Single(f32),
+    /// remembers the first point of a two point calibration
+    Low(f32),
+    /// completes the two point calibration started with `Low`, sets gain and offset
+    High(f32),
+    /// back to the nominal conversion
+    Reset,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ShellError {
+    UnknownCommand,
+    MissingArgument,
+    UnknownKey,
+    InvalidValue,
+    Failed(&'static str),
+}
+
+impl ShellError {
+    pub fn message(&self) -> &'static str {
+        match self {
+            ShellError::UnknownCommand => "unknown command, try 'help'",
+            ShellError::MissingArgument => "missing argument, try 'help'",
+            ShellError::UnknownKey => "unknown key, try 'config'",
+            ShellError::InvalidValue => "invalid value",
+            ShellError::Failed(message) => message,
+        }
+    }
+}
+
+const HELP: &str = "\
+help                          this text\r\n\
+status                        measurements and state\r\n\
+config                        all configuration values\r\n\
+config get <key>              one configuration value\r\n\
+config set <key> <value>      change a configuration value\r\n\
+log                           dump the event log\r\n\
+log clear                     clear the event log\r\n\
+log level                     show the log levels\r\n\
+log level [<module>] <level>  set the default or a module's level (error..trace)\r\n\
+log cdc on|off                copy log messages to this console\r\n\
+calibrate                     show the calibration of vbat, vin and current\r\n\
+calibrate <channel> <value>   correct a channel's gain against a reference reading\r\n\
+calibrate <channel> low <v>   first point of a two point calibration\r\n\
+calibrate <channel> high <v>  second point, sets gain and offset\r\n\
+calibrate <channel> reset     drop a channel's calibration\r\n\
+reset                         restart the UPS\r\n";
+
+#[derive(Clone, Copy, PartialEq)]
+enum Escape {
+    None,
+    /// ESC received
+    Start,
+    /// ESC [ received, waiting for the final byte
+    Csi,
+}
+
+///
+///
+/// assembles lines from the received bytes, echoes them and runs the commands
+///
+/// supports backspace, Ctrl-C (discard line), Ctrl-U (clear line) and the up
+/// arrow to recall the previous line, other escape sequences are ignored
+///
+pub struct Shell {
+    line: [u8; LINE_LEN],
+    len: usize,
+    previous: [u8; LINE_LEN],
+    previous_len: usize,
+    escape: Escape,
+    /// the last byte was a CR, so a following LF does not end another line
+    after_cr: bool,
+}
+
+impl Shell {
+    pub const fn new() -> Self {
+        Shell {
+            line: [0; LINE_LEN],
+            len: 0,
+            previous: [0; LINE_LEN],
+            previous_len: 0,
+            escape: Escape::None,
+            after_cr: false,
+        }
+    }
+
+    pub fn prompt(&self, out: &mut dyn Write) -> fmt::Result {
+        out.write_str(PROMPT)
+    }
+
+    ///
+    ///
+    /// processes received bytes, the echo and the output of finished commands go to `out`
+    ///
+    pub fn input(&mut self, bytes: &[u8], context: &mut dyn ShellContext, out: &mut dyn Write) -> fmt::Result {
+        for &byte in bytes {
+            self.byte(byte, context, out)?;
+        }
+        Ok(())
+    }
+
+    fn byte(&mut self, byte: u8, context: &mut dyn ShellContext, out: &mut dyn Write) -> fmt::Result {
+        let after_cr = core::mem::replace(&mut self.after_cr, byte == b'\r');
+        match self.escape {
+            Escape::Start => {
+                self.escape = if byte == b'[' { Escape::Csi } else { Escape::None };
+                return Ok(());
+            }
+            Escape::Csi => {
+                // parameter bytes until the final byte in 0x40..=0x7E
+                if (0x40..=0x7E).contains(&byte) {
+                    self.escape = Escape::None;
+                    if byte == b'A' {
+                        self.recall(out)?;
+                    }
+                }
+                return Ok(());
+            }
+            Escape::None => {}
+        }
+
+        match byte {
+            b'\n' if after_cr => Ok(()),
+            b'\r' | b'\n' => {
+                out.write_str("\r\n")?;
+                let len = self.len;
+                self.len = 0;
+                if len > 0 {
+                    self.previous = self.line;
+                    self.previous_len = len;
+                    let line = self.line;
+                    // only printable ASCII is stored
+                    let line = core::str::from_utf8(&line[..len]).unwrap_or("");
+                    if let Err(error) = execute(line, context, out) {
+                        write!(out, "error: {}\r\n", error.message())?;
+                    }
+                }
+                self.prompt(out)
+            }
+            BACKSPACE | DELETE => {
+                if self.len > 0 {
+                    self.len -= 1;
+                    out.write_str("\x08 \x08")?;
+                }
+                Ok(())
+            }
+            CTRL_C => {
+                self.len = 0;
+                out.write_str("^C\r\n")?;
+                self.prompt(out)
+            }
+            CTRL_U => self.clear(out),
+            ESCAPE => {
+                self.escape = Escape::Start;
+                Ok(())
+            }
+            0x20..=0x7E if self.len < LINE_LEN => {
+                self.line[self.len] = byte;
+                self.len += 1;
+                out.write_char(byte as char)
+            }
+            // control characters and bytes beyond the end of the line are dropped
+            _ => Ok(()),
+        }
+    }
+
+    fn clear(&mut self, out: &mut dyn Write) -> fmt::Result {
+        for _ in 0..self.len {
+            out.write_str("\x08 \x08")?;
+        }
+        self.len = 0;
+        Ok(())
+    }
+
+    fn recall(&mut self, out: &mut dyn Write) -> fmt::Result {
+        self.clear(out)?;
+        self.line = self.previous;
+        self.len = self.previous_len;
+        out.write_str(core::str::from_utf8(&self.line[..self.len]).unwrap_or(""))
+    }
+}
+
+impl Default for Shell {
+    fn default() -> Self {
+        Shell::new()
+    }
+}
+
+///
+///
+/// runs one command line
+///
+pub fn execute(line: &str, context: &mut dyn ShellContext, out: &mut dyn Write) -> Result<(), ShellError> {
+    let mut words = line.split_whitespace();
+    let write_failed = |_| ShellError::Failed("output failed");
+    match (words.next(), words.next()) {
+        (None, _) => Ok(()),
+        (Some("help"), None) => out.write_str(HELP).map_err(write_failed),
+        (Some("status"), None) => context.status(out).map_err(write_failed),
+        (Some("config"), None) => {
+            for key in context.config_keys() {
+                write!(out, "{} = ", key).map_err(write_failed)?;
+                context.config_get(key, out)?;
+                out.write_str("\r\n").map_err(write_failed)?;
+            }
+            Ok(())
+        }
+        (Some("config"), Some("get")) => {
+            let key = words.next().ok_or(ShellError::MissingArgument)?;
+            context.config_get(key, out)?;
+            out.write_str("\r\n").map_err(write_failed)
+        }
+        (Some("config"), Some("set")) => {
+            let key = words.next().ok_or(ShellError::MissingArgument)?;
+            let value = words.next().ok_or(ShellError::MissingArgument)?;
+            context.config_set(key, value)?;
+            out.write_str("ok\r\n").map_err(write_failed)
+        }
+        (Some("log"), None) => context.log_dump(out).map_err(write_failed),
+        (Some("log"), Some("clear")) => {
+            context.log_clear();
+            out.write_str("ok\r\n").map_err(write_failed)
+        }
+        (Some("log"), Some("level")) => match (words.next(), words.next()) {
+            (None, _) => context.log_levels(out).map_err(write_failed),
+            (Some(level), None) => {
+                context.set_log_level(None, level)?;
+                out.write_str("ok\r\n").map_err(write_failed)
+            }
+            (Some(module), Some(level)) => {
+                context.set_log_level(Some(module), level)?;
+                out.write_str("ok\r\n").map_err(write_failed)
+            }
+        },
+        (Some("log"), Some("cdc")) => {
+            match words.next() {
+                Some("on") => context.set_log_cdc(true),
+                Some("off") => context.set_log_cdc(false),
+                Some(_) => return Err(ShellError::InvalidValue),
+                None => return Err(ShellError::MissingArgument),
+            }
+            out.write_str("ok\r\n").map_err(write_failed)
+        }
+        (Some("calibrate"), Some(channel)) => {
+            fn reference(word: Option<&str>) -> Result<f32, ShellError> {
+                word.ok_or(ShellError::MissingArgument)?
+                    .parse::<f32>()
+                    .map_err(|_| ShellError::InvalidValue)
+            }
+            let step = match words.next() {
+                Some("low") => CalibrationStep::Low(reference(words.next())?),
+                Some("high") => CalibrationStep::High(reference(words.next())?),
+                Some("reset") => CalibrationStep::Reset,
+                word => CalibrationStep::Single(reference(word)?),
+            };
+            context.calibrate(channel, step)?;
+            out.write_str("ok\r\n").map_err(write_failed)
+        }
+        (Some("calibrate"), None) => context.calibration(out).map_err(write_failed),
+        (Some("reset"), None) => {
+            out.write_str("resetting\r\n").map_err(write_failed)?;
+            context.reset();
+            Ok(())
+        }
+        _ => Err(ShellError::UnknownCommand),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use std::string::String;
+    use std::vec::Vec;
+
+    /// records every call, `config_get` knows the single key "limit"
+    #[derive(Default)]
+    struct Recorder {
+        calls: Vec<String>,
+        limit: String,
+    }
+
+    impl ShellContext for Recorder {
+        fn status(&mut self, out: &mut dyn Write) -> fmt::Result {
+            self.calls.push("status".into());
+            out.write_str("vbat 7.40 V\r\n")
+        }
+
+        fn config_keys(&self) -> &'static [&'static str] {
+            &["limit"]
+        }
+
+        fn config_get(&self, key: &str, out: &mut dyn Write) -> Result<(), ShellError> {
+            match key {
+                "limit" => out.write_str(&self.limit).map_err(|_| ShellError::Failed("output failed")),
+                _ => Err(ShellError::UnknownKey),
+            }
+        }
+
+        fn config_set(&mut self, key: &str, value: &str) -> Result<(), ShellError> {
+            match key {
+                "limit" if value.parse::<u8>().is_ok() => {
+                    self.limit = value.into();
+                    Ok(())
+                }
+                "limit" => Err(ShellError::InvalidValue),
+                _ => Err(ShellError::UnknownKey),
+            }
+        }
+
+        fn log_dump(&mut self, out: &mut dyn Write) -> fmt::Result {
+            self.calls.push("log_dump".into());
+            out.write_str("[I] boot\r\n")
+        }
+
+        fn log_clear(&mut self) {
+            self.calls.push("log_clear".into());
+        }
+
+        fn log_levels(&self, out: &mut dyn Write) -> fmt::Result {
+            out.write_str("default info\r\n")
+        }
+
+        fn set_log_level(&mut self, module: Option<&str>, level: &str) -> Result<(), ShellError> {
+            self.calls.push(std::format!("set_log_level {:?} {}", module, level));
+            Ok(())
+        }
+
+        fn set_log_cdc(&mut self, on: bool) {
+            self.calls.push(std::format!("set_log_cdc {}", on));
+        }
+
+        fn reset(&mut self) {
+            self.calls.push("reset".into());
+        }
+
+        fn calibration(&mut self, out: &mut dyn Write) -> fmt::Result {
+            self.calls.push("calibration".into());
+            out.write_str("vbat gain 1.000\r\n")
+        }
+
+        fn calibrate(&mut self, channel: &str, step: CalibrationStep) -> Result<(), ShellError> {
+            if channel != "vbat" {
+                return Err(ShellError::UnknownKey);
+            }
+            self.calls.push(std::format!("calibrate {:?}", step));
+            Ok(())
+        }
+    }
+
+    fn feed(shell: &mut Shell, context: &mut Recorder, bytes: &[u8]) -> String {
+        let mut out = String::new();
+        shell.input(bytes, context, &mut out).unwrap();
+        out
+    }
+
+    /// runs one line in a fresh shell, returns the output after the echo
+    fn run(line: &str) -> (Recorder, String) {
+        let mut context = Recorder::default();
+        let mut shell = Shell::new();
+        let mut bytes = Vec::from(line.as_bytes());
+        bytes.push(b'\r');
+        let out = feed(&mut shell, &mut context, &bytes);
+        let echo = std::format!("{}\r\n", line);
+        assert!(out.starts_with(&echo), "{:?}", out);
+        assert!(out.ends_with(PROMPT), "{:?}", out);
+        let output = String::from(&out[echo.len()..out.len() - PROMPT.len()]);
+        (context, output)
+    }
+
+    #[test]
+    fn echoes_and_runs_a_line() {
+        let mut context = Recorder::default();
+        let mut shell = Shell::new();
+        assert_eq!(feed(&mut shell, &mut context, b"stat"), "stat");
+        assert!(context.calls.is_empty());
+        assert_eq!(feed(&mut shell, &mut context, b"us\r"), "us\r\nvbat 7.40 V\r\n> ");
+        assert_eq!(context.calls, ["status"]);
+    }
+
+    #[test]
+    fn crlf_and_lf_end_one_line_each() {
+        let mut context = Recorder::default();
+        let mut shell = Shell::new();
+        let out = feed(&mut shell, &mut context, b"status\r\nstatus\n\r\n");
+        assert_eq!(context.calls, ["status", "status"]);
+        // the CR after the second LF ends an empty line, its LF is swallowed
+        assert_eq!(out.matches(PROMPT).count(), 3);
+    }
+
+    #[test]
+    fn empty_line_only_prompts() {
+        let mut context = Recorder::default();
+        let mut shell = Shell::new();
+        assert_eq!(feed(&mut shell, &mut context, b"\r"), "\r\n> ");
+        assert!(context.calls.is_empty());
+    }
+
+    #[test]
+    fn backspace_and_delete_remove_characters() {
+        let mut context = Recorder::default();
+        let mut shell = Shell::new();
+        let out = feed(&mut shell, &mut context, b"statx\x08us\x7F\x7Fus\r");
+        assert!(out.starts_with("statx\x08 \x08us\x08 \x08\x08 \x08us\r\n"), "{:?}", out);
+        assert_eq!(context.calls, ["status"]);
+        // backspace on an empty line echoes nothing
+        assert_eq!(feed(&mut shell, &mut context, b"\x08"), "");
+    }
+
+    #[test]
+    fn ctrl_c_and_ctrl_u_discard_the_line() {
+        let mut context = Recorder::default();
+        let mut shell = Shell::new();
+        assert_eq!(feed(&mut shell, &mut context, b"reset\x03"), "reset^C\r\n> ");
+        assert_eq!(feed(&mut shell, &mut context, b"log\x15"), "log\x08 \x08\x08 \x08\x08 \x08");
+        feed(&mut shell, &mut context, b"\r");
+        assert!(context.calls.is_empty());
+    }
+
+    #[test]
+    fn up_arrow_recalls_the_previous_line() {
+        let mut context = Recorder::default();
+        let mut shell = Shell::new();
+        feed(&mut shell, &mut context, b"status\r");
+        assert_eq!(feed(&mut shell, &mut context, b"x\x1B[A"), "x\x08 \x08status");
+        feed(&mut shell, &mut context, b"\r");
+        assert_eq!(context.calls, ["status", "status"]);
+        // other escape sequences are ignored
+        assert_eq!(feed(&mut shell, &mut context, b"\x1B[1;5C\x1BO"), "");
+    }
+
+    #[test]
+    fn overlong_line_is_truncated() {
+        let mut context = Recorder::default();
+        let mut shell = Shell::new();
+        let mut line = Vec::from(&b"status "[..]);
+        line.resize(LINE_LEN + 10, b'x');
+        line.push(b'\r');
+        let out = feed(&mut shell, &mut context, &line);
+        assert_eq!(out.find('\r'), Some(LINE_LEN));
+        // the stored part runs as usual, the surplus bytes are not echoed
+        assert!(out.contains("error: unknown command"), "{:?}", out);
+        assert!(context.calls.is_empty());
+    }
+
+    #[test]
+    fn help_lists_every_command() {
+        let (_, out) = run("help");
+        assert_eq!(out, HELP);
+    }
+
+    #[test]
+    fn config_commands() {
+        let (_, out) = run("config");
+        assert_eq!(out, "limit = \r\n");
+        let mut context = Recorder::default();
+        let mut shell = Shell::new();
+        assert!(feed(&mut shell, &mut context, b"config set limit 20\r").contains("ok\r\n"));
+        assert!(feed(&mut shell, &mut context, b"config get limit\r").contains("\r\n20\r\n"));
+        assert!(feed(&mut shell, &mut context, b"config set limit x\r").contains("error: invalid value"));
+        assert!(feed(&mut shell, &mut context, b"config get other\r").contains("error: unknown key"));
+        assert!(feed(&mut shell, &mut context, b"config set limit\r").contains("error: missing argument"));
+    }
+
+    #[test]
+    fn log_commands() {
+        let (context, out) = run("log");
+        assert_eq!(context.calls, ["log_dump"]);
+        assert_eq!(out, "[I] boot\r\n");
+        assert_eq!(run("log clear").0.calls, ["log_clear"]);
+        assert_eq!(run("log level").1, "default info\r\n");
+        assert_eq!(run("log level debug").0.calls, ["set_log_level None debug"]);
+        assert_eq!(run("log level adc trace").0.calls, ["set_log_level Some(\"adc\") trace"]);
+        assert_eq!(run("log cdc on").0.calls, ["set_log_cdc true"]);
+        assert_eq!(run("log cdc off").0.calls, ["set_log_cdc false"]);
+        assert_eq!(run("log cdc maybe").1, "error: invalid value\r\n");
+        assert_eq!(run("log cdc").1, "error: missing argument, try 'help'\r\n");
+    }
+
+    #[test]
+    fn calibrate_commands() {
+        assert_eq!(run("calibrate").0.calls, ["calibration"]);
+        assert_eq!(run("calibrate vbat 7.5").0.calls, ["calibrate Single(7.5)"]);
+        assert_eq!(run("calibrate vbat low 6").0.calls, ["calibrate Low(6.0)"]);
+        assert_eq!(run("calibrate vbat high 8.4").0.calls, ["calibrate High(8.4)"]);
+        assert_eq!(run("calibrate vbat reset").0.calls, ["calibrate Reset"]);
+        assert_eq!(run("calibrate vbat").1, "error: missing argument, try 'help'\r\n");
+        assert_eq!(run("calibrate vbat high").1, "error: missing argument, try 'help'\r\n");
+        assert_eq!(run("calibrate vbat volts").1, "error: invalid value\r\n");
+        assert_eq!(run("calibrate vin 12").1, "error: unknown key, try 'config'\r\n");
+    }
+
+    #[test]
+    fn reset_command() {
+        let (context, out) = run("reset");
+        assert_eq!(out, "resetting\r\n");
+        assert_eq!(context.calls, ["reset"]);
+    }
+
+    #[test]
+    fn unknown_commands() {
+        assert_eq!(run("reboot").1, "error: unknown command, try 'help'\r\n");
+        assert_eq!(run("status now").1, "error: unknown command, try 'help'\r\n");
+    }
+}
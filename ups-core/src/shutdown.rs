@@ -0,0 +1,326 @@
+//! Output switching behind the DelayBeforeShutdown / DelayBeforeReboot reports.
+//!
+//! Pure state machine, the firmware ticks it from the output task and drives the load
+//! switch with the result.
+
+/// minimum time the output stays disconnected before it is switched on again
+pub const MIN_OFF_TIME_MS: u32 = 10_000;
+
+///
+///
+/// DelayBeforeShutdown / DelayBeforeReboot countdowns
+///
+/// when the shutdown countdown expires the output is cut and restored once mains
+/// is present again, when the reboot countdown expires the output is cut and
+/// restored after `MIN_OFF_TIME_MS` regardless of mains
+///
+pub struct OutputControl {
+    shutdown_ms: Option<u32>,
+    reboot_ms: Option<u32>,
+    off_ms: Option<u32>,
+    /// time the output stays off before it may be restored
+    min_off_ms: u32,
+    power_cycle: bool,
+    /// the running shutdown countdown was started by `start_autonomous_shutdown`
+    autonomous: bool,
+}
+
+impl Default for OutputControl {
+    fn default() -> Self {
+        OutputControl::new()
+    }
+}
+
+impl OutputControl {
+    pub const fn new() -> Self {
+        OutputControl {
+            shutdown_ms: None,
+            reboot_ms: None,
+            off_ms: None,
+            min_off_ms: MIN_OFF_TIME_MS,
+            power_cycle: false,
+            autonomous: false,
+        }
+    }
+
+    ///
+    ///
+    /// starts the shutdown countdown, a negative value aborts it
+    ///
+    /// replaces a pending `set_shutdown_with_restore`, including its restore time
+    ///
+    pub fn set_shutdown_delay(&mut self, seconds: i16) {
+        self.shutdown_ms = delay_to_ms(seconds);
+        self.min_off_ms = MIN_OFF_TIME_MS;
+        self.autonomous = false;
+    }
+
+    /// starts the reboot countdown, a negative value aborts it
+    pub fn set_reboot_delay(&mut self, seconds: i16) {
+        self.reboot_ms = delay_to_ms(seconds);
+    }
+
+    ///
+    ///
+    /// starts the shutdown countdown, once mains is present the output is restored
+    /// not before `restore_after_s` seconds after the cut (at least `MIN_OFF_TIME_MS`)
+    ///
+    pub fn set_shutdown_with_restore(&mut self, seconds: u16, restore_after_s: u32) {
+        self.shutdown_ms = Some(seconds as u32 * 1000);
+        self.min_off_ms = MIN_OFF_TIME_MS.max(restore_after_s.saturating_mul(1000));
+        self.autonomous = false;
+    }
+
+    ///
+    ///
+    /// starts the shutdown countdown on behalf of `AutonomousShutdown`, a countdown the host
+    /// started is left alone
+    ///
+    /// returns: false if another shutdown countdown is already running
+    ///
+    pub fn start_autonomous_shutdown(&mut self, seconds: u16) -> bool {
+        if self.shutdown_ms.is_some() {
+            return false;
+        }
+        self.shutdown_ms = Some(seconds as u32 * 1000);
+        self.autonomous = true;
+        true
+    }
+
+    ///
+    ///
+    /// aborts the shutdown countdown if it is still the one `start_autonomous_shutdown`
+    /// started, a countdown the host set since then keeps running
+    ///
+    /// returns: true if a countdown was aborted
+    ///
+    pub fn abort_autonomous_shutdown(&mut self) -> bool {
+        if !self.autonomous || self.shutdown_ms.is_none() {
+            return false;
+        }
+        self.shutdown_ms = None;
+        self.autonomous = false;
+        true
+    }
+
+    /// aborts the countdowns and switches the output on again if it was cut
+    pub fn cancel(&mut self) {
+        self.shutdown_ms = None;
+        self.reboot_ms = None;
+        self.off_ms = None;
+        self.min_off_ms = MIN_OFF_TIME_MS;
+        self.power_cycle = false;
+        self.autonomous = false;
+    }
+
+    /// remaining seconds until shutdown, -1 if no countdown is running
+    pub fn shutdown_delay(&self) -> i16 {
+        ms_to_delay(self.shutdown_ms)
+    }
+
+    /// remaining seconds until reboot, -1 if no countdown is running
+    pub fn reboot_delay(&self) -> i16 {
+        ms_to_delay(self.reboot_ms)
+    }
+
+    pub fn output_enabled(&self) -> bool {
+        self.off_ms.is_none()
+    }
+
+    ///
+    ///
+    /// advances the countdowns
+    ///
+    /// returns: true if the output should be enabled
+    ///
+    pub fn tick(&mut self, elapsed_ms: u32, mains_present: bool) -> bool {
+        if let Some(off_ms) = self.off_ms {
+            let off_ms = off_ms.saturating_add(elapsed_ms);
+            if off_ms >= self.min_off_ms && (mains_present || self.power_cycle) {
+                self.off_ms = None;
+                self.min_off_ms = MIN_OFF_TIME_MS;
+                self.power_cycle = false;
+            } else {
+                self.off_ms = Some(off_ms);
+            }
+        }
+
+        if let Some(remaining) = self.shutdown_ms {
+            if remaining <= elapsed_ms {
+                self.shutdown_ms = None;
+                self.autonomous = false;
+                self.cut(false);
+            } else {
+                self.shutdown_ms = Some(remaining - elapsed_ms);
+            }
+        }
+
+        if let Some(remaining) = self.reboot_ms {
+            if remaining <= elapsed_ms {
+                self.reboot_ms = None;
+                self.cut(true);
+            } else {
+                self.reboot_ms = Some(remaining - elapsed_ms);
+            }
+        }
+
+        self.output_enabled()
+    }
+
+    fn cut(&mut self, power_cycle: bool) {
+        if self.off_ms.is_none() {
+            self.off_ms = Some(0);
+        }
+        self.power_cycle |= power_cycle;
+    }
+}
+
+fn delay_to_ms(seconds: i16) -> Option<u32> {
+    if seconds < 0 {
+        None
+    } else {
+        Some(seconds as u32 * 1000)
+    }
+}
+
+fn ms_to_delay(ms: Option<u32>) -> i16 {
+    match ms {
+        None => -1,
+        Some(ms) => ((ms + 999) / 1000) as i16,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn idle_keeps_the_output_on() {
+        let mut output = OutputControl::new();
+        assert!(output.tick(60_000, false));
+        assert_eq!(output.shutdown_delay(), -1);
+        assert_eq!(output.reboot_delay(), -1);
+    }
+
+    #[test]
+    fn shutdown_cuts_until_mains_returns() {
+        let mut output = OutputControl::new();
+        output.set_shutdown_delay(2);
+        assert!(output.tick(1000, false));
+        assert_eq!(output.shutdown_delay(), 1);
+        assert!(!output.tick(1000, false));
+        assert_eq!(output.shutdown_delay(), -1);
+        // on battery the output stays off
+        assert!(!output.tick(MIN_OFF_TIME_MS, false));
+        assert!(output.tick(100, true));
+    }
+
+    #[test]
+    fn output_stays_off_for_the_minimum_time() {
+        let mut output = OutputControl::new();
+        output.set_shutdown_delay(0);
+        // the cut happens in this tick, the off time starts with the next one
+        assert!(!output.tick(100, true));
+        assert!(!output.tick(MIN_OFF_TIME_MS - 1, true));
+        assert!(output.tick(1, true));
+    }
+
+    #[test]
+    fn remaining_seconds_round_up() {
+        let mut output = OutputControl::new();
+        output.set_reboot_delay(3);
+        output.tick(100, true);
+        assert_eq!(output.reboot_delay(), 3);
+        output.tick(1900, true);
+        assert_eq!(output.reboot_delay(), 1);
+    }
+
+    #[test]
+    fn negative_delay_aborts_the_countdown() {
+        let mut output = OutputControl::new();
+        output.set_shutdown_delay(5);
+        output.set_shutdown_delay(-1);
+        assert!(output.tick(10_000, false));
+        assert_eq!(output.shutdown_delay(), -1);
+    }
+
+    #[test]
+    fn reboot_restores_without_mains() {
+        let mut output = OutputControl::new();
+        output.set_reboot_delay(1);
+        assert!(!output.tick(1000, false));
+        assert!(!output.tick(MIN_OFF_TIME_MS - 1, false));
+        assert!(output.tick(1, false));
+    }
+
+    #[test]
+    fn restore_time_extends_the_off_time() {
+        let mut output = OutputControl::new();
+        output.set_shutdown_with_restore(0, 60);
+        assert!(!output.tick(0, true));
+        assert!(!output.tick(59_999, true));
+        assert!(output.tick(1, true));
+        // the next cut uses the minimum again
+        output.set_shutdown_delay(0);
+        assert!(!output.tick(0, true));
+        assert!(output.tick(MIN_OFF_TIME_MS, true));
+    }
+
+    #[test]
+    fn plain_delay_overrides_a_shutdown_with_restore() {
+        let mut output = OutputControl::new();
+        output.set_shutdown_with_restore(30, 600);
+        output.set_shutdown_delay(0);
+        assert!(!output.tick(0, true));
+        assert!(output.tick(MIN_OFF_TIME_MS, true));
+    }
+
+    #[test]
+    fn autonomous_shutdown_leaves_the_host_countdown_alone() {
+        let mut output = OutputControl::new();
+        output.set_shutdown_delay(30);
+        assert!(!output.start_autonomous_shutdown(60));
+        assert_eq!(output.shutdown_delay(), 30);
+        assert!(!output.abort_autonomous_shutdown());
+        assert_eq!(output.shutdown_delay(), 30);
+    }
+
+    #[test]
+    fn autonomous_abort_only_cancels_its_own_countdown() {
+        let mut output = OutputControl::new();
+        assert!(output.start_autonomous_shutdown(60));
+        assert_eq!(output.shutdown_delay(), 60);
+        // the host takes over with its own delay
+        output.set_shutdown_delay(20);
+        assert!(!output.abort_autonomous_shutdown());
+        assert_eq!(output.shutdown_delay(), 20);
+
+        let mut output = OutputControl::new();
+        assert!(output.start_autonomous_shutdown(60));
+        assert!(output.abort_autonomous_shutdown());
+        assert_eq!(output.shutdown_delay(), -1);
+        assert!(output.tick(120_000, false));
+    }
+
+    #[test]
+    fn autonomous_abort_does_not_restore_a_cut_output() {
+        let mut output = OutputControl::new();
+        assert!(output.start_autonomous_shutdown(1));
+        assert!(!output.tick(1000, false));
+        assert!(!output.abort_autonomous_shutdown());
+        assert!(!output.tick(MIN_OFF_TIME_MS, false));
+        assert!(output.tick(100, true));
+    }
+
+    #[test]
+    fn cancel_switches_the_output_on() {
+        let mut output = OutputControl::new();
+        output.set_shutdown_delay(0);
+        output.set_reboot_delay(30);
+        assert!(!output.tick(0, false));
+        output.cancel();
+        assert!(output.output_enabled());
+        assert_eq!(output.reboot_delay(), -1);
+        assert!(output.tick(60_000, false));
+    }
+}
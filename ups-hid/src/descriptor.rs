@@ -0,0 +1,383 @@
+//! Const-evaluable builder for the HID power device report descriptor.
+//!
+//! Every report is declared once as a `ReportDef`, the descriptor bytes and the
+//! payload sizes used by the report encoder are both derived from these declarations.
+
+pub const POWER_DEVICE: u16 = 0x84;
+pub const BATTERY_SYSTEM: u16 = 0x85;
+
+// short item prefixes without the size bits
+const USAGE_PAGE: u8 = 0x04;
+const LOGICAL_MINIMUM: u8 = 0x14;
+const LOGICAL_MAXIMUM: u8 = 0x24;
+const UNIT_EXPONENT: u8 = 0x54;
+const UNIT: u8 = 0x64;
+const REPORT_SIZE: u8 = 0x74;
+const REPORT_ID: u8 = 0x84;
+const REPORT_COUNT: u8 = 0x94;
+const USAGE: u8 = 0x08;
+const STRING_INDEX: u8 = 0x78;
+const INPUT: u8 = 0x80;
+const FEATURE: u8 = 0xB0;
+const COLLECTION: u8 = 0xA0;
+const END_COLLECTION: u8 = 0xC0;
+
+const COLLECTION_APPLICATION: u8 = 0x01;
+const COLLECTION_LOGICAL: u8 = 0x02;
+
+// main item flags
+pub const CONSTANT: u8 = 0x01;
+pub const VARIABLE: u8 = 0x02;
+pub const NO_PREFERRED: u8 = 0x20;
+pub const VOLATILE: u8 = 0x80;
+
+// units
+pub const UNIT_NONE: u32 = 0x0000_0000;
+pub const UNIT_SECONDS: u32 = 0x0000_1001;
+pub const UNIT_VOLT: u32 = 0x00F0_D121; // cm, g: exponent 7 is V, 5 is cV
+pub const UNIT_AMPERE: u32 = 0x0010_0001;
+
+pub const PD_UPS: u16 = 0x04;
+pub const PD_POWER_SUMMARY: u16 = 0x24;
+
+///
+///
+/// one field of a report, emitted as INPUT and / or FEATURE main item
+///
+#[derive(Clone, Copy)]
+pub struct Field {
+    pub usage_page: u16,
+    /// 0 for constant padding without usage
+    pub usage: u16,
+    pub bits: u8,
+    pub count: u8,
+    pub logical_min: i32,
+    pub logical_max: i32,
+    pub unit: u32,
+    pub unit_exponent: i8,
+    /// 0 if the field does not reference a string
+    pub string_index: u8,
+    pub input: Option<u8>,
+    pub feature: Option<u8>,
+}
+
+impl Field {
+    /// 8 bit field with range 0..=255, without unit
+    pub const fn new(usage_page: u16, usage: u16) -> Self {
+        Field {
+            usage_page,
+            usage,
+            bits: 8,
+            count: 1,
+            logical_min: 0,
+            logical_max: 255,
+            unit: UNIT_NONE,
+            unit_exponent: 0,
+            string_index: 0,
+            input: None,
+            feature: None,
+        }
+    }
+
+    /// constant padding bits in the input and feature report
+    pub const fn padding(usage_page: u16, bits: u8, count: u8) -> Self {
+        Field::new(usage_page, 0)
+            .bits(bits)
+            .count(count)
+            .logical(0, 1)
+            .input(CONSTANT)
+            .feature(CONSTANT)
+    }
+
+    pub const fn bits(mut self, bits: u8) -> Self {
+        self.bits = bits;
+        self
+    }
+
+    pub const fn count(mut self, count: u8) -> Self {
+        self.count = count;
+        self
+    }
+
+    pub const fn logical(mut self, min: i32, max: i32) -> Self {
+        self.logical_min = min;
+        self.logical_max = max;
+        self
+    }
+
+    pub const fn unit(mut self, unit: u32, exponent: i8) -> Self {
+        self.unit = unit;
+        self.unit_exponent = exponent;
+        self
+    }
+
+    pub const fn string(mut self, index: u8) -> Self {
+        self.string_index = index;
+        self
+    }
+
+    pub const fn input(mut self, flags: u8) -> Self {
+        self.input = Some(flags);
+        self
+    }
+
+    pub const fn feature(mut self, flags: u8) -> Self {
+        self.feature = Some(flags);
+        self
+    }
+
+    const fn total_bits(&self) -> usize {
+        self.bits as usize * self.count as usize
+    }
+}
+
+///
+///
+/// declaration of a report, optionally wrapped in a logical collection (usage page, usage)
+///
+#[derive(Clone, Copy)]
+pub struct ReportDef {
+    pub id: u8,
+    pub collection: Option<(u16, u16)>,
+    pub fields: &'static [Field],
+}
+
+impl ReportDef {
+    pub const fn input_bits(&self) -> usize {
+        let mut bits = 0;
+        let mut i = 0;
+        while i < self.fields.len() {
+            if self.fields[i].input.is_some() {
+                bits += self.fields[i].total_bits();
+            }
+            i += 1;
+        }
+        bits
+    }
+
+    pub const fn feature_bits(&self) -> usize {
+        let mut bits = 0;
+        let mut i = 0;
+        while i < self.fields.len() {
+            if self.fields[i].feature.is_some() {
+                bits += self.fields[i].total_bits();
+            }
+            i += 1;
+        }
+        bits
+    }
+
+    /// payload length in bytes, without the report id
+    pub const fn payload_size(&self) -> usize {
+        let input = self.input_bits();
+        let feature = self.feature_bits();
+        let bits = if input > feature { input } else { feature };
+        (bits + 7) / 8
+    }
+}
+
+///
+///
+/// looks up a report declaration by id
+///
+pub const fn find_report(reports: &[ReportDef], id: u8) -> Option<ReportDef> {
+    let mut i = 0;
+    while i < reports.len() {
+        if reports[i].id == id {
+            return Some(reports[i]);
+        }
+        i += 1;
+    }
+    None
+}
+
+/// current global item state, `None` until the item was emitted once
+#[derive(Clone, Copy)]
+struct Globals {
+    usage_page: Option<u16>,
+    bits: Option<u8>,
+    count: Option<u8>,
+    logical_min: Option<i32>,
+    logical_max: Option<i32>,
+    unit: Option<u32>,
+    unit_exponent: Option<i8>,
+}
+
+///
+///
+/// descriptor under construction
+///
+/// with `N` smaller than the descriptor only the length is counted, so
+/// `build::<0>(..).len` gives the `N` needed to hold the bytes
+///
+pub struct Builder<const N: usize> {
+    pub bytes: [u8; N],
+    pub len: usize,
+    globals: Globals,
+}
+
+impl<const N: usize> Builder<N> {
+    const fn new() -> Self {
+        Builder {
+            bytes: [0; N],
+            len: 0,
+            globals: Globals {
+                usage_page: None,
+                bits: None,
+                count: None,
+                logical_min: None,
+                logical_max: None,
+                unit: None,
+                unit_exponent: None,
+            },
+        }
+    }
+
+    const fn byte(mut self, byte: u8) -> Self {
+        if self.len < N {
+            self.bytes[self.len] = byte;
+        }
+        self.len += 1;
+        self
+    }
+
+    const fn item(mut self, prefix: u8, value: u32, size: usize) -> Self {
+        let size_code = match size {
+            0 => 0,
+            1 => 1,
+            2 => 2,
+            _ => 3,
+        };
+        self = self.byte(prefix | size_code);
+        let mut i = 0;
+        while i < size {
+            self = self.byte((value >> (8 * i)) as u8);
+            i += 1;
+        }
+        self
+    }
+
+    const fn unsigned(self, prefix: u8, value: u32) -> Self {
+        let size = if value <= 0xFF {
+            1
+        } else if value <= 0xFFFF {
+            2
+        } else {
+            4
+        };
+        self.item(prefix, value, size)
+    }
+
+    const fn signed(self, prefix: u8, value: i32) -> Self {
+        let size = if value >= i8::MIN as i32 && value <= i8::MAX as i32 {
+            1
+        } else if value >= i16::MIN as i32 && value <= i16::MAX as i32 {
+            2
+        } else {
+            4
+        };
+        self.item(prefix, value as u32, size)
+    }
+
+    const fn usage_page(mut self, page: u16) -> Self {
+        if !matches!(self.globals.usage_page, Some(current) if current == page) {
+            self = self.unsigned(USAGE_PAGE, page as u32);
+            self.globals.usage_page = Some(page);
+        }
+        self
+    }
+
+    const fn globals(mut self, field: &Field) -> Self {
+        self = self.usage_page(field.usage_page);
+        if !matches!(self.globals.bits, Some(current) if current == field.bits) {
+            self = self.unsigned(REPORT_SIZE, field.bits as u32);
+            self.globals.bits = Some(field.bits);
+        }
+        if !matches!(self.globals.count, Some(current) if current == field.count) {
+            self = self.unsigned(REPORT_COUNT, field.count as u32);
+            self.globals.count = Some(field.count);
+        }
+        if !matches!(self.globals.logical_min, Some(current) if current == field.logical_min) {
+            self = self.signed(LOGICAL_MINIMUM, field.logical_min);
+            self.globals.logical_min = Some(field.logical_min);
+        }
+        if !matches!(self.globals.logical_max, Some(current) if current == field.logical_max) {
+            self = self.signed(LOGICAL_MAXIMUM, field.logical_max);
+            self.globals.logical_max = Some(field.logical_max);
+        }
+        if !matches!(self.globals.unit, Some(current) if current == field.unit) {
+            self = self.unsigned(UNIT, field.unit);
+            self.globals.unit = Some(field.unit);
+        }
+        if !matches!(self.globals.unit_exponent, Some(current) if current == field.unit_exponent) {
+            self = self.item(UNIT_EXPONENT, (field.unit_exponent as u8 & 0x0F) as u32, 1);
+            self.globals.unit_exponent = Some(field.unit_exponent);
+        }
+        self
+    }
+
+    /// local items of a field followed by its main item
+    const fn main_item(mut self, field: &Field, prefix: u8, flags: u8) -> Self {
+        if field.usage != 0 {
+            self = self.unsigned(USAGE, field.usage as u32);
+        }
+        if field.string_index != 0 {
+            self = self.unsigned(STRING_INDEX, field.string_index as u32);
+        }
+        self.item(prefix, flags as u32, 1)
+    }
+
+    const fn report(mut self, report: &ReportDef) -> Self {
+        if let Some((page, usage)) = report.collection {
+            self = self.usage_page(page);
+            self = self.unsigned(USAGE, usage as u32);
+            self = self.item(COLLECTION, COLLECTION_LOGICAL as u32, 1);
+        }
+        self = self.unsigned(REPORT_ID, report.id as u32);
+        let mut i = 0;
+        while i < report.fields.len() {
+            let field = &report.fields[i];
+            self = self.globals(field);
+            if let Some(flags) = field.input {
+                self = self.main_item(field, INPUT, flags);
+            }
+            if let Some(flags) = field.feature {
+                self = self.main_item(field, FEATURE, flags);
+            }
+            i += 1;
+        }
+        if report.collection.is_some() {
+            self = self.byte(END_COLLECTION);
+        }
+        self
+    }
+}
+
+///
+///
+/// builds the descriptor of a UPS: an application collection containing the
+/// power summary logical collection with all reports
+///
+/// `vendor` reports (vendor specific usages some hosts look for) follow the power
+/// summary inside the application collection
+///
+pub const fn build<const N: usize>(reports: &[ReportDef], vendor: &[ReportDef]) -> Builder<N> {
+    let mut builder = Builder::<N>::new()
+        .usage_page(POWER_DEVICE)
+        .unsigned(USAGE, PD_UPS as u32)
+        .item(COLLECTION, COLLECTION_APPLICATION as u32, 1)
+        .unsigned(USAGE, PD_POWER_SUMMARY as u32)
+        .item(COLLECTION, COLLECTION_LOGICAL as u32, 1);
+    let mut i = 0;
+    while i < reports.len() {
+        builder = builder.report(&reports[i]);
+        i += 1;
+    }
+    builder = builder.byte(END_COLLECTION);
+    let mut i = 0;
+    while i < vendor.len() {
+        builder = builder.report(&vendor[i]);
+        i += 1;
+    }
+    builder.byte(END_COLLECTION)
+}
@@ -0,0 +1,16 @@
+//! HID power device report descriptors.
+//!
+//! `descriptor` builds the report descriptor from report declarations, `parser` reads a
+//! descriptor back into a field map and checks it against the declarations, `report`
+//! declares the reports of the UPS and encodes them, `usages` names the usages of the
+//! Power Device and Battery System pages.
+//!
+//! Everything is `const fn` and allocation free, so the firmware runs the checks at
+//! compile time while host tools and the tests use the same code at run time.
+
+#![no_std]
+
+pub mod descriptor;
+pub mod parser;
+pub mod report;
+pub mod usages;
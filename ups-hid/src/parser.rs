@@ -0,0 +1,700 @@
+//! Minimal HID report descriptor parser.
+//!
+//! Everything is `const fn` and allocation free, so the descriptor can be checked
+//! at compile time and the parser can be reused by host side tooling.
+//!
+//! `check` does not trust the builder: every main item is compared against the
+//! field it was declared as, and every usage must exist on its usage page.
+
+use crate::descriptor::{find_report, Field, ReportDef};
+use crate::usages::is_known_usage;
+
+pub const MAX_REPORTS: usize = 32;
+pub const MAX_FIELDS: usize = 32;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum MainKind {
+    Input,
+    Output,
+    Feature,
+}
+
+///
+///
+/// one INPUT / OUTPUT / FEATURE main item of a report
+///
+#[derive(Clone, Copy, Debug)]
+pub struct FieldInfo {
+    pub kind: MainKind,
+    pub usage_page: u16,
+    /// first usage of the item, 0 for padding
+    pub usage: u16,
+    /// bit offset inside the report payload, after the report id
+    pub bit_offset: usize,
+    pub bits: u8,
+    pub count: u8,
+    pub logical_min: i32,
+    pub logical_max: i32,
+    pub unit: u32,
+    pub unit_exponent: i8,
+    pub flags: u8,
+}
+
+impl FieldInfo {
+    const EMPTY: FieldInfo = FieldInfo {
+        kind: MainKind::Input,
+        usage_page: 0,
+        usage: 0,
+        bit_offset: 0,
+        bits: 0,
+        count: 0,
+        logical_min: 0,
+        logical_max: 0,
+        unit: 0,
+        unit_exponent: 0,
+        flags: 0,
+    };
+}
+
+///
+///
+/// field map of one report id
+///
+#[derive(Clone, Copy, Debug)]
+pub struct ReportLayout {
+    pub id: u8,
+    pub input_bits: usize,
+    pub output_bits: usize,
+    pub feature_bits: usize,
+    pub fields: [FieldInfo; MAX_FIELDS],
+    pub field_count: usize,
+}
+
+impl ReportLayout {
+    const EMPTY: ReportLayout = ReportLayout {
+        id: 0,
+        input_bits: 0,
+        output_bits: 0,
+        feature_bits: 0,
+        fields: [FieldInfo::EMPTY; MAX_FIELDS],
+        field_count: 0,
+    };
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct ParsedDescriptor {
+    pub reports: [ReportLayout; MAX_REPORTS],
+    pub report_count: usize,
+}
+
+impl ParsedDescriptor {
+    pub const fn report(&self, id: u8) -> Option<&ReportLayout> {
+        let mut i = 0;
+        while i < self.report_count {
+            if self.reports[i].id == id {
+                return Some(&self.reports[i]);
+            }
+            i += 1;
+        }
+        None
+    }
+}
+
+/// errors carry the byte offset of the offending item
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ParseError {
+    Truncated(usize),
+    UnbalancedCollection(usize),
+    UnclosedCollection,
+    MissingReportSize(usize),
+    MissingReportCount(usize),
+    MissingReportId(usize),
+    InvalidLogicalRange(usize),
+    UnsupportedItem(usize),
+    TooManyReports,
+    TooManyFields(u8),
+}
+
+impl ParseError {
+    pub const fn message(&self) -> &'static str {
+        match self {
+            ParseError::Truncated(_) => "report descriptor is truncated",
+            ParseError::UnbalancedCollection(_) => "END_COLLECTION without COLLECTION",
+            ParseError::UnclosedCollection => "COLLECTION without END_COLLECTION",
+            ParseError::MissingReportSize(_) => "main item without REPORT_SIZE",
+            ParseError::MissingReportCount(_) => "main item without REPORT_COUNT",
+            ParseError::MissingReportId(_) => "main item without REPORT_ID",
+            ParseError::InvalidLogicalRange(_) => "LOGICAL_MINIMUM is larger than LOGICAL_MAXIMUM",
+            ParseError::UnsupportedItem(_) => "unsupported item (long item or PUSH / POP)",
+            ParseError::TooManyReports => "more report ids than MAX_REPORTS",
+            ParseError::TooManyFields(_) => "more main items in a report than MAX_FIELDS",
+        }
+    }
+}
+
+const fn unsigned_value(descriptor: &[u8], start: usize, size: usize) -> u32 {
+    let mut value = 0;
+    let mut i = 0;
+    while i < size {
+        value |= (descriptor[start + i] as u32) << (8 * i);
+        i += 1;
+    }
+    value
+}
+
+const fn signed_value(descriptor: &[u8], start: usize, size: usize) -> i32 {
+    let value = unsigned_value(descriptor, start, size);
+    match size {
+        1 => value as u8 as i8 as i32,
+        2 => value as u16 as i16 as i32,
+        _ => value as i32,
+    }
+}
+
+///
+///
+/// parses a report descriptor into a field map per report id
+///
+/// returns: Result<ParsedDescriptor, ParseError>
+///
+pub const fn parse(descriptor: &[u8]) -> Result<ParsedDescriptor, ParseError> {
+    let mut parsed = ParsedDescriptor {
+        reports: [ReportLayout::EMPTY; MAX_REPORTS],
+        report_count: 0,
+    };
+
+    // global items
+    let mut usage_page: u16 = 0;
+    let mut logical_min: i32 = 0;
+    let mut logical_max: i32 = 0;
+    let mut unit: u32 = 0;
+    let mut unit_exponent: i8 = 0;
+    let mut report_size: Option<u8> = None;
+    let mut report_count: Option<u8> = None;
+    let mut report_id: Option<u8> = None;
+    // local items
+    let mut usage: Option<(u16, u16)> = None;
+
+    let mut depth = 0;
+    let mut i = 0;
+    while i < descriptor.len() {
+        let prefix = descriptor[i];
+        if prefix == 0xFE {
+            return Err(ParseError::UnsupportedItem(i));
+        }
+        let size = match prefix & 0x03 {
+            3 => 4,
+            size => size as usize,
+        };
+        if i + 1 + size > descriptor.len() {
+            return Err(ParseError::Truncated(i));
+        }
+        let data = i + 1;
+        let value = unsigned_value(descriptor, data, size);
+        let item_type = (prefix >> 2) & 0x03;
+        let tag = prefix >> 4;
+
+        match (item_type, tag) {
+            // main items
+            (0, 0x8) | (0, 0x9) | (0, 0xB) => {
+                let kind = match tag {
+                    0x8 => MainKind::Input,
+                    0x9 => MainKind::Output,
+                    _ => MainKind::Feature,
+                };
+                let bits = match report_size {
+                    Some(bits) => bits,
+                    None => return Err(ParseError::MissingReportSize(i)),
+                };
+                let count = match report_count {
+                    Some(count) => count,
+                    None => return Err(ParseError::MissingReportCount(i)),
+                };
+                let id = match report_id {
+                    Some(id) => id,
+                    None => return Err(ParseError::MissingReportId(i)),
+                };
+                if logical_min > logical_max {
+                    return Err(ParseError::InvalidLogicalRange(i));
+                }
+
+                let mut r = 0;
+                while r < parsed.report_count && parsed.reports[r].id != id {
+                    r += 1;
+                }
+                if r == parsed.report_count {
+                    if r == MAX_REPORTS {
+                        return Err(ParseError::TooManyReports);
+                    }
+                    parsed.reports[r].id = id;
+                    parsed.report_count += 1;
+                }
+                // no `&mut` in const fn, index the report directly
+                let n = parsed.reports[r].field_count;
+                if n == MAX_FIELDS {
+                    return Err(ParseError::TooManyFields(id));
+                }
+                let total_bits = bits as usize * count as usize;
+                let bit_offset = match kind {
+                    MainKind::Input => parsed.reports[r].input_bits,
+                    MainKind::Output => parsed.reports[r].output_bits,
+                    MainKind::Feature => parsed.reports[r].feature_bits,
+                };
+                let (field_page, field_usage) = match usage {
+                    Some(usage) => usage,
+                    None => (usage_page, 0),
+                };
+                parsed.reports[r].fields[n] = FieldInfo {
+                    kind,
+                    usage_page: field_page,
+                    usage: field_usage,
+                    bit_offset,
+                    bits,
+                    count,
+                    logical_min,
+                    logical_max,
+                    unit,
+                    unit_exponent,
+                    flags: value as u8,
+                };
+                parsed.reports[r].field_count = n + 1;
+                match kind {
+                    MainKind::Input => parsed.reports[r].input_bits = bit_offset + total_bits,
+                    MainKind::Output => parsed.reports[r].output_bits = bit_offset + total_bits,
+                    MainKind::Feature => parsed.reports[r].feature_bits = bit_offset + total_bits,
+                }
+                usage = None;
+            }
+            (0, 0xA) => {
+                depth += 1;
+                usage = None;
+            }
+            (0, 0xC) => {
+                if depth == 0 {
+                    return Err(ParseError::UnbalancedCollection(i));
+                }
+                depth -= 1;
+            }
+            // global items
+            (1, 0x0) => usage_page = value as u16,
+            (1, 0x1) => logical_min = signed_value(descriptor, data, size),
+            (1, 0x2) => logical_max = signed_value(descriptor, data, size),
+            (1, 0x5) => unit_exponent = ((value as u8) << 4) as i8 >> 4,
+            (1, 0x6) => unit = value,
+            (1, 0x7) => report_size = Some(value as u8),
+            (1, 0x8) => report_id = Some(value as u8),
+            (1, 0x9) => report_count = Some(value as u8),
+            (1, 0xA) | (1, 0xB) => return Err(ParseError::UnsupportedItem(i)),
+            // local items, only the first usage of a main item is kept
+            (2, 0x0) => {
+                if usage.is_none() {
+                    usage = if size == 4 {
+                        Some(((value >> 16) as u16, value as u16))
+                    } else {
+                        Some((usage_page, value as u16))
+                    };
+                }
+            }
+            _ => {}
+        }
+        i = data + size;
+    }
+
+    if depth != 0 {
+        return Err(ParseError::UnclosedCollection);
+    }
+    Ok(parsed)
+}
+
+///
+///
+/// rewrites the value of every STRING_INDEX item, `map` gets the index the descriptor was
+/// built with and returns the one the USB stack allocated
+///
+/// returns: Result<(), ParseError>
+///
+pub fn remap_string_indexes(descriptor: &mut [u8], mut map: impl FnMut(u8) -> u8) -> Result<(), ParseError> {
+    let mut i = 0;
+    while i < descriptor.len() {
+        let prefix = descriptor[i];
+        if prefix == 0xFE {
+            return Err(ParseError::UnsupportedItem(i));
+        }
+        let size = match prefix & 0x03 {
+            3 => 4,
+            size => size as usize,
+        };
+        if i + 1 + size > descriptor.len() {
+            return Err(ParseError::Truncated(i));
+        }
+        // local item STRING_INDEX, the builder emits indexes as a single byte
+        if prefix & 0xFC == 0x78 && size > 0 {
+            let index = unsigned_value(descriptor, i + 1, size);
+            if index <= u8::MAX as u32 {
+                let bytes = (map(index as u8) as u32).to_le_bytes();
+                descriptor[i + 1..i + 1 + size].copy_from_slice(&bytes[..size]);
+            }
+        }
+        i += 1 + size;
+    }
+    Ok(())
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Mismatch {
+    Parse(ParseError),
+    /// report payload is not a whole number of bytes
+    UnalignedReport(u8),
+    /// report id in the descriptor without a declaration
+    UndeclaredReport(u8),
+    /// declared report id missing in the descriptor
+    MissingReport(u8),
+    /// payload size in the descriptor differs from the declaration
+    SizeMismatch(u8),
+    /// vendor report reuses the id of a standard report
+    DuplicateReport(u8),
+    /// PresentStatus does not have the width of the `Status` bitfield
+    StatusWidth,
+    /// main item differs from the declared field (usage, size, count, range or unit)
+    FieldMismatch(u8),
+    /// logical range does not fit into the report size of the item
+    LogicalRange(u8),
+    /// usage is not defined on its usage page
+    UnknownUsage(u8),
+}
+
+impl Mismatch {
+    pub const fn message(&self) -> &'static str {
+        match self {
+            Mismatch::Parse(error) => error.message(),
+            Mismatch::UnalignedReport(_) => "report payload is not a whole number of bytes",
+            Mismatch::UndeclaredReport(_) => "report id in the descriptor is not declared",
+            Mismatch::MissingReport(_) => "declared report id is missing in the descriptor",
+            Mismatch::SizeMismatch(_) => "report size in the descriptor differs from its declaration",
+            Mismatch::DuplicateReport(_) => "vendor report reuses the id of a standard report",
+            Mismatch::StatusWidth => "PresentStatus width differs from the Status bitfield",
+            Mismatch::FieldMismatch(_) => "main item differs from the declared field",
+            Mismatch::LogicalRange(_) => "logical range does not fit into the report size",
+            Mismatch::UnknownUsage(_) => "usage is not defined on its usage page",
+        }
+    }
+}
+
+///
+///
+/// returns: true if the logical range of a field can be represented with its report size
+///
+const fn range_fits(field: &FieldInfo) -> bool {
+    if field.bits == 0 || field.bits > 32 {
+        return false;
+    }
+    let bits = field.bits as u32;
+    if field.logical_min < 0 {
+        let limit = 1i64 << (bits - 1);
+        field.logical_min as i64 >= -limit && (field.logical_max as i64) < limit
+    } else {
+        (field.logical_max as i64) < (1i64 << bits)
+    }
+}
+
+///
+///
+/// returns: true if a parsed main item is the declared field
+///
+const fn same_field(parsed: &FieldInfo, declared: &Field) -> bool {
+    parsed.usage_page == declared.usage_page
+        && parsed.usage == declared.usage
+        && parsed.bits == declared.bits
+        && parsed.count == declared.count
+        && parsed.logical_min == declared.logical_min
+        && parsed.logical_max == declared.logical_max
+        && parsed.unit == declared.unit
+        && parsed.unit_exponent == declared.unit_exponent
+}
+
+///
+///
+/// compares the main items of a report, in descriptor order, with its declared fields
+///
+const fn check_fields(layout: &ReportLayout, declared: &ReportDef) -> Result<(), Mismatch> {
+    let mut n = 0;
+    let mut i = 0;
+    while i < declared.fields.len() {
+        let field = &declared.fields[i];
+        let mut pass = 0;
+        while pass < 2 {
+            let emitted = match pass {
+                0 => field.input,
+                _ => field.feature,
+            };
+            if let Some(flags) = emitted {
+                if n == layout.field_count {
+                    return Err(Mismatch::FieldMismatch(layout.id));
+                }
+                let parsed = &layout.fields[n];
+                let kind_matches = match pass {
+                    0 => matches!(parsed.kind, MainKind::Input),
+                    _ => matches!(parsed.kind, MainKind::Feature),
+                };
+                if !kind_matches
+                    || parsed.flags != flags
+                    || !same_field(parsed, field)
+                {
+                    return Err(Mismatch::FieldMismatch(layout.id));
+                }
+                n += 1;
+            }
+            pass += 1;
+        }
+        i += 1;
+    }
+    if n != layout.field_count {
+        return Err(Mismatch::FieldMismatch(layout.id));
+    }
+    Ok(())
+}
+
+///
+///
+/// checks a descriptor against the declarations of the reports the firmware sends
+///
+/// `reports` and `vendor` are the declarations the descriptor was built from,
+/// `status_id` / `status_bits` are the id of the PresentStatus report and the width
+/// of the bitfield that is sent in it
+///
+pub const fn check(
+    descriptor: &[u8],
+    reports: &[ReportDef],
+    vendor: &[ReportDef],
+    status_id: u8,
+    status_bits: usize,
+) -> Result<(), Mismatch> {
+    let parsed = match parse(descriptor) {
+        Ok(parsed) => parsed,
+        Err(error) => return Err(Mismatch::Parse(error)),
+    };
+
+    let mut i = 0;
+    while i < parsed.report_count {
+        let layout = &parsed.reports[i];
+        if layout.input_bits % 8 != 0 || layout.output_bits % 8 != 0 || layout.feature_bits % 8 != 0 {
+            return Err(Mismatch::UnalignedReport(layout.id));
+        }
+        let declared = match find_report(reports, layout.id) {
+            Some(declared) => declared,
+            None => match find_report(vendor, layout.id) {
+                Some(declared) => declared,
+                None => return Err(Mismatch::UndeclaredReport(layout.id)),
+            },
+        };
+        let bits = if layout.input_bits > layout.feature_bits {
+            layout.input_bits
+        } else {
+            layout.feature_bits
+        };
+        if bits / 8 != declared.payload_size() {
+            return Err(Mismatch::SizeMismatch(layout.id));
+        }
+        if layout.id == status_id
+            && ((layout.input_bits != 0 && layout.input_bits != status_bits)
+            || (layout.feature_bits != 0 && layout.feature_bits != status_bits))
+        {
+            return Err(Mismatch::StatusWidth);
+        }
+        let mut f = 0;
+        while f < layout.field_count {
+            let field = &layout.fields[f];
+            if field.usage != 0 && !is_known_usage(field.usage_page, field.usage) {
+                return Err(Mismatch::UnknownUsage(layout.id));
+            }
+            if !range_fits(field) {
+                return Err(Mismatch::LogicalRange(layout.id));
+            }
+            f += 1;
+        }
+        if let Err(mismatch) = check_fields(layout, &declared) {
+            return Err(mismatch);
+        }
+        i += 1;
+    }
+
+    let mut i = 0;
+    while i < reports.len() {
+        if parsed.report(reports[i].id).is_none() {
+            return Err(Mismatch::MissingReport(reports[i].id));
+        }
+        i += 1;
+    }
+    let mut i = 0;
+    while i < vendor.len() {
+        if parsed.report(vendor[i].id).is_none() {
+            return Err(Mismatch::MissingReport(vendor[i].id));
+        }
+        if find_report(reports, vendor[i].id).is_some() {
+            return Err(Mismatch::DuplicateReport(vendor[i].id));
+        }
+        i += 1;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use std::vec::Vec;
+    use crate::descriptor::{build, CONSTANT, POWER_DEVICE, UNIT_VOLT, VARIABLE, VOLATILE};
+
+    const VOLTAGE: ReportDef = ReportDef {
+        id: 0x0B,
+        collection: None,
+        fields: &[Field::new(POWER_DEVICE, 0x30)
+            .bits(16)
+            .logical(0, 65535)
+            .unit(UNIT_VOLT, 5)
+            .input(CONSTANT | VARIABLE)
+            .feature(CONSTANT | VARIABLE | VOLATILE)],
+    };
+
+    // Usage Page (Power Device), Usage (UPS), Collection (Application),
+    // Usage (PowerSummary), Collection (Logical), Report ID (0x0B), Report Size (16),
+    // Report Count (1), Logical Min (0), Logical Max (65535), Unit (V), Unit Exponent (5),
+    // Usage (Voltage), Input (Cnst,Var), Usage (Voltage), Feature (Cnst,Var,Vol),
+    // End Collection, End Collection
+    const HAND_WRITTEN: &[u8] = &[
+        0x05, 0x84, 0x09, 0x04, 0xA1, 0x01, 0x09, 0x24, 0xA1, 0x02, 0x85, 0x0B, 0x75, 0x10,
+        0x95, 0x01, 0x15, 0x00, 0x27, 0xFF, 0xFF, 0x00, 0x00, 0x67, 0x21, 0xD1, 0xF0, 0x00,
+        0x55, 0x05, 0x09, 0x30, 0x81, 0x03, 0x09, 0x30, 0xB1, 0x83, 0xC0, 0xC0,
+    ];
+
+    #[test]
+    fn parses_hand_written_descriptor() {
+        let parsed = parse(HAND_WRITTEN).unwrap();
+        assert_eq!(parsed.report_count, 1);
+        let layout = parsed.report(0x0B).unwrap();
+        assert_eq!((layout.input_bits, layout.output_bits, layout.feature_bits), (16, 0, 16));
+        assert_eq!(layout.field_count, 2);
+        let input = layout.fields[0];
+        assert_eq!(input.kind, MainKind::Input);
+        assert_eq!((input.usage_page, input.usage), (POWER_DEVICE, 0x30));
+        assert_eq!((input.bits, input.count, input.bit_offset), (16, 1, 0));
+        assert_eq!((input.logical_min, input.logical_max), (0, 65535));
+        assert_eq!((input.unit, input.unit_exponent), (UNIT_VOLT, 5));
+        assert_eq!(layout.fields[1].kind, MainKind::Feature);
+        assert_eq!(layout.fields[1].flags, 0x83);
+        assert_eq!(check(HAND_WRITTEN, &[VOLTAGE], &[], 0x01, 8), Ok(()));
+    }
+
+    #[test]
+    fn builder_matches_hand_written_descriptor() {
+        let built = build::<64>(&[VOLTAGE], &[]);
+        assert_eq!(&built.bytes[..built.len], HAND_WRITTEN);
+    }
+
+    #[test]
+    fn rejects_truncated_item() {
+        // Logical Maximum announces four data bytes, only three follow
+        let truncated = &HAND_WRITTEN[..21];
+        assert_eq!(parse(truncated).unwrap_err(), ParseError::Truncated(18));
+        assert_eq!(check(truncated, &[VOLTAGE], &[], 0x01, 8), Err(Mismatch::Parse(ParseError::Truncated(18))));
+    }
+
+    #[test]
+    fn rejects_unclosed_and_unbalanced_collections() {
+        let unclosed = &HAND_WRITTEN[..HAND_WRITTEN.len() - 1];
+        assert_eq!(parse(unclosed).unwrap_err(), ParseError::UnclosedCollection);
+        assert_eq!(parse(&[0xC0]).unwrap_err(), ParseError::UnbalancedCollection(0));
+    }
+
+    #[test]
+    fn rejects_mismatched_report_size() {
+        let mut bytes = [0; 40];
+        bytes.copy_from_slice(HAND_WRITTEN);
+        // Report Size (8) instead of 16
+        bytes[13] = 0x08;
+        let layout = parse(&bytes).unwrap().reports[0];
+        assert_eq!(layout.input_bits, 8);
+        assert_eq!(check(&bytes, &[VOLTAGE], &[], 0x01, 8), Err(Mismatch::SizeMismatch(0x0B)));
+    }
+
+    #[test]
+    fn rejects_field_that_differs_from_declaration() {
+        let mut bytes = [0; 40];
+        bytes.copy_from_slice(HAND_WRITTEN);
+        // Usage (Current) instead of Voltage on the feature item
+        bytes[35] = 0x31;
+        assert_eq!(check(&bytes, &[VOLTAGE], &[], 0x01, 8), Err(Mismatch::FieldMismatch(0x0B)));
+    }
+
+    #[test]
+    fn rejects_unknown_usage() {
+        let mut bytes = [0; 40];
+        bytes.copy_from_slice(HAND_WRITTEN);
+        // 0x3F is reserved on the Power Device page
+        bytes[31] = 0x3F;
+        bytes[35] = 0x3F;
+        const UNKNOWN: ReportDef = ReportDef {
+            id: 0x0B,
+            collection: None,
+            fields: &[Field::new(POWER_DEVICE, 0x3F)
+                .bits(16)
+                .logical(0, 65535)
+                .unit(UNIT_VOLT, 5)
+                .input(CONSTANT | VARIABLE)
+                .feature(CONSTANT | VARIABLE | VOLATILE)],
+        };
+        assert_eq!(check(&bytes, &[UNKNOWN], &[], 0x01, 8), Err(Mismatch::UnknownUsage(0x0B)));
+    }
+
+    #[test]
+    fn rejects_logical_range_wider_than_report_size() {
+        const WIDE: ReportDef = ReportDef {
+            id: 0x0B,
+            collection: None,
+            fields: &[Field::new(POWER_DEVICE, 0x30).logical(0, 300).input(CONSTANT | VARIABLE)],
+        };
+        let built = build::<64>(&[WIDE], &[]);
+        assert_eq!(check(&built.bytes[..built.len], &[WIDE], &[], 0x01, 8), Err(Mismatch::LogicalRange(0x0B)));
+    }
+
+    #[test]
+    fn rejects_undeclared_and_missing_reports() {
+        let other = ReportDef { id: 0x0C, ..VOLTAGE };
+        assert_eq!(check(HAND_WRITTEN, &[other], &[], 0x01, 8), Err(Mismatch::UndeclaredReport(0x0B)));
+        assert_eq!(check(HAND_WRITTEN, &[VOLTAGE, other], &[], 0x01, 8), Err(Mismatch::MissingReport(0x0C)));
+        assert_eq!(check(HAND_WRITTEN, &[VOLTAGE], &[VOLTAGE], 0x01, 8), Err(Mismatch::DuplicateReport(0x0B)));
+    }
+
+    #[test]
+    fn remaps_string_indexes() {
+        const STRINGS: ReportDef = ReportDef {
+            id: 0x01,
+            collection: None,
+            fields: &[
+                Field::new(POWER_DEVICE, 0xFE).string(2).feature(CONSTANT | VARIABLE),
+                Field::new(POWER_DEVICE, 0xFD).string(4).feature(CONSTANT | VARIABLE),
+            ],
+        };
+        let built = build::<64>(&[STRINGS], &[]);
+        let mut bytes = built.bytes;
+        let len = built.len;
+        remap_string_indexes(&mut bytes[..len], |index| if index == 4 { 7 } else { index }).unwrap();
+        let changed: Vec<usize> = (0..len).filter(|&i| bytes[i] != built.bytes[i]).collect();
+        assert_eq!(changed.len(), 1);
+        assert_eq!(&bytes[changed[0] - 1..=changed[0]], &[0x79, 0x07]);
+        assert_eq!(check(&bytes[..len], &[STRINGS], &[], 0x07, 8), Ok(()));
+        assert_eq!(remap_string_indexes(&mut bytes[..len - 3], |index| index), Err(ParseError::Truncated(len - 4)));
+    }
+
+    #[test]
+    fn vendor_pages_accept_every_usage() {
+        const VENDOR: ReportDef = ReportDef {
+            id: 0x20,
+            collection: None,
+            fields: &[Field::new(0xFF86, 0x3F).feature(CONSTANT | VARIABLE)],
+        };
+        let built = build::<64>(&[VOLTAGE], &[VENDOR]);
+        assert_eq!(check(&built.bytes[..built.len], &[VOLTAGE], &[VENDOR], 0x01, 8), Ok(()));
+    }
+}
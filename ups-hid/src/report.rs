@@ -0,0 +1,626 @@
+//! Reports of the UPS: report ids, the `Report` encoder and the declarations the
+//! report descriptor is built from.
+
+use crate::descriptor::*;
+use crate::parser::check;
+use modular_bitfield::bitfield;
+use core::mem::size_of;
+use modular_bitfield::prelude::*;
+
+pub const HID_PD_IPRODUCT: u8 = 0x01;               // FEATURE ONLY
+pub const HID_PD_SERIAL: u8 = 0x02;                 // FEATURE ONLY
+pub const HID_PD_MANUFACTURER: u8 = 0x03;           // FEATURE ONLY
+// string indexes the descriptor is built with, the firmware replaces them with the
+// indexes its USB stack allocates (see `parser::remap_string_indexes`)
+pub const IDEVICECHEMISTRY: u8 = 0x04;
+pub const IOEMVENDOR: u8 = 0x05;
+
+pub const HID_PD_RECHARGEABLE: u8 = 0x06;           // FEATURE ONLY
+pub const HID_PD_PRESENTSTATUS: u8 = 0x07;          // INPUT OR FEATURE(required by Windows)
+pub const HID_PD_REMAINTIMELIMIT: u8 = 0x08;
+pub const HID_PD_MANUFACTUREDATE: u8 = 0x09;
+pub const HID_PD_CONFIGVOLTAGE: u8 = 0x0A;          // FEATURE ONLY
+pub const HID_PD_VOLTAGE: u8 = 0x0B;                // INPUT (NA) OR FEATURE(implemented)
+pub const HID_PD_REMAININGCAPACITY: u8 = 0x0C;      // INPUT OR FEATURE(required by Windows)
+pub const HID_PD_RUNTIMETOEMPTY: u8 = 0x0D;
+pub const HID_PD_FULLCHRGECAPACITY: u8 = 0x0E;      // INPUT OR FEATURE. Last Full Charge Capacity
+pub const HID_PD_WARNCAPACITYLIMIT: u8 = 0x0F;
+pub const HID_PD_CPCTYGRANULARITY1: u8 = 0x10;
+pub const HID_PD_REMNCAPACITYLIMIT: u8 = 0x11;
+pub const HID_PD_DELAYBE4SHUTDOWN: u8 = 0x12;       // FEATURE ONLY
+pub const HID_PD_DELAYBE4REBOOT: u8 = 0x13;
+pub const HID_PD_AUDIBLEALARMCTRL: u8 = 0x14;       // FEATURE ONLY
+pub const HID_PD_CURRENT: u8 = 0x15;                // INPUT OR FEATURE
+pub const HID_PD_CAPACITYMODE: u8 = 0x16;
+pub const HID_PD_DESIGNCAPACITY: u8 = 0x17;
+pub const HID_PD_CPCTYGRANULARITY2: u8 = 0x18;
+pub const HID_PD_AVERAGETIME2FULL: u8 = 0x1A;
+pub const HID_PD_AVERAGECURRENT: u8 = 0x1B;
+pub const HID_PD_AVERAGETIME2EMPTY: u8 = 0x1C;
+
+pub const HID_PD_IDEVICECHEMISTRY: u8 = 0x1F;       // Feature
+pub const HID_PD_IOEMINFORMATION: u8 = 0x20;        // Feature
+
+pub const IPRODUCT: u8 = 0x02;
+pub const ISERIAL: u8 = 0x03;
+pub const IMANUFACTURER: u8 = 0x01;
+
+// usb-device serves string indexes 1 to 3 from the device's StringDescriptors
+const _: () = assert!(IMANUFACTURER == 1 && IPRODUCT == 2 && ISERIAL == 3);
+
+pub const STRING_DEVICE_CHEMISTRY: &str = "LiIon";
+
+#[derive(Clone, Copy)]
+#[bitfield(bits = 16)]
+pub struct Status {
+    pub charging: B1,
+    pub discharging: B1,
+    pub ac_present: B1,
+    pub battery_present: B1,
+    pub below_remaining_capacity_limit: B1,
+    pub remaining_time_limit_expired: B1,
+    pub need_replace: B1,
+    pub voltage_nr: B1,
+    pub full_charge: B1,
+    pub full_discharge: B1,
+    pub shutdown_requested: B1,
+    pub shutdown_imminent: B1,
+    pub communication_lost: B1,
+    pub overload: B1,
+    #[skip] _a: B2,
+}
+
+impl Default for Status {
+    fn default() -> Self {
+        Status::new()
+    }
+}
+
+/// longest report on the wire: report id and up to 24 bit payload
+pub const MAX_REPORT_LEN: usize = 4;
+
+///
+///
+/// typed value of a report, one variant per report id
+///
+/// the payload width of every variant matches its REPORT_SIZE in `Report::DESCRIPTOR`
+///
+#[derive(Clone, Copy)]
+pub enum Report {
+    IProduct(u8),
+    SerialNumber(u8),
+    Manufacturer(u8),
+    Rechargeable(u8),
+    PresentStatus(Status),
+    RemainingTimeLimit(u16),
+    ManufactureDate(u16),
+    ConfigVoltage(u16),
+    Voltage(u16),
+    RemainingCapacity(u8),
+    RunTimeToEmpty(u16),
+    FullChargeCapacity(u8),
+    WarningCapacityLimit(u8),
+    CapacityGranularity1(u8),
+    RemainingCapacityLimit(u8),
+    DelayBeforeShutdown(i16),
+    DelayBeforeReboot(i16),
+    AudibleAlarmControl(u8),
+    Current(i16),
+    CapacityMode(u8),
+    DesignCapacity(u8),
+    CapacityGranularity2(u8),
+    AverageTimeToFull(u16),
+    AverageCurrent(i16),
+    AverageTimeToEmpty(u16),
+    IDeviceChemistry(u8),
+    IOEMInformation(u8),
+}
+
+impl Report {
+    pub const fn id(&self) -> u8 {
+        match self {
+            Report::IProduct(_) => HID_PD_IPRODUCT,
+            Report::SerialNumber(_) => HID_PD_SERIAL,
+            Report::Manufacturer(_) => HID_PD_MANUFACTURER,
+            Report::Rechargeable(_) => HID_PD_RECHARGEABLE,
+            Report::PresentStatus(_) => HID_PD_PRESENTSTATUS,
+            Report::RemainingTimeLimit(_) => HID_PD_REMAINTIMELIMIT,
+            Report::ManufactureDate(_) => HID_PD_MANUFACTUREDATE,
+            Report::ConfigVoltage(_) => HID_PD_CONFIGVOLTAGE,
+            Report::Voltage(_) => HID_PD_VOLTAGE,
+            Report::RemainingCapacity(_) => HID_PD_REMAININGCAPACITY,
+            Report::RunTimeToEmpty(_) => HID_PD_RUNTIMETOEMPTY,
+            Report::FullChargeCapacity(_) => HID_PD_FULLCHRGECAPACITY,
+            Report::WarningCapacityLimit(_) => HID_PD_WARNCAPACITYLIMIT,
+            Report::CapacityGranularity1(_) => HID_PD_CPCTYGRANULARITY1,
+            Report::RemainingCapacityLimit(_) => HID_PD_REMNCAPACITYLIMIT,
+            Report::DelayBeforeShutdown(_) => HID_PD_DELAYBE4SHUTDOWN,
+            Report::DelayBeforeReboot(_) => HID_PD_DELAYBE4REBOOT,
+            Report::AudibleAlarmControl(_) => HID_PD_AUDIBLEALARMCTRL,
+            Report::Current(_) => HID_PD_CURRENT,
+            Report::CapacityMode(_) => HID_PD_CAPACITYMODE,
+            Report::DesignCapacity(_) => HID_PD_DESIGNCAPACITY,
+            Report::CapacityGranularity2(_) => HID_PD_CPCTYGRANULARITY2,
+            Report::AverageTimeToFull(_) => HID_PD_AVERAGETIME2FULL,
+            Report::AverageCurrent(_) => HID_PD_AVERAGECURRENT,
+            Report::AverageTimeToEmpty(_) => HID_PD_AVERAGETIME2EMPTY,
+            Report::IDeviceChemistry(_) => HID_PD_IDEVICECHEMISTRY,
+            Report::IOEMInformation(_) => HID_PD_IOEMINFORMATION,
+        }
+    }
+
+    /// payload length in bytes, without the report id, as declared in `REPORTS`
+    pub const fn size(&self) -> usize {
+        match find_report(REPORTS, self.id()) {
+            Some(report) => report.payload_size(),
+            None => 0,
+        }
+    }
+
+    ///
+    ///
+    /// payload as an unsigned little endian value
+    ///
+    /// returns: the value and the width of the variant's type in bytes, checked against
+    /// `size()` at compile time
+    ///
+    const fn raw_value(&self) -> (u32, usize) {
+        match *self {
+            Report::IProduct(value)
+            | Report::SerialNumber(value)
+            | Report::Manufacturer(value)
+            | Report::Rechargeable(value)
+            | Report::RemainingCapacity(value)
+            | Report::FullChargeCapacity(value)
+            | Report::WarningCapacityLimit(value)
+            | Report::CapacityGranularity1(value)
+            | Report::RemainingCapacityLimit(value)
+            | Report::AudibleAlarmControl(value)
+            | Report::CapacityMode(value)
+            | Report::DesignCapacity(value)
+            | Report::CapacityGranularity2(value)
+            | Report::IDeviceChemistry(value)
+            | Report::IOEMInformation(value) => (value as u32, size_of::<u8>()),
+            Report::RemainingTimeLimit(value)
+            | Report::ManufactureDate(value)
+            | Report::ConfigVoltage(value)
+            | Report::Voltage(value)
+            | Report::RunTimeToEmpty(value)
+            | Report::AverageTimeToFull(value)
+            | Report::AverageTimeToEmpty(value) => (value as u32, size_of::<u16>()),
+            Report::DelayBeforeShutdown(value)
+            | Report::DelayBeforeReboot(value)
+            | Report::Current(value)
+            | Report::AverageCurrent(value) => (value as u16 as u32, size_of::<i16>()),
+            Report::PresentStatus(status) => (u16::from_le_bytes(status.into_bytes()) as u32, size_of::<Status>()),
+        }
+    }
+
+    const fn from_raw_value(id: u8, raw: u32) -> Option<Report> {
+        let report = match id {
+            HID_PD_IPRODUCT => Report::IProduct(raw as u8),
+            HID_PD_SERIAL => Report::SerialNumber(raw as u8),
+            HID_PD_MANUFACTURER => Report::Manufacturer(raw as u8),
+            HID_PD_RECHARGEABLE => Report::Rechargeable(raw as u8),
+            HID_PD_PRESENTSTATUS => Report::PresentStatus(Status::from_bytes((raw as u16).to_le_bytes())),
+            HID_PD_REMAINTIMELIMIT => Report::RemainingTimeLimit(raw as u16),
+            HID_PD_MANUFACTUREDATE => Report::ManufactureDate(raw as u16),
+            HID_PD_CONFIGVOLTAGE => Report::ConfigVoltage(raw as u16),
+            HID_PD_VOLTAGE => Report::Voltage(raw as u16),
+            HID_PD_REMAININGCAPACITY => Report::RemainingCapacity(raw as u8),
+            HID_PD_RUNTIMETOEMPTY => Report::RunTimeToEmpty(raw as u16),
+            HID_PD_FULLCHRGECAPACITY => Report::FullChargeCapacity(raw as u8),
+            HID_PD_WARNCAPACITYLIMIT => Report::WarningCapacityLimit(raw as u8),
+            HID_PD_CPCTYGRANULARITY1 => Report::CapacityGranularity1(raw as u8),
+            HID_PD_REMNCAPACITYLIMIT => Report::RemainingCapacityLimit(raw as u8),
+            HID_PD_DELAYBE4SHUTDOWN => Report::DelayBeforeShutdown(raw as u16 as i16),
+            HID_PD_DELAYBE4REBOOT => Report::DelayBeforeReboot(raw as u16 as i16),
+            HID_PD_AUDIBLEALARMCTRL => Report::AudibleAlarmControl(raw as u8),
+            HID_PD_CURRENT => Report::Current(raw as u16 as i16),
+            HID_PD_CAPACITYMODE => Report::CapacityMode(raw as u8),
+            HID_PD_DESIGNCAPACITY => Report::DesignCapacity(raw as u8),
+            HID_PD_CPCTYGRANULARITY2 => Report::CapacityGranularity2(raw as u8),
+            HID_PD_AVERAGETIME2FULL => Report::AverageTimeToFull(raw as u16),
+            HID_PD_AVERAGECURRENT => Report::AverageCurrent(raw as u16 as i16),
+            HID_PD_AVERAGETIME2EMPTY => Report::AverageTimeToEmpty(raw as u16),
+            HID_PD_IDEVICECHEMISTRY => Report::IDeviceChemistry(raw as u8),
+            HID_PD_IOEMINFORMATION => Report::IOEMInformation(raw as u8),
+            _ => return None,
+        };
+        Some(report)
+    }
+
+    ///
+    ///
+    /// encodes the report as sent on the wire: report id followed by the little endian payload
+    ///
+    /// returns: ReportBytes
+    ///
+    pub fn encode(&self) -> ReportBytes {
+        let size = self.size();
+        let mut bytes = [0; MAX_REPORT_LEN];
+        bytes[0] = self.id();
+        bytes[1..=size].copy_from_slice(&self.raw_value().0.to_le_bytes()[..size]);
+        ReportBytes { bytes, len: size + 1 }
+    }
+
+    ///
+    ///
+    /// decodes a report received from the host, starting with the report id
+    ///
+    /// returns: `None` if the id is unknown or the length does not match the report
+    ///
+    pub fn decode(bytes: &[u8]) -> Option<Report> {
+        let (&id, payload) = bytes.split_first()?;
+        if payload.len() > MAX_REPORT_LEN - 1 {
+            return None;
+        }
+        let mut raw = [0; 4];
+        raw[..payload.len()].copy_from_slice(payload);
+        let report = Report::from_raw_value(id, u32::from_le_bytes(raw))?;
+        if report.size() != payload.len() {
+            return None;
+        }
+        Some(report)
+    }
+}
+
+///
+///
+/// encoded report with its exact length on the wire
+///
+#[derive(Clone, Copy, PartialEq)]
+pub struct ReportBytes {
+    bytes: [u8; MAX_REPORT_LEN],
+    len: usize,
+}
+
+impl AsRef<[u8]> for ReportBytes {
+    fn as_ref(&self) -> &[u8] {
+        &self.bytes[..self.len]
+    }
+}
+
+const PERCENT: Field = Field::new(BATTERY_SYSTEM, 0).logical(0, 100);
+const SECONDS: Field = Field::new(BATTERY_SYSTEM, 0).bits(16).logical(0, 65535).unit(UNIT_SECONDS, 0);
+const DELAY: Field = Field::new(POWER_DEVICE, 0).bits(16).logical(-32768, 32767).unit(UNIT_SECONDS, 0);
+const CENTIVOLTS: Field = Field::new(POWER_DEVICE, 0).bits(16).logical(0, 65535).unit(UNIT_VOLT, 5);
+const MILLIAMPS: Field = Field::new(POWER_DEVICE, 0).bits(16).logical(-32768, 32767).unit(UNIT_AMPERE, -3);
+
+const CONST_FEATURE: u8 = CONSTANT | VARIABLE | NO_PREFERRED;
+const DATA_FEATURE: u8 = VARIABLE | NO_PREFERRED;
+const CONST_VOLATILE: u8 = CONSTANT | VARIABLE | NO_PREFERRED | VOLATILE;
+const DATA_VOLATILE: u8 = VARIABLE | NO_PREFERRED | VOLATILE;
+
+const fn with_usage(field: Field, usage_page: u16, usage: u16) -> Field {
+    let mut field = field;
+    field.usage_page = usage_page;
+    field.usage = usage;
+    field
+}
+
+const fn status_bit(usage_page: u16, usage: u16, flags: u8) -> Field {
+    Field::new(usage_page, usage).bits(1).logical(0, 1).input(flags).feature(flags)
+}
+
+///
+///
+/// declaration of every report, `Report::DESCRIPTOR` and the payload sizes used by
+/// `Report::encode` / `Report::decode` are derived from this table
+///
+pub const REPORTS: &[ReportDef] = &[
+    ReportDef {
+        id: HID_PD_IPRODUCT,
+        collection: None,
+        fields: &[Field::new(POWER_DEVICE, 0xFE).string(IPRODUCT).feature(CONST_FEATURE)],
+    },
+    ReportDef {
+        id: HID_PD_SERIAL,
+        collection: None,
+        fields: &[Field::new(POWER_DEVICE, 0xFF).string(ISERIAL).feature(CONST_FEATURE)],
+    },
+    ReportDef {
+        id: HID_PD_MANUFACTURER,
+        collection: None,
+        fields: &[Field::new(POWER_DEVICE, 0xFD).string(IMANUFACTURER).feature(CONST_FEATURE)],
+    },
+    ReportDef {
+        id: HID_PD_RECHARGEABLE,
+        collection: None,
+        fields: &[Field::new(BATTERY_SYSTEM, 0x8B).feature(CONST_FEATURE)],
+    },
+    ReportDef {
+        id: HID_PD_IDEVICECHEMISTRY,
+        collection: None,
+        fields: &[Field::new(BATTERY_SYSTEM, 0x89).string(IDEVICECHEMISTRY).feature(CONST_FEATURE)],
+    },
+    ReportDef {
+        id: HID_PD_IOEMINFORMATION,
+        collection: None,
+        fields: &[Field::new(BATTERY_SYSTEM, 0x8F).string(IOEMVENDOR).feature(CONST_FEATURE)],
+    },
+    ReportDef {
+        id: HID_PD_CAPACITYMODE,
+        collection: None,
+        fields: &[Field::new(BATTERY_SYSTEM, 0x2C).feature(CONST_FEATURE)],
+    },
+    ReportDef {
+        id: HID_PD_CPCTYGRANULARITY1,
+        collection: None,
+        fields: &[with_usage(PERCENT, BATTERY_SYSTEM, 0x8D).feature(DATA_FEATURE)],
+    },
+    ReportDef {
+        id: HID_PD_CPCTYGRANULARITY2,
+        collection: None,
+        fields: &[with_usage(PERCENT, BATTERY_SYSTEM, 0x8E).feature(CONST_FEATURE)],
+    },
+    ReportDef {
+        id: HID_PD_FULLCHRGECAPACITY,
+        collection: None,
+        fields: &[with_usage(PERCENT, BATTERY_SYSTEM, 0x67).feature(CONSTANT | VARIABLE | VOLATILE)],
+    },
+    ReportDef {
+        id: HID_PD_DESIGNCAPACITY,
+        collection: None,
+        fields: &[with_usage(PERCENT, BATTERY_SYSTEM, 0x83).feature(CONSTANT | VARIABLE | VOLATILE)],
+    },
+    ReportDef {
+        id: HID_PD_REMAININGCAPACITY,
+        collection: None,
+        fields: &[with_usage(PERCENT, BATTERY_SYSTEM, 0x66).input(CONST_VOLATILE).feature(CONST_VOLATILE)],
+    },
+    ReportDef {
+        id: HID_PD_WARNCAPACITYLIMIT,
+        collection: None,
+        fields: &[with_usage(PERCENT, BATTERY_SYSTEM, 0x8C).feature(DATA_VOLATILE)],
+    },
+    ReportDef {
+        id: HID_PD_REMNCAPACITYLIMIT,
+        collection: None,
+        fields: &[with_usage(PERCENT, BATTERY_SYSTEM, 0x29).feature(DATA_VOLATILE)],
+    },
+    ReportDef {
+        id: HID_PD_MANUFACTUREDATE,
+        collection: None,
+        fields: &[Field::new(BATTERY_SYSTEM, 0x85).bits(16).logical(0, 65535).feature(CONST_VOLATILE)],
+    },
+    ReportDef {
+        id: HID_PD_AVERAGETIME2FULL,
+        collection: None,
+        fields: &[with_usage(SECONDS, BATTERY_SYSTEM, 0x6A).feature(CONST_VOLATILE)],
+    },
+    ReportDef {
+        id: HID_PD_AVERAGETIME2EMPTY,
+        collection: None,
+        fields: &[with_usage(SECONDS, BATTERY_SYSTEM, 0x69).input(CONST_VOLATILE).feature(CONST_VOLATILE)],
+    },
+    ReportDef {
+        id: HID_PD_RUNTIMETOEMPTY,
+        collection: None,
+        fields: &[with_usage(SECONDS, BATTERY_SYSTEM, 0x68).input(CONST_VOLATILE).feature(CONST_VOLATILE)],
+    },
+    ReportDef {
+        id: HID_PD_REMAINTIMELIMIT,
+        collection: None,
+        fields: &[with_usage(SECONDS, BATTERY_SYSTEM, 0x2A).logical(120, 1380).input(DATA_FEATURE).feature(DATA_VOLATILE)],
+    },
+    // the firmware stalls writes of the two delays unless the boost converter can be switched,
+    // see the `load-switch` feature
+    ReportDef {
+        id: HID_PD_DELAYBE4SHUTDOWN,
+        collection: None,
+        fields: &[with_usage(DELAY, POWER_DEVICE, 0x57).feature(DATA_VOLATILE)],
+    },
+    ReportDef {
+        id: HID_PD_DELAYBE4REBOOT,
+        collection: None,
+        fields: &[with_usage(DELAY, POWER_DEVICE, 0x55).feature(DATA_VOLATILE)],
+    },
+    ReportDef {
+        id: HID_PD_CONFIGVOLTAGE,
+        collection: None,
+        fields: &[with_usage(CENTIVOLTS, POWER_DEVICE, 0x40).feature(CONST_FEATURE)],
+    },
+    ReportDef {
+        id: HID_PD_VOLTAGE,
+        collection: None,
+        fields: &[with_usage(CENTIVOLTS, POWER_DEVICE, 0x30).input(CONST_VOLATILE).feature(CONST_VOLATILE)],
+    },
+    ReportDef {
+        id: HID_PD_CURRENT,
+        collection: None,
+        fields: &[with_usage(MILLIAMPS, POWER_DEVICE, 0x31).input(CONST_VOLATILE).feature(CONST_VOLATILE)],
+    },
+    ReportDef {
+        id: HID_PD_AVERAGECURRENT,
+        collection: None,
+        fields: &[with_usage(MILLIAMPS, BATTERY_SYSTEM, 0x62).input(CONST_VOLATILE).feature(CONST_VOLATILE)],
+    },
+    ReportDef {
+        id: HID_PD_AUDIBLEALARMCTRL,
+        collection: None,
+        fields: &[Field::new(POWER_DEVICE, 0x5A).logical(1, 3).input(DATA_FEATURE).feature(DATA_VOLATILE)],
+    },
+    ReportDef {
+        id: HID_PD_PRESENTSTATUS,
+        collection: Some((POWER_DEVICE, 0x02)),
+        // bit order matches the `Status` bitfield
+        fields: &[
+            status_bit(BATTERY_SYSTEM, 0x44, CONST_VOLATILE), // Charging
+            status_bit(BATTERY_SYSTEM, 0x45, CONST_VOLATILE), // Discharging
+            status_bit(BATTERY_SYSTEM, 0xD0, CONST_VOLATILE), // ACPresent
+            status_bit(BATTERY_SYSTEM, 0xD1, CONST_VOLATILE), // BatteryPresent
+            status_bit(BATTERY_SYSTEM, 0x42, CONST_VOLATILE), // BelowRemainingCapacityLimit
+            status_bit(BATTERY_SYSTEM, 0x43, DATA_VOLATILE), // RemainingTimeLimitExpired
+            status_bit(BATTERY_SYSTEM, 0x4B, CONST_VOLATILE), // NeedReplacement
+            status_bit(BATTERY_SYSTEM, 0xDB, CONST_VOLATILE), // VoltageNotRegulated
+            status_bit(BATTERY_SYSTEM, 0x46, CONST_VOLATILE), // FullyCharged
+            status_bit(BATTERY_SYSTEM, 0x47, CONST_VOLATILE), // FullyDischarged
+            status_bit(POWER_DEVICE, 0x68, DATA_VOLATILE), // ShutdownRequested
+            status_bit(POWER_DEVICE, 0x69, CONST_VOLATILE), // ShutdownImminent
+            status_bit(POWER_DEVICE, 0x73, CONST_VOLATILE), // CommunicationLost
+            status_bit(POWER_DEVICE, 0x65, CONST_VOLATILE), // Overload
+            Field::padding(POWER_DEVICE, 1, 2),
+        ],
+    },
+];
+
+const DESCRIPTOR_LEN: usize = build::<0>(REPORTS, &[]).len;
+const DESCRIPTOR_BYTES: [u8; DESCRIPTOR_LEN] = build::<DESCRIPTOR_LEN>(REPORTS, &[]).bytes;
+
+impl Report {
+    /// descriptor without vendor reports, identity profiles may extend it (see `identity`)
+    pub const DESCRIPTOR: &'static [u8] = &DESCRIPTOR_BYTES;
+}
+
+// conformance check of the generated descriptor, fails the build on a mismatch
+const _: () = match check(&DESCRIPTOR_BYTES, REPORTS, &[], HID_PD_PRESENTSTATUS, size_of::<Status>() * 8) {
+    Ok(()) => (),
+    Err(mismatch) => panic!("{}", mismatch.message()),
+};
+
+// every declared report has a variant whose type is exactly as wide as its payload, so
+// `encode` / `decode` never truncate or pad a value
+const _: () = {
+    let mut i = 0;
+    while i < REPORTS.len() {
+        match Report::from_raw_value(REPORTS[i].id, 0) {
+            Some(report) => {
+                assert!(report.id() == REPORTS[i].id, "from_raw_value returns a report with another id");
+                assert!(report.raw_value().1 == REPORTS[i].payload_size(), "variant width differs from the payload size");
+            }
+            None => panic!("declared report without a Report variant"),
+        }
+        i += 1;
+    }
+};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::{parse, MainKind};
+
+    /// every variant with the exact bytes it is sent as, report id first
+    fn encoded() -> [(Report, &'static [u8]); 27] {
+        let status = Status::new()
+            .with_charging(1)
+            .with_ac_present(1)
+            .with_battery_present(1)
+            .with_full_charge(1)
+            .with_overload(1);
+        [
+            (Report::IProduct(IPRODUCT), &[0x01, 0x02]),
+            (Report::SerialNumber(ISERIAL), &[0x02, 0x03]),
+            (Report::Manufacturer(IMANUFACTURER), &[0x03, 0x01]),
+            (Report::Rechargeable(1), &[0x06, 0x01]),
+            (Report::PresentStatus(status), &[0x07, 0x0D, 0x21]),
+            (Report::RemainingTimeLimit(600), &[0x08, 0x58, 0x02]),
+            (Report::ManufactureDate(0x5A21), &[0x09, 0x21, 0x5A]),
+            (Report::ConfigVoltage(740), &[0x0A, 0xE4, 0x02]),
+            (Report::Voltage(0x1234), &[0x0B, 0x34, 0x12]),
+            (Report::RemainingCapacity(87), &[0x0C, 0x57]),
+            (Report::RunTimeToEmpty(0xFFFF), &[0x0D, 0xFF, 0xFF]),
+            (Report::FullChargeCapacity(100), &[0x0E, 0x64]),
+            (Report::WarningCapacityLimit(20), &[0x0F, 0x14]),
+            (Report::CapacityGranularity1(1), &[0x10, 0x01]),
+            (Report::RemainingCapacityLimit(10), &[0x11, 0x0A]),
+            (Report::DelayBeforeShutdown(-1), &[0x12, 0xFF, 0xFF]),
+            (Report::DelayBeforeReboot(300), &[0x13, 0x2C, 0x01]),
+            (Report::AudibleAlarmControl(2), &[0x14, 0x02]),
+            (Report::Current(-1500), &[0x15, 0x24, 0xFA]),
+            (Report::CapacityMode(2), &[0x16, 0x02]),
+            (Report::DesignCapacity(100), &[0x17, 0x64]),
+            (Report::CapacityGranularity2(1), &[0x18, 0x01]),
+            (Report::AverageTimeToFull(3600), &[0x1A, 0x10, 0x0E]),
+            (Report::AverageCurrent(i16::MIN), &[0x1B, 0x00, 0x80]),
+            (Report::AverageTimeToEmpty(7200), &[0x1C, 0x20, 0x1C]),
+            (Report::IDeviceChemistry(IDEVICECHEMISTRY), &[0x1F, 0x04]),
+            (Report::IOEMInformation(IOEMVENDOR), &[0x20, 0x05]),
+        ]
+    }
+
+    #[test]
+    fn encodes_every_report() {
+        for (report, bytes) in encoded() {
+            let encoded = report.encode();
+            assert_eq!(encoded.as_ref(), bytes, "report id {:#04x}", report.id());
+            assert_eq!(report.size() + 1, bytes.len(), "report id {:#04x}", report.id());
+        }
+    }
+
+    #[test]
+    fn every_declared_report_has_a_variant() {
+        let ids = encoded().map(|(report, _)| report.id());
+        assert_eq!(ids.len(), REPORTS.len());
+        for declared in REPORTS {
+            assert!(ids.contains(&declared.id), "report id {:#04x}", declared.id);
+        }
+    }
+
+    #[test]
+    fn decodes_what_it_encodes() {
+        for (report, bytes) in encoded() {
+            let decoded = Report::decode(bytes).unwrap();
+            assert_eq!(decoded.id(), report.id());
+            assert_eq!(decoded.encode().as_ref(), bytes);
+        }
+    }
+
+    #[test]
+    fn decode_rejects_wrong_length_and_unknown_id() {
+        assert!(Report::decode(&[]).is_none());
+        assert!(Report::decode(&[HID_PD_VOLTAGE, 0x34]).is_none());
+        assert!(Report::decode(&[HID_PD_REMAININGCAPACITY, 0x57, 0x00]).is_none());
+        assert!(Report::decode(&[HID_PD_VOLTAGE, 0x34, 0x12, 0x00, 0x00]).is_none());
+        assert!(Report::decode(&[0x19, 0x00]).is_none());
+    }
+
+    #[test]
+    fn descriptor_parses_back_into_the_declared_reports() {
+        let parsed = parse(Report::DESCRIPTOR).unwrap();
+        assert_eq!(parsed.report_count, REPORTS.len());
+        for (report, bytes) in encoded() {
+            let layout = parsed.report(report.id()).unwrap();
+            let declared = find_report(REPORTS, report.id()).unwrap();
+            assert_eq!(layout.input_bits, declared.input_bits(), "report id {:#04x}", report.id());
+            assert_eq!(layout.feature_bits, declared.feature_bits(), "report id {:#04x}", report.id());
+            assert_eq!(layout.output_bits, 0);
+            let bits = layout.input_bits.max(layout.feature_bits);
+            assert_eq!(bits, (bytes.len() - 1) * 8, "report id {:#04x}", report.id());
+        }
+    }
+
+    #[test]
+    fn present_status_bits_follow_the_bitfield() {
+        let parsed = parse(Report::DESCRIPTOR).unwrap();
+        let layout = parsed.report(HID_PD_PRESENTSTATUS).unwrap();
+        let input = layout.fields[..layout.field_count]
+            .iter()
+            .filter(|field| field.kind == MainKind::Input && field.usage != 0);
+        // (usage page, usage) of the descriptor field at the bit position of each setter
+        let expected: [(u16, u16, Status); 14] = [
+            (BATTERY_SYSTEM, 0x44, Status::new().with_charging(1)),
+            (BATTERY_SYSTEM, 0x45, Status::new().with_discharging(1)),
+            (BATTERY_SYSTEM, 0xD0, Status::new().with_ac_present(1)),
+            (BATTERY_SYSTEM, 0xD1, Status::new().with_battery_present(1)),
+            (BATTERY_SYSTEM, 0x42, Status::new().with_below_remaining_capacity_limit(1)),
+            (BATTERY_SYSTEM, 0x43, Status::new().with_remaining_time_limit_expired(1)),
+            (BATTERY_SYSTEM, 0x4B, Status::new().with_need_replace(1)),
+            (BATTERY_SYSTEM, 0xDB, Status::new().with_voltage_nr(1)),
+            (BATTERY_SYSTEM, 0x46, Status::new().with_full_charge(1)),
+            (BATTERY_SYSTEM, 0x47, Status::new().with_full_discharge(1)),
+            (POWER_DEVICE, 0x68, Status::new().with_shutdown_requested(1)),
+            (POWER_DEVICE, 0x69, Status::new().with_shutdown_imminent(1)),
+            (POWER_DEVICE, 0x73, Status::new().with_communication_lost(1)),
+            (POWER_DEVICE, 0x65, Status::new().with_overload(1)),
+        ];
+        let mut count = 0;
+        for (field, (page, usage, status)) in input.zip(expected) {
+            let bit = u16::from_le_bytes(status.into_bytes()).trailing_zeros() as usize;
+            assert_eq!((field.usage_page, field.usage, field.bits), (page, usage, 1));
+            assert_eq!(field.bit_offset, bit, "usage {:#04x}", usage);
+            count += 1;
+        }
+        assert_eq!(count, expected.len());
+    }
+}
@@ -0,0 +1,131 @@
+use crate::Error;
+
+/// worst case length of `len` bytes after COBS encoding, without the delimiter
+pub const fn max_encoded_len(len: usize) -> usize {
+    len + len / 254 + 1
+}
+
+///
+///
+/// COBS encodes `data` into `out`, the result contains no 0 bytes
+///
+/// returns: the number of bytes written
+///
+pub fn encode(data: &[u8], out: &mut [u8]) -> Result<usize, Error> {
+    if out.len() < max_encoded_len(data.len()) {
+        return Err(Error::BufferTooSmall);
+    }
+    let mut code_index = 0;
+    let mut write = 1;
+    let mut code = 1u8;
+    for &byte in data {
+        if byte == 0 {
+            out[code_index] = code;
+            code_index = write;
+            write += 1;
+            code = 1;
+        } else {
+            out[write] = byte;
+            write += 1;
+            code += 1;
+            if code == 0xFF {
+                out[code_index] = code;
+                code_index = write;
+                write += 1;
+                code = 1;
+            }
+        }
+    }
+    out[code_index] = code;
+    Ok(write)
+}
+
+///
+///
+/// decodes a COBS block in place, `data` must not contain the delimiter
+///
+/// returns: the number of decoded bytes at the start of `data`
+///
+pub fn decode_in_place(data: &mut [u8]) -> Result<usize, Error> {
+    let mut read = 0;
+    let mut write = 0;
+    while read < data.len() {
+        let code = data[read] as usize;
+        if code == 0 || read + code > data.len() {
+            return Err(Error::Cobs);
+        }
+        read += 1;
+        for _ in 1..code {
+            data[write] = data[read];
+            write += 1;
+            read += 1;
+        }
+        // a block shorter than 254 bytes stands for a 0, except at the end
+        if code != 0xFF && read < data.len() {
+            data[write] = 0;
+            write += 1;
+        }
+    }
+    Ok(write)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(data: &[u8], expected_len: usize) {
+        let mut out = [0; 600];
+        let len = encode(data, &mut out).unwrap();
+        assert_eq!(len, expected_len);
+        assert!(len <= max_encoded_len(data.len()));
+        assert!(!out[..len].contains(&0));
+        let decoded = decode_in_place(&mut out[..len]).unwrap();
+        assert_eq!(&out[..decoded], data);
+    }
+
+    #[test]
+    fn empty_input() {
+        let mut out = [0; 1];
+        assert_eq!(encode(&[], &mut out), Ok(1));
+        assert_eq!(out, [0x01]);
+        assert_eq!(decode_in_place(&mut out), Ok(0));
+        assert_eq!(decode_in_place(&mut []), Ok(0));
+    }
+
+    #[test]
+    fn embedded_zeros() {
+        let mut out = [0; 8];
+        assert_eq!(encode(&[0x00], &mut out), Ok(2));
+        assert_eq!(out[..2], [0x01, 0x01]);
+        assert_eq!(encode(&[0x11, 0x00, 0x00, 0x22], &mut out), Ok(5));
+        assert_eq!(out[..5], [0x02, 0x11, 0x01, 0x02, 0x22]);
+        round_trip(&[0x00, 0x00, 0x00], 4);
+        round_trip(&[0x11, 0x22, 0x00], 4);
+    }
+
+    #[test]
+    fn runs_of_254_and_255_bytes() {
+        let data = [0x5A; 300];
+        // a full block of 254 bytes is followed by an empty block
+        round_trip(&data[..253], 254);
+        round_trip(&data[..254], 256);
+        round_trip(&data[..255], 257);
+        let mut out = [0; 260];
+        encode(&data[..255], &mut out).unwrap();
+        assert_eq!((out[0], out[255], out[256]), (0xFF, 0x02, 0x5A));
+        // a zero right after a full block
+        let mut zero_after = [0x5A; 255];
+        zero_after[254] = 0;
+        round_trip(&zero_after, 257);
+    }
+
+    #[test]
+    fn rejects_small_buffer_and_invalid_blocks() {
+        let mut out = [0; 3];
+        assert_eq!(encode(&[1, 2, 3], &mut out), Err(Error::BufferTooSmall));
+        // code points past the end
+        assert_eq!(decode_in_place(&mut [0x05, 0x11, 0x22]), Err(Error::Cobs));
+        // a 0 inside the frame
+        assert_eq!(decode_in_place(&mut [0x01, 0x00]), Err(Error::Cobs));
+    }
+}
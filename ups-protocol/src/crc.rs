@@ -0,0 +1,35 @@
+///
+///
+/// CRC-16 with polynomial 0xA001 (reflected 0x8005) and initial value 0xFFFF,
+/// as used by Modbus
+///
+/// returns: u16
+///
+pub fn crc16(bytes: &[u8]) -> u16 {
+    let mut crc = 0xFFFF_u16;
+    for byte in bytes {
+        crc ^= *byte as u16;
+        for _ in 0..8 {
+            if crc & 1 != 0 {
+                crc = (crc >> 1) ^ 0xA001;
+            } else {
+                crc >>= 1;
+            }
+        }
+    }
+    crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_value() {
+        // check value of CRC-16/MODBUS
+        assert_eq!(crc16(b"123456789"), 0x4B37);
+        assert_eq!(crc16(&[]), 0xFFFF);
+        // read holding registers request from the Modbus specification, CRC sent low byte first
+        assert_eq!(crc16(&[0x01, 0x03, 0x00, 0x00, 0x00, 0x0A]).to_le_bytes(), [0xC5, 0xCD]);
+    }
+}
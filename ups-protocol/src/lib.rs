@@ -0,0 +1,612 @@
+//! Binary protocol between the UPS and host tools on the CDC port.
+//!
+//! Every frame is COBS encoded and terminated by a 0 byte. Decoded it reads
+//!
+//! | offset | length | content                                       |
+//! |--------|--------|-----------------------------------------------|
+//! | 0      | 1      | `PROTOCOL_VERSION`                            |
+//! | 1      | 1      | sequence number                               |
+//! | 2      | 1      | message tag, see `Message`                    |
+//! | 3      | n      | message body                                  |
+//! | 3 + n  | 2      | CRC-16/MODBUS of the bytes before, LE         |
+//!
+//! Requests are sent by the host, the device answers each one with a response carrying
+//! the same sequence number. Events are sent by the device unsolicited with its own
+//! sequence counter. Integers and floats are little endian, strings and byte arrays are
+//! prefixed with a one byte length.
+
+#![no_std]
+
+mod cobs;
+mod crc;
+
+pub use cobs::{decode_in_place, encode, max_encoded_len};
+pub use crc::crc16;
+
+/// incremented on every incompatible change of the frame layout or of a message
+pub const PROTOCOL_VERSION: u8 = 1;
+/// longest decoded frame
+pub const MAX_FRAME_LEN: usize = 128;
+/// longest frame on the wire, including the delimiter
+pub const MAX_ENCODED_LEN: usize = max_encoded_len(MAX_FRAME_LEN) + 1;
+/// most log bytes in one `Message::LogChunk`
+pub const MAX_LOG_CHUNK: usize = 64;
+
+const HEADER_LEN: usize = 3;
+const CRC_LEN: usize = 2;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Error {
+    BufferTooSmall,
+    /// the frame is not valid COBS
+    Cobs,
+    Crc,
+    /// the frame was built for another `PROTOCOL_VERSION`
+    Version(u8),
+    /// the body ends before the message does
+    Truncated,
+    /// bytes left over after the body of the message
+    TrailingBytes,
+    UnknownMessage(u8),
+    /// a field holds a value outside its range, e.g. an unknown `ErrorCode`
+    InvalidValue,
+    /// a string is not UTF-8
+    Utf8,
+}
+
+///
+///
+/// why a request failed, sent in `Message::Nack`
+///
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ErrorCode {
+    UnknownMessage,
+    UnknownKey,
+    InvalidValue,
+    Failed,
+}
+
+impl ErrorCode {
+    pub const fn to_u8(self) -> u8 {
+        match self {
+            ErrorCode::UnknownMessage => 1,
+            ErrorCode::UnknownKey => 2,
+            ErrorCode::InvalidValue => 3,
+            ErrorCode::Failed => 4,
+        }
+    }
+
+    pub const fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            1 => Some(ErrorCode::UnknownMessage),
+            2 => Some(ErrorCode::UnknownKey),
+            3 => Some(ErrorCode::InvalidValue),
+            4 => Some(ErrorCode::Failed),
+            _ => None,
+        }
+    }
+}
+
+///
+///
+/// measurements and state in `Message::Status`
+///
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct StatusMessage {
+    /// time since boot
+    pub time_ms: u32,
+    pub v_bat: f32,
+    pub v_in: f32,
+    pub current: f32,
+    /// die temperature in °C
+    pub temperature: f32,
+    /// estimated capacity in %
+    pub capacity: u8,
+    /// estimated runtime in seconds
+    pub runtime_s: u16,
+    /// bits of the HID PresentStatus report, charging in bit 0
+    pub status: u16,
+    pub output_enabled: bool,
+}
+
+///
+///
+/// every message of the protocol, the tag selects the kind:
+/// 0x01..=0x7F requests, 0x80..=0xBF responses, 0xC0..=0xFF events
+///
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Message<'a> {
+    /// asks for `Info`
+    GetInfo,
+    /// asks for `Status`
+    GetStatus,
+    /// asks for `ConfigValue` of a configuration key
+    GetConfig { key: &'a str },
+    /// changes a configuration key, answered with `Ack`
+    SetConfig { key: &'a str, value: &'a str },
+    /// asks for the `LogChunk` starting at `offset` bytes into the event log
+    ReadLog { offset: u16 },
+    /// clears the event log, answered with `Ack`
+    ClearLog,
+
+    Info { firmware_version: &'a str, serial_number: &'a str },
+    Status(StatusMessage),
+    ConfigValue { value: &'a str },
+    /// part of the event log, lines are terminated by `\n`, `total` is the length of the log
+    LogChunk { offset: u16, total: u16, data: &'a [u8] },
+    Ack,
+    Nack(ErrorCode),
+
+    MainsChanged { present: bool },
+    /// the PresentStatus bits changed
+    StatusChanged { status: u16 },
+}
+
+impl<'a> Message<'a> {
+    pub const fn tag(&self) -> u8 {
+        match self {
+            Message::GetInfo => 0x01,
+            Message::GetStatus => 0x02,
+            Message::GetConfig { .. } => 0x03,
+            Message::SetConfig { .. } => 0x04,
+            Message::ReadLog { .. } => 0x05,
+            Message::ClearLog => 0x06,
+            Message::Info { .. } => 0x81,
+            Message::Status(_) => 0x82,
+            Message::ConfigValue { .. } => 0x83,
+            Message::LogChunk { .. } => 0x84,
+            Message::Ack => 0x85,
+            Message::Nack(_) => 0x86,
+            Message::MainsChanged { .. } => 0xC1,
+            Message::StatusChanged { .. } => 0xC2,
+        }
+    }
+
+    pub const fn is_request(&self) -> bool {
+        self.tag() < 0x80
+    }
+
+    pub const fn is_event(&self) -> bool {
+        self.tag() >= 0xC0
+    }
+
+    fn write_body(&self, writer: &mut Writer) -> Result<(), Error> {
+        match *self {
+            Message::GetInfo | Message::GetStatus | Message::ClearLog | Message::Ack => Ok(()),
+            Message::GetConfig { key } => writer.str(key),
+            Message::SetConfig { key, value } => {
+                writer.str(key)?;
+                writer.str(value)
+            }
+            Message::ReadLog { offset } => writer.bytes(&offset.to_le_bytes()),
+            Message::Info { firmware_version, serial_number } => {
+                writer.str(firmware_version)?;
+                writer.str(serial_number)
+            }
+            Message::Status(status) => {
+                writer.bytes(&status.time_ms.to_le_bytes())?;
+                writer.bytes(&status.v_bat.to_le_bytes())?;
+                writer.bytes(&status.v_in.to_le_bytes())?;
+                writer.bytes(&status.current.to_le_bytes())?;
+                writer.bytes(&status.temperature.to_le_bytes())?;
+                writer.bytes(&[status.capacity])?;
+                writer.bytes(&status.runtime_s.to_le_bytes())?;
+                writer.bytes(&status.status.to_le_bytes())?;
+                writer.bytes(&[status.output_enabled as u8])
+            }
+            Message::ConfigValue { value } => writer.str(value),
+            Message::LogChunk { offset, total, data } => {
+                writer.bytes(&offset.to_le_bytes())?;
+                writer.bytes(&total.to_le_bytes())?;
+                writer.prefixed(data)
+            }
+            Message::Nack(code) => writer.bytes(&[code.to_u8()]),
+            Message::MainsChanged { present } => writer.bytes(&[present as u8]),
+            Message::StatusChanged { status } => writer.bytes(&status.to_le_bytes()),
+        }
+    }
+
+    fn read_body(tag: u8, reader: &mut Reader<'a>) -> Result<Self, Error> {
+        let message = match tag {
+            0x01 => Message::GetInfo,
+            0x02 => Message::GetStatus,
+            0x03 => Message::GetConfig { key: reader.str()? },
+            0x04 => Message::SetConfig { key: reader.str()?, value: reader.str()? },
+            0x05 => Message::ReadLog { offset: reader.u16()? },
+            0x06 => Message::ClearLog,
+            0x81 => Message::Info {
+                firmware_version: reader.str()?,
+                serial_number: reader.str()?,
+            },
+            0x82 => Message::Status(StatusMessage {
+                time_ms: reader.u32()?,
+                v_bat: reader.f32()?,
+                v_in: reader.f32()?,
+                current: reader.f32()?,
+                temperature: reader.f32()?,
+                capacity: reader.u8()?,
+                runtime_s: reader.u16()?,
+                status: reader.u16()?,
+                output_enabled: reader.bool()?,
+            }),
+            0x83 => Message::ConfigValue { value: reader.str()? },
+            0x84 => Message::LogChunk {
+                offset: reader.u16()?,
+                total: reader.u16()?,
+                data: reader.prefixed()?,
+            },
+            0x85 => Message::Ack,
+            0x86 => {
+                let code = reader.u8()?;
+                Message::Nack(ErrorCode::from_u8(code).ok_or(Error::InvalidValue)?)
+            }
+            0xC1 => Message::MainsChanged { present: reader.bool()? },
+            0xC2 => Message::StatusChanged { status: reader.u16()? },
+            _ => return Err(Error::UnknownMessage(tag)),
+        };
+        Ok(message)
+    }
+}
+
+///
+///
+/// a decoded frame
+///
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Frame<'a> {
+    pub seq: u8,
+    pub message: Message<'a>,
+}
+
+impl<'a> Frame<'a> {
+    ///
+    ///
+    /// encodes the frame for the wire, including the terminating 0
+    ///
+    /// returns: the number of bytes written to `out`
+    ///
+    pub fn encode(&self, out: &mut [u8]) -> Result<usize, Error> {
+        let mut frame = [0; MAX_FRAME_LEN];
+        let mut writer = Writer { buffer: &mut frame, len: 0 };
+        writer.bytes(&[PROTOCOL_VERSION, self.seq, self.message.tag()])?;
+        self.message.write_body(&mut writer)?;
+        let len = writer.len;
+        let crc = crc16(&frame[..len]);
+        let mut writer = Writer { buffer: &mut frame, len };
+        writer.bytes(&crc.to_le_bytes())?;
+        let len = writer.len;
+
+        let encoded = encode(&frame[..len], out)?;
+        if encoded >= out.len() {
+            return Err(Error::BufferTooSmall);
+        }
+        out[encoded] = 0;
+        Ok(encoded + 1)
+    }
+
+    ///
+    ///
+    /// decodes a frame received without its terminating 0, the COBS decoding is done in place
+    ///
+    pub fn decode(bytes: &'a mut [u8]) -> Result<Self, Error> {
+        let len = decode_in_place(bytes)?;
+        let bytes = &bytes[..len];
+        if len < HEADER_LEN + CRC_LEN {
+            return Err(Error::Truncated);
+        }
+        let crc = u16::from_le_bytes([bytes[len - 2], bytes[len - 1]]);
+        if crc16(&bytes[..len - CRC_LEN]) != crc {
+            return Err(Error::Crc);
+        }
+        if bytes[0] != PROTOCOL_VERSION {
+            return Err(Error::Version(bytes[0]));
+        }
+        let mut reader = Reader { bytes: &bytes[HEADER_LEN..len - CRC_LEN] };
+        let message = Message::read_body(bytes[2], &mut reader)?;
+        if !reader.bytes.is_empty() {
+            return Err(Error::TrailingBytes);
+        }
+        Ok(Frame { seq: bytes[1], message })
+    }
+}
+
+///
+///
+/// collects received bytes until a frame delimiter
+///
+pub struct FrameReader {
+    buffer: [u8; MAX_ENCODED_LEN],
+    len: usize,
+    /// the frame is longer than `MAX_ENCODED_LEN` and is dropped
+    overflow: bool,
+}
+
+impl FrameReader {
+    pub const fn new() -> Self {
+        FrameReader {
+            buffer: [0; MAX_ENCODED_LEN],
+            len: 0,
+            overflow: false,
+        }
+    }
+
+    ///
+    ///
+    /// returns: the encoded frame without the delimiter once `byte` completes one,
+    /// pass it to `Frame::decode`
+    ///
+    pub fn push(&mut self, byte: u8) -> Option<&mut [u8]> {
+        if byte == 0 {
+            let len = core::mem::replace(&mut self.len, 0);
+            if core::mem::replace(&mut self.overflow, false) || len == 0 {
+                return None;
+            }
+            return Some(&mut self.buffer[..len]);
+        }
+        if self.len < MAX_ENCODED_LEN {
+            self.buffer[self.len] = byte;
+            self.len += 1;
+        } else {
+            self.overflow = true;
+        }
+        None
+    }
+}
+
+impl Default for FrameReader {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+struct Writer<'b> {
+    buffer: &'b mut [u8],
+    len: usize,
+}
+
+impl Writer<'_> {
+    fn bytes(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        let end = self.len + bytes.len();
+        if end > self.buffer.len() {
+            return Err(Error::BufferTooSmall);
+        }
+        self.buffer[self.len..end].copy_from_slice(bytes);
+        self.len = end;
+        Ok(())
+    }
+
+    fn prefixed(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        let len = u8::try_from(bytes.len()).map_err(|_| Error::BufferTooSmall)?;
+        self.bytes(&[len])?;
+        self.bytes(bytes)
+    }
+
+    fn str(&mut self, string: &str) -> Result<(), Error> {
+        self.prefixed(string.as_bytes())
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], Error> {
+        if len > self.bytes.len() {
+            return Err(Error::Truncated);
+        }
+        let (head, tail) = self.bytes.split_at(len);
+        self.bytes = tail;
+        Ok(head)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], Error> {
+        let mut array = [0; N];
+        array.copy_from_slice(self.take(N)?);
+        Ok(array)
+    }
+
+    fn u8(&mut self) -> Result<u8, Error> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn bool(&mut self) -> Result<bool, Error> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(Error::InvalidValue),
+        }
+    }
+
+    fn u16(&mut self) -> Result<u16, Error> {
+        Ok(u16::from_le_bytes(self.array()?))
+    }
+
+    fn u32(&mut self) -> Result<u32, Error> {
+        Ok(u32::from_le_bytes(self.array()?))
+    }
+
+    fn f32(&mut self) -> Result<f32, Error> {
+        Ok(f32::from_le_bytes(self.array()?))
+    }
+
+    fn prefixed(&mut self) -> Result<&'a [u8], Error> {
+        let len = self.u8()? as usize;
+        self.take(len)
+    }
+
+    fn str(&mut self) -> Result<&'a str, Error> {
+        core::str::from_utf8(self.prefixed()?).map_err(|_| Error::Utf8)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// COBS encodes `decoded` as it is, with a valid CRC appended if `crc` is set
+    fn wire(decoded: &[u8], crc: bool, out: &mut [u8; MAX_ENCODED_LEN]) -> usize {
+        let mut frame = [0; MAX_FRAME_LEN];
+        frame[..decoded.len()].copy_from_slice(decoded);
+        let mut len = decoded.len();
+        if crc {
+            frame[len..len + CRC_LEN].copy_from_slice(&crc16(decoded).to_le_bytes());
+            len += CRC_LEN;
+        }
+        encode(&frame[..len], out).unwrap()
+    }
+
+    fn decode_wire(decoded: &[u8], crc: bool) -> Result<Message<'static>, Error> {
+        let mut out = [0; MAX_ENCODED_LEN];
+        let len = wire(decoded, crc, &mut out);
+        // the message borrows from the buffer, only compare the error or a borrow free message
+        let frame = Frame::decode(&mut out[..len])?;
+        match frame.message {
+            Message::Nack(code) => Ok(Message::Nack(code)),
+            Message::Ack => Ok(Message::Ack),
+            Message::MainsChanged { present } => Ok(Message::MainsChanged { present }),
+            message => panic!("unexpected {:?}", message),
+        }
+    }
+
+    fn every_message() -> [Message<'static>; 14] {
+        [
+            Message::GetInfo,
+            Message::GetStatus,
+            Message::GetConfig { key: "warning_capacity_limit" },
+            Message::SetConfig { key: "usb_mode", value: "composite" },
+            Message::ReadLog { offset: 0x1234 },
+            Message::ClearLog,
+            Message::Info { firmware_version: "0.1.0", serial_number: "" },
+            Message::Status(StatusMessage {
+                time_ms: 86_400_000,
+                v_bat: 7.4,
+                v_in: 0.0,
+                current: -1.25,
+                temperature: 31.5,
+                capacity: 87,
+                runtime_s: 5400,
+                status: 0x210D,
+                output_enabled: true,
+            }),
+            Message::ConfigValue { value: "20" },
+            Message::LogChunk { offset: 64, total: 300, data: b"[I] boot\n\0\xFF" },
+            Message::Ack,
+            Message::Nack(ErrorCode::InvalidValue),
+            Message::MainsChanged { present: false },
+            Message::StatusChanged { status: 0x0004 },
+        ]
+    }
+
+    #[test]
+    fn every_message_round_trips() {
+        for (seq, message) in every_message().into_iter().enumerate() {
+            let frame = Frame { seq: seq as u8, message };
+            let mut out = [0; MAX_ENCODED_LEN];
+            let len = frame.encode(&mut out).unwrap();
+            // the delimiter only appears at the end
+            assert_eq!(out[..len].iter().position(|&byte| byte == 0), Some(len - 1), "{:?}", message);
+            assert_eq!(Frame::decode(&mut out[..len - 1]), Ok(frame));
+        }
+    }
+
+    #[test]
+    fn message_kinds_follow_the_tag() {
+        for message in every_message() {
+            let kind = (message.is_request(), message.is_event());
+            match message.tag() {
+                0x01..=0x7F => assert_eq!(kind, (true, false)),
+                0x80..=0xBF => assert_eq!(kind, (false, false)),
+                _ => assert_eq!(kind, (false, true)),
+            }
+        }
+    }
+
+    #[test]
+    fn frame_reader_splits_at_delimiters() {
+        let mut out = [0; MAX_ENCODED_LEN];
+        let len = Frame { seq: 7, message: Message::GetStatus }.encode(&mut out).unwrap();
+        let mut reader = FrameReader::new();
+        // empty frames between delimiters are skipped
+        assert!(reader.push(0).is_none());
+        for &byte in &out[..len - 1] {
+            assert!(reader.push(byte).is_none());
+        }
+        let encoded = reader.push(0).unwrap();
+        assert_eq!(Frame::decode(encoded), Ok(Frame { seq: 7, message: Message::GetStatus }));
+    }
+
+    #[test]
+    fn frame_reader_drops_overlong_frames() {
+        let mut reader = FrameReader::new();
+        for _ in 0..MAX_ENCODED_LEN + 1 {
+            assert!(reader.push(0x01).is_none());
+        }
+        assert!(reader.push(0).is_none());
+        assert_eq!(reader.push(0x01), None);
+        assert_eq!(reader.push(0).map(|frame| frame.len()), Some(1));
+    }
+
+    #[test]
+    fn rejects_bad_crc() {
+        let mut out = [0; MAX_ENCODED_LEN];
+        let len = Frame { seq: 1, message: Message::Ack }.encode(&mut out).unwrap();
+        // flips a bit of the CRC, the encoded length of a 5 byte frame stays 6
+        out[len - 2] ^= 0x01;
+        assert_eq!(Frame::decode(&mut out[..len - 1]), Err(Error::Crc));
+        assert_eq!(decode_wire(&[PROTOCOL_VERSION, 1, 0x85, 0x00, 0x00], false), Err(Error::Crc));
+    }
+
+    #[test]
+    fn rejects_other_versions() {
+        let version = PROTOCOL_VERSION + 1;
+        assert_eq!(decode_wire(&[version, 1, 0x85], true), Err(Error::Version(version)));
+    }
+
+    #[test]
+    fn rejects_truncated_frames() {
+        assert_eq!(decode_wire(&[PROTOCOL_VERSION, 1], true), Err(Error::Truncated));
+        assert_eq!(decode_wire(&[], false), Err(Error::Truncated));
+        // StatusChanged needs two body bytes
+        assert_eq!(decode_wire(&[PROTOCOL_VERSION, 1, 0xC2, 0x04], true), Err(Error::Truncated));
+        // GetConfig announces a 5 byte key, 2 follow
+        let mut out = [0; MAX_ENCODED_LEN];
+        let len = wire(&[PROTOCOL_VERSION, 1, 0x03, 5, b'u', b's'], true, &mut out);
+        assert_eq!(Frame::decode(&mut out[..len]), Err(Error::Truncated));
+    }
+
+    #[test]
+    fn rejects_trailing_bytes() {
+        assert_eq!(decode_wire(&[PROTOCOL_VERSION, 1, 0x85], true), Ok(Message::Ack));
+        assert_eq!(decode_wire(&[PROTOCOL_VERSION, 1, 0x85, 0x00], true), Err(Error::TrailingBytes));
+        assert_eq!(decode_wire(&[PROTOCOL_VERSION, 1, 0x86, 3, 3], true), Err(Error::TrailingBytes));
+    }
+
+    #[test]
+    fn rejects_invalid_values() {
+        assert_eq!(decode_wire(&[PROTOCOL_VERSION, 1, 0x86, 3], true), Ok(Message::Nack(ErrorCode::InvalidValue)));
+        assert_eq!(decode_wire(&[PROTOCOL_VERSION, 1, 0x86, 0], true), Err(Error::InvalidValue));
+        assert_eq!(decode_wire(&[PROTOCOL_VERSION, 1, 0x86, 5], true), Err(Error::InvalidValue));
+        assert_eq!(decode_wire(&[PROTOCOL_VERSION, 1, 0xC1, 1], true), Ok(Message::MainsChanged { present: true }));
+        assert_eq!(decode_wire(&[PROTOCOL_VERSION, 1, 0xC1, 2], true), Err(Error::InvalidValue));
+    }
+
+    #[test]
+    fn rejects_unknown_messages_and_bad_strings() {
+        assert_eq!(decode_wire(&[PROTOCOL_VERSION, 1, 0x7F], true), Err(Error::UnknownMessage(0x7F)));
+        let mut out = [0; MAX_ENCODED_LEN];
+        let len = wire(&[PROTOCOL_VERSION, 1, 0x03, 2, 0xC3, 0x28], true, &mut out);
+        assert_eq!(Frame::decode(&mut out[..len]), Err(Error::Utf8));
+    }
+
+    #[test]
+    fn encode_checks_the_buffers() {
+        let mut out = [0; 8];
+        let frame = Frame { seq: 0, message: Message::ConfigValue { value: "composite" } };
+        assert_eq!(frame.encode(&mut out), Err(Error::BufferTooSmall));
+        let data = [0xAA; MAX_FRAME_LEN];
+        let frame = Frame { seq: 0, message: Message::LogChunk { offset: 0, total: 0, data: &data[..120] } };
+        let mut out = [0; MAX_ENCODED_LEN];
+        assert_eq!(frame.encode(&mut out), Err(Error::BufferTooSmall));
+    }
+}